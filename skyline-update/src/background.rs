@@ -0,0 +1,247 @@
+//! Update checks which run on a background thread, so they don't hold up the game booting
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::{Client, Installer, UpdateResponse};
+
+#[derive(Default)]
+struct State {
+    /// The result of the check once finished, `None` inside if no update is available
+    checked: Option<Option<UpdateResponse>>,
+    /// Set if the check failed because the update server didn't respond in time
+    timed_out: bool,
+    /// Set once the plugin has said it is safe to prompt the user
+    prompt: bool,
+    /// Set when the handle is dropped without allowing the prompt
+    cancelled: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+/// An update check running on a background thread, returned by
+/// [`Client::check_update_in_background`].
+///
+/// The check itself starts straight away, but the user is only asked whether to install an update
+/// once [`BackgroundUpdate::prompt`] (or [`BackgroundUpdate::join`]) is called, for example when
+/// the title screen is reached. Dropping the handle before then cancels the update.
+pub struct BackgroundUpdate {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<bool>>,
+}
+
+impl BackgroundUpdate {
+    pub(crate) fn spawn<I>(client: Client, name: String, version: String, allow_beta: bool, installer: I) -> Self
+        where I: Installer + Send + 'static,
+    {
+        let shared = Arc::new(Shared::default());
+        let thread_shared = Arc::clone(&shared);
+
+        let thread = thread::spawn(move || {
+            let shared = thread_shared;
+            let mut session = client.session(allow_beta);
+            let (update, timed_out) = match client.check(&mut session, &name, &version, &installer) {
+                Ok(update) => (update, false),
+                Err(e) => {
+                    client.report_error(&name, &e);
+                    (None, e.is_timeout())
+                }
+            };
+
+            // the server won't keep an idle connection open while waiting for the prompt
+            session.framed = None;
+
+            let mut state = shared.state.lock().unwrap();
+            state.checked = Some(update.clone());
+            state.timed_out = timed_out;
+            shared.changed.notify_all();
+
+            let update = match update {
+                Some(update) => update,
+                None => return false,
+            };
+
+            while !state.prompt && !state.cancelled {
+                state = shared.changed.wait(state).unwrap();
+            }
+            if !state.prompt {
+                return false
+            }
+            drop(state);
+
+            client.prompt_and_update(&mut session, &update, &installer)
+                .unwrap_or_else(|e| {
+                    client.report_error(&name, &e);
+                    false
+                })
+        });
+
+        Self { shared, thread: Some(thread) }
+    }
+
+    /// Whether an update is available, or `None` if the check hasn't finished yet
+    pub fn update_available(&self) -> Option<bool> {
+        self.shared.state.lock().unwrap()
+            .checked
+            .as_ref()
+            .map(Option::is_some)
+    }
+
+    /// The update found by the check, if it has finished and an update is available
+    pub fn update_info(&self) -> Option<UpdateResponse> {
+        self.shared.state.lock().unwrap().checked.clone().flatten()
+    }
+
+    /// Whether the check failed because the update server didn't respond in time, in which case
+    /// [`BackgroundUpdate::update_available`] is `Some(false)`
+    pub fn timed_out(&self) -> bool {
+        self.shared.state.lock().unwrap().timed_out
+    }
+
+    /// Allow the user to be prompted, straight away if the check has finished or otherwise as
+    /// soon as it does. Installing happens on the background thread.
+    pub fn prompt(&self) {
+        self.shared.state.lock().unwrap().prompt = true;
+        self.shared.changed.notify_all();
+    }
+
+    /// Whether the check, and the prompt and install if allowed, have finished
+    pub fn is_finished(&self) -> bool {
+        self.thread.as_ref().map(JoinHandle::is_finished).unwrap_or(true)
+    }
+
+    /// Allow the prompt if it hasn't been already, then wait for the update to finish, returning
+    /// whether an update was installed
+    pub fn join(mut self) -> bool {
+        self.prompt();
+        self.thread.take()
+            .map(|thread| thread.join().unwrap_or(false))
+            .unwrap_or(false)
+    }
+}
+
+impl Drop for BackgroundUpdate {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().cancelled = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::prelude::*;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    use update_protocol::ResponseCode;
+
+    use crate::{Error, Transport};
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<AtomicBool>);
+
+    impl Installer for Recorder {
+        fn should_update(&self, _: &UpdateResponse) -> bool {
+            self.0.store(true, Ordering::SeqCst);
+            true
+        }
+
+        fn install_file(&self, _: PathBuf, _: Vec<u8>) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    /// Serve a single update check over HTTP, offering an update with no files
+    fn server() -> Client {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut request = [0; 4096];
+            let _ = socket.read(&mut request);
+
+            let body = serde_json::to_string(&UpdateResponse {
+                code: ResponseCode::Update,
+                plugin_name: "plugin".to_owned(),
+                new_plugin_version: "2.0.0".to_owned(),
+                ..Default::default()
+            }).unwrap();
+            let _ = write!(socket, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        });
+
+        Client::with_host("127.0.0.1")
+            .transport(Transport::Http { port })
+            .game(None)
+            .timeout(Duration::from_secs(5))
+    }
+
+    fn wait_for_check(update: &BackgroundUpdate) -> bool {
+        loop {
+            if let Some(available) = update.update_available() {
+                return available
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn defers_prompt() {
+        let installer = Recorder::default();
+        let update = server().custom_check_update_in_background("plugin", "1.0.0", false, installer.clone());
+
+        assert!(wait_for_check(&update));
+        assert_eq!(update.update_info().unwrap().new_plugin_version, "2.0.0");
+        thread::sleep(Duration::from_millis(20));
+        assert!(!installer.0.load(Ordering::SeqCst));
+
+        assert!(update.join());
+        assert!(installer.0.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn cancels_on_drop() {
+        let installer = Recorder::default();
+        let update = server().custom_check_update_in_background("plugin", "1.0.0", false, installer.clone());
+        assert!(wait_for_check(&update));
+
+        let thread = {
+            let mut update = update;
+            update.thread.take().unwrap()
+        };
+        assert!(!thread.join().unwrap());
+        assert!(!installer.0.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn reports_timeouts() {
+        // accepts connections but never responds
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let client = Client::with_host("127.0.0.1")
+            .transport(Transport::Http { port })
+            .game(None)
+            .read_timeout(Some(Duration::from_millis(50)));
+
+        let installer = Recorder::default();
+        let update = client.custom_check_update_in_background("plugin", "1.0.0", false, installer.clone());
+        assert!(!wait_for_check(&update));
+        assert!(update.timed_out());
+        assert!(!update.join());
+        assert!(!installer.0.load(Ordering::SeqCst));
+
+        let result = client.read_timeout(None)
+            .deadline(Some(Duration::from_millis(50)))
+            .try_get_update_info("plugin", "1.0.0", false);
+        assert!(matches!(result, Err(Error::Timeout)));
+        drop(listener);
+    }
+}
@@ -0,0 +1,197 @@
+//! Resolution of the plugins an update depends on
+
+use std::collections::HashMap;
+
+use semver::{Version, VersionReq};
+use update_protocol::{ResponseCode, UpdateResponse};
+
+use crate::Error;
+
+/// Resolve the updates needed to satisfy the dependencies of `update`, returning every update to
+/// install in order: dependencies before the plugins requiring them, and `update` itself last.
+///
+/// `installed` gives the installed version of a plugin, if known. `fetch` asks the server for
+/// the newest version of a plugin, given the installed version.
+pub(crate) fn resolve<I, F>(update: &UpdateResponse, installed: I, fetch: F) -> Result<Vec<UpdateResponse>, Error>
+    where I: Fn(&str) -> Option<Version>,
+          F: FnMut(&str, Option<&Version>) -> Result<UpdateResponse, Error>,
+{
+    let mut resolver = Resolver {
+        installed,
+        fetch,
+        planned: HashMap::new(),
+        requirements: HashMap::new(),
+        path: vec![],
+        order: vec![],
+    };
+
+    resolver.visit(update.clone())?;
+
+    Ok(resolver.order)
+}
+
+struct Resolver<I, F> {
+    installed: I,
+    fetch: F,
+    /// The version each plugin being updated will have once installed
+    planned: HashMap<String, Version>,
+    /// Every requirement found so far for each plugin
+    requirements: HashMap<String, Vec<VersionReq>>,
+    /// The chain of plugins currently being resolved, for detecting cycles
+    path: Vec<String>,
+    order: Vec<UpdateResponse>,
+}
+
+fn parse_version(version: &str) -> Result<Version, Error> {
+    version.parse().map_err(|_| Error::InvalidResponse("invalid plugin version"))
+}
+
+impl<I, F> Resolver<I, F>
+    where I: Fn(&str) -> Option<Version>,
+          F: FnMut(&str, Option<&Version>) -> Result<UpdateResponse, Error>,
+{
+    /// Plan to install a version of a plugin, checking it satisfies everything requiring it
+    fn plan(&mut self, plugin_name: &str, version: Version) -> Result<(), Error> {
+        for requirement in self.requirements.get(plugin_name).into_iter().flatten() {
+            if !requirement.matches(&version) {
+                return Err(Error::DependencyConflict {
+                    plugin_name: plugin_name.to_owned(),
+                    requirement: requirement.to_string(),
+                    version: Some(version.to_string()),
+                })
+            }
+        }
+
+        self.planned.insert(plugin_name.to_owned(), version);
+        Ok(())
+    }
+
+    fn visit(&mut self, update: UpdateResponse) -> Result<(), Error> {
+        self.plan(&update.plugin_name, parse_version(&update.new_plugin_version)?)?;
+        self.path.push(update.plugin_name.clone());
+
+        for dependency in &update.dependencies {
+            let name = &dependency.plugin_name;
+            let requirement = VersionReq::parse(&dependency.version_req)
+                .map_err(|_| Error::InvalidResponse("invalid dependency version requirement"))?;
+
+            if let Some(start) = self.path.iter().position(|plugin| plugin == name) {
+                let mut cycle = self.path[start..].to_vec();
+                cycle.push(name.clone());
+                return Err(Error::DependencyCycle(cycle))
+            }
+
+            self.requirements.entry(name.clone())
+                .or_default()
+                .push(requirement.clone());
+
+            let conflict = |version: Option<&Version>| Error::DependencyConflict {
+                plugin_name: name.clone(),
+                requirement: dependency.version_req.clone(),
+                version: version.map(Version::to_string),
+            };
+
+            // already being updated as a dependency of another plugin
+            if let Some(version) = self.planned.get(name) {
+                if requirement.matches(version) {
+                    continue
+                }
+                return Err(conflict(Some(version)))
+            }
+
+            let installed = (self.installed)(name);
+            if installed.as_ref().map(|version| requirement.matches(version)).unwrap_or(false) {
+                continue
+            }
+
+            let response = (self.fetch)(name, installed.as_ref())?;
+            match response.code {
+                ResponseCode::Update => {
+                    let version = parse_version(&response.new_plugin_version)?;
+                    if !requirement.matches(&version) {
+                        return Err(conflict(Some(&version)))
+                    }
+                    self.visit(response)?;
+                }
+                ResponseCode::PluginNotFound => return Err(Error::DependencyNotFound(name.clone())),
+                _ => return Err(conflict(installed.as_ref())),
+            }
+        }
+
+        self.path.pop();
+        self.order.push(update);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use update_protocol::Dependency;
+
+    fn update(name: &str, version: &str, dependencies: &[(&str, &str)]) -> UpdateResponse {
+        UpdateResponse {
+            code: ResponseCode::Update,
+            plugin_name: name.to_owned(),
+            new_plugin_version: version.to_owned(),
+            dependencies: dependencies.iter()
+                .map(|(plugin_name, version_req)| Dependency {
+                    plugin_name: (*plugin_name).to_owned(),
+                    version_req: (*version_req).to_owned(),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Resolve against a server offering `available` with `installed` already installed
+    fn run(root: UpdateResponse, available: &[UpdateResponse], installed: &[(&str, &str)]) -> Result<Vec<String>, Error> {
+        let installed = |name: &str| {
+            installed.iter()
+                .find(|(plugin, _)| *plugin == name)
+                .map(|(_, version)| version.parse().unwrap())
+        };
+        let fetch = |name: &str, _: Option<&Version>| {
+            Ok(available.iter()
+                .find(|update| update.plugin_name == name)
+                .cloned()
+                .unwrap_or_else(UpdateResponse::plugin_not_found))
+        };
+
+        resolve(&root, installed, fetch)
+            .map(|order| order.into_iter().map(|update| update.plugin_name).collect())
+    }
+
+    #[test]
+    fn installs_dependencies_first() {
+        let available = [
+            update("hook", "1.2.0", &[]),
+            update("lib", "0.3.0", &[("hook", ">=1.0.0")]),
+            update("up_to_date", "1.0.0", &[]),
+        ];
+        let root = update("plugin", "2.0.0", &[("lib", "^0.3"), ("hook", "^1.1"), ("up_to_date", "1")]);
+
+        let order = run(root, &available, &[("hook", "1.0.0"), ("up_to_date", "1.0.0")]).unwrap();
+        assert_eq!(order, ["lib", "hook", "plugin"]);
+    }
+
+    #[test]
+    fn detects_cycles_and_conflicts() {
+        let available = [
+            update("a", "1.0.0", &[("b", "1")]),
+            update("b", "1.0.0", &[("plugin", "1")]),
+            update("hook", "2.0.0", &[]),
+            update("lib", "1.0.0", &[("hook", "^2")]),
+        ];
+
+        let cycle = run(update("plugin", "1.0.0", &[("a", "1")]), &available, &[]);
+        assert!(matches!(cycle, Err(Error::DependencyCycle(ref cycle)) if cycle == &["plugin", "a", "b", "plugin"]));
+
+        let conflict = run(update("plugin", "1.0.0", &[("lib", "1"), ("hook", "^1")]), &available, &[]);
+        assert!(matches!(conflict, Err(Error::DependencyConflict { ref plugin_name, .. }) if plugin_name == "hook"));
+
+        let missing = run(update("plugin", "1.0.0", &[("missing", "1")]), &available, &[]);
+        assert!(matches!(missing, Err(Error::DependencyNotFound(ref name)) if name == "missing"));
+    }
+}
@@ -0,0 +1,40 @@
+//! A minimal HTTP/1.1 client for talking to the update server's HTTP API
+
+use std::io::prelude::*;
+
+use update_protocol::http::{self as protocol, Response};
+
+use crate::Error;
+
+/// Send a request over a fresh connection and read the full response
+pub(crate) fn request<S: Read + Write>(
+    mut stream: S,
+    method: &str,
+    host: &str,
+    path: &str,
+    body: Option<&[u8]>,
+    auth_token: Option<&str>,
+) -> Result<Response, Error> {
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: skyline-update\r\nAccept: */*\r\nConnection: close\r\n",
+        method, path, host
+    );
+    if let Some(token) = auth_token {
+        head += &format!("Authorization: Bearer {}\r\n", token);
+    }
+    if let Some(body) = body {
+        head += &format!("Content-Type: application/json\r\nContent-Length: {}\r\n", body.len());
+    }
+    head += "\r\n";
+
+    stream.write_all(head.as_bytes())?;
+    if let Some(body) = body {
+        stream.write_all(body)?;
+    }
+    stream.flush()?;
+
+    let mut raw = vec![];
+    stream.read_to_end(&mut raw)?;
+
+    Ok(protocol::parse_response(&raw)?)
+}
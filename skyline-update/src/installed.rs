@@ -0,0 +1,32 @@
+//! A record of the plugin versions installed on the SD card, used by the default installer to
+//! decide which dependencies of an update need installing
+
+use std::collections::BTreeMap;
+
+use log::warn;
+
+const INSTALLED_PATH: &str = "sd:/atmosphere/skyline-update/installed.json";
+
+fn load() -> BTreeMap<String, String> {
+    std::fs::read(INSTALLED_PATH)
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+pub(crate) fn version(plugin_name: &str) -> Option<String> {
+    load().remove(plugin_name)
+}
+
+pub(crate) fn set_version(plugin_name: &str, version: &str) {
+    let mut installed = load();
+    if installed.get(plugin_name).map(String::as_str) == Some(version) {
+        return
+    }
+    installed.insert(plugin_name.to_owned(), version.to_owned());
+
+    let _ = std::fs::create_dir_all("sd:/atmosphere/skyline-update");
+    if let Err(e) = std::fs::write(INSTALLED_PATH, serde_json::to_vec(&installed).unwrap()) {
+        warn!("Error recording installed version of {}: {}", plugin_name, e);
+    }
+}
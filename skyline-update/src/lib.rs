@@ -1,13 +1,55 @@
-use std::path::PathBuf;
+use std::fmt;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::io::prelude::*;
-use std::net::{TcpStream, IpAddr};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
 
-use update_protocol::{Request, ResponseCode};
+use update_protocol::{Capability, Request, ResponseCode, UpdateFile, UpdateRequestOptions};
+use update_protocol::{ListResponse, UpdateManyResponse, UpdateQuery, sha256_hex};
+use update_protocol::version::{self, Version};
+use update_protocol::frame::{self, FrameKind};
 
-pub use update_protocol::UpdateResponse;
+use log::{debug, error, info, warn};
+use timeout::TimeoutStream;
+
+pub use update_protocol::{GameInfo, UpdateResponse};
+pub use background::BackgroundUpdate;
+pub use logger::PrintLogger;
+pub use retry::{DEFAULT_BACKOFF, DEFAULT_RETRIES};
+pub use timeout::{DEFAULT_CONNECT_TIMEOUT, DEFAULT_READ_TIMEOUT};
+
+mod http;
+mod background;
+mod dependencies;
+mod logger;
+mod retry;
+mod timeout;
+
+#[cfg(target_os = "switch")]
+mod installed;
+
+#[cfg(feature = "tls")]
+mod tls;
+
+#[cfg(feature = "tls")]
+pub use tls::TlsOptions;
 
 const PORT: u16 = 45000;
 
+/// Optional protocol features supported by this client
+const CAPABILITIES: &[Capability] = &[Capability::ExtendedResponseCodes, Capability::Framed, Capability::UpdateMany];
+
+/// Default port of the update server's HTTP API
+pub const HTTP_PORT: u16 = 80;
+
+/// Default port of the update server's HTTP API over TLS
+pub const HTTPS_PORT: u16 = 443;
+
+/// Port for TLS connections to the request port, TLS downloads use the port after it
+pub const TLS_PORT: u16 = 45443;
+
 pub struct DefaultInstaller;
 
 #[cfg(not(target_os = "switch"))]
@@ -16,11 +58,15 @@ impl Installer for DefaultInstaller {
         true
     }
 
+    fn should_update_many(&self, _: &[UpdateResponse]) -> bool {
+        true
+    }
+
     fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), ()> {
-        println!("Installing {} bytes to path {}", buf.len(), path.display());
+        info!("Installing {} bytes to path {}", buf.len(), path.display());
 
         if let Ok(string) = String::from_utf8(buf) {
-            println!("As string: {:?}", string);
+            debug!("As string: {:?}", string);
         }
 
         Ok(())
@@ -37,10 +83,30 @@ impl Installer for DefaultInstaller {
         ))
     }
 
+    fn should_update_many(&self, responses: &[UpdateResponse]) -> bool {
+        let updates = responses.iter()
+            .map(|response| format!("{} (Ver. {})", response.plugin_name, response.new_plugin_version))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        skyline_web::Dialog::yes_no(format!(
+            "Updates have been found for:\n\n{}\n\nWould you like to download them?",
+            updates,
+        ))
+    }
+
+    fn installed_version(&self, plugin_name: &str) -> Option<String> {
+        installed::version(plugin_name)
+    }
+
+    fn set_installed_version(&self, plugin_name: &str, version: &str) {
+        installed::set_version(plugin_name, version)
+    }
+
     fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), ()> {
         let _ = std::fs::create_dir_all(path.parent().ok_or(())?);
         if let Err(e) = std::fs::write(path, buf) {
-            println!("[updater] Error writing file to sd: {}", e);
+            error!("Error writing file to sd: {}", e);
             Err(())
         } else {
             Ok(())
@@ -48,102 +114,976 @@ impl Installer for DefaultInstaller {
     }
 }
 
+/// Normalize a plugin version before sending it to the server, accepting `git describe` output
+/// and a leading `v` (see [`update_protocol::version`])
+fn normalize_version(version: &str) -> Result<String, Error> {
+    version.parse::<Version>()
+        .map(|version| version.to_string())
+        .map_err(Error::InvalidVersion)
+}
+
+/// Check an access token can be sent in a header and to the download port, rejecting tokens
+/// containing control characters such as CR or LF, or longer than [`update_protocol::MAX_TOKEN_LEN`]
+fn valid_token(token: String) -> Option<String> {
+    if token.chars().any(char::is_control) {
+        warn!("Ignoring access token containing control characters");
+        None
+    } else if token.len() > update_protocol::MAX_TOKEN_LEN {
+        warn!("Ignoring access token longer than {} bytes", update_protocol::MAX_TOKEN_LEN);
+        None
+    } else {
+        Some(token)
+    }
+}
+
+/// The game the plugin is running in
+#[cfg(target_os = "switch")]
+fn current_game() -> Option<GameInfo> {
+    let mut display_version = skyline::nn::oe::DisplayVersion { name: [0; 16] };
+    let version = unsafe {
+        skyline::nn::oe::GetDisplayVersion(&mut display_version);
+        std::ffi::CStr::from_ptr(display_version.name.as_ptr() as _)
+    };
+
+    Some(GameInfo {
+        title_id: skyline::info::get_program_id(),
+        version: version.to_string_lossy().into_owned(),
+    })
+}
+
+#[cfg(not(target_os = "switch"))]
+fn current_game() -> Option<GameInfo> {
+    None
+}
+
 /// An installer for use with custom_check_update
 pub trait Installer {
     fn should_update(&self, response: &UpdateResponse) -> bool;
     fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), ()>;
+
+    /// Ask whether to install all of the updates found by a batch check, by default asking
+    /// about each update in turn
+    fn should_update_many(&self, responses: &[UpdateResponse]) -> bool {
+        responses.iter().all(|response| self.should_update(response))
+    }
+
+    /// The installed version of a plugin, used to decide which dependencies of an update need
+    /// installing. By default nothing is known to be installed, so dependencies are always
+    /// installed at their newest version.
+    fn installed_version(&self, _plugin_name: &str) -> Option<String> {
+        None
+    }
+
+    /// Called when a plugin checks for updates with its current version, and after a plugin
+    /// has been installed or updated
+    fn set_installed_version(&self, _plugin_name: &str, _version: &str) {}
 }
 
-fn update<I>(ip: IpAddr, response: &UpdateResponse, installer: &I) -> bool
-    where I: Installer,
-{
-    for file in &response.required_files {
-        if let Ok(mut stream) = TcpStream::connect((ip, PORT + 1)) {
-            let mut buf = vec![];
-            let _ = stream.write_all(&u64::to_be_bytes(file.download_index));
-            if let Err(e) = stream.read_to_end(&mut buf) {
-                println!("[updater] Error downloading file: {}", e);
-                return false
+/// An error encountered while communicating with the update server
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Json(serde_json::Error),
+    /// The HTTP API responded with a non-success status code
+    HttpStatus(u16),
+    /// The connection was closed before the full response was received
+    Truncated { expected: u64, received: u64 },
+    InvalidResponse(&'static str),
+    /// The TLS handshake failed, including if the certificate didn't match a pinned fingerprint
+    Tls(String),
+    /// The server responded to a framed protocol request with an error
+    Server(String),
+    /// A dependency of the update isn't hosted on the update server
+    DependencyNotFound(String),
+    /// No version of a dependency available satisfies every plugin requiring it
+    DependencyConflict { plugin_name: String, requirement: String, version: Option<String> },
+    /// Plugins which (indirectly) depend on themselves, starting and ending with the same plugin
+    DependencyCycle(Vec<String>),
+    /// The version of the plugin being checked couldn't be understood, and wasn't sent
+    InvalidVersion(version::ParseError),
+    /// The installer failed to install a file
+    Install(PathBuf),
+    /// A downloaded file didn't match the SHA-256 hash given by the server
+    HashMismatch { expected: String, actual: String },
+    /// The server didn't connect or respond in time, or the deadline for the whole check or
+    /// install passed. Usually means the console is offline or the server is down.
+    Timeout,
+}
+
+impl Error {
+    /// Whether the update server didn't respond in time, see [`Error::Timeout`]
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, Error::Timeout)
+    }
+
+    /// Whether the error may go away by trying again, such as the connection failing or a
+    /// download being cut short
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Io(_) | Error::Timeout | Error::Truncated { .. } | Error::HashMismatch { .. } => true,
+            Error::HttpStatus(status) => (500..600).contains(status),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Json(e) => write!(f, "invalid response from server: {}", e),
+            Error::HttpStatus(status) => write!(f, "server responded with HTTP status {}", status),
+            Error::Truncated { expected, received } => {
+                write!(f, "connection closed after {} of {} bytes", received, expected)
+            }
+            Error::InvalidResponse(reason) => write!(f, "invalid response from server: {}", reason),
+            Error::Tls(e) => write!(f, "TLS error: {}", e),
+            Error::Server(e) => write!(f, "server error: {}", e),
+            Error::DependencyNotFound(name) => write!(f, "dependency {} not found on the update server", name),
+            Error::DependencyConflict { plugin_name, requirement, version: Some(version) } => {
+                write!(f, "dependency {} {} does not satisfy the requirement {}", plugin_name, version, requirement)
+            }
+            Error::DependencyConflict { plugin_name, requirement, version: None } => {
+                write!(f, "no version of dependency {} satisfies the requirement {}", plugin_name, requirement)
+            }
+            Error::DependencyCycle(cycle) => write!(f, "dependency cycle: {}", cycle.join(" -> ")),
+            Error::InvalidVersion(e) => write!(f, "{}", e),
+            Error::Install(path) => write!(f, "failed to install {}", path.display()),
+            Error::HashMismatch { expected, actual } => {
+                write!(f, "downloaded file has SHA-256 {} instead of {}", actual, expected)
+            }
+            Error::Timeout => write!(f, "timed out waiting for the update server"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            // read timeouts are reported as `WouldBlock` on some platforms
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Error::Timeout,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl From<update_protocol::http::ParseError> for Error {
+    fn from(e: update_protocol::http::ParseError) -> Self {
+        match e {
+            update_protocol::http::ParseError::Truncated { expected, received } => Error::Truncated { expected, received },
+            update_protocol::http::ParseError::Invalid(reason) => Error::InvalidResponse(reason),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+/// A plugin to check for updates with [`Client::check_updates`]
+#[derive(Debug, Clone)]
+pub struct UpdateCheck {
+    pub name: String,
+    pub version: String,
+    pub allow_beta: bool,
+}
+
+impl UpdateCheck {
+    pub fn new(name: impl Into<String>, version: impl Into<String>, allow_beta: bool) -> Self {
+        Self {
+            name: name.into(),
+            version: version.into(),
+            allow_beta,
+        }
+    }
+}
+
+/// How to communicate with the update server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transport {
+    /// The original protocol: a JSON request on port 45000 and downloads from port 45001
+    #[default]
+    Tcp,
+    /// The HTTP API, for servers behind reverse proxies, CDNs or firewalls which only allow HTTP
+    Http { port: u16 },
+    /// The framed protocol on port 45000, which sends the request and all downloads for an update
+    /// over a single connection
+    Framed,
+}
+
+/// A connection to the update server, either plain TCP or TLS
+trait Stream: Read + Write {}
+
+impl<S: Read + Write> Stream for S {}
+
+/// State kept for a single check or install, so the framed transport can reuse one connection
+/// for the request and every download
+struct Session {
+    /// The framed connection, and the index of the server it is connected to
+    framed: Option<(usize, Box<dyn Stream>)>,
+    /// Whether dependencies may be installed from the beta channel
+    allow_beta: bool,
+    /// When to give up on the check or install, see [`Client::deadline`]
+    deadline: Option<Instant>,
+    /// The server each update was received from, which its files are downloaded from first
+    sources: HashMap<String, usize>,
+}
+
+/// A connection configuration for an update server, and any mirrors of it
+///
+/// ```rust,no_run
+/// use skyline_update::{Client, Transport};
+///
+/// Client::with_host("updates.example.com")
+///     .mirror("mirror.example.com")
+///     .transport(Transport::Http { port: 80 })
+///     .check_update("plugin_name", env!("CARGO_PKG_VERSION"), false);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Client {
+    /// The server followed by its mirrors, in the order they are tried
+    hosts: Vec<String>,
+    transport: Transport,
+    game: Option<GameInfo>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    deadline: Option<Duration>,
+    retries: u32,
+    backoff: Duration,
+    auth_token: Option<String>,
+
+    #[cfg(feature = "tls")]
+    tls: Option<std::sync::Arc<rustls::ClientConfig>>,
+}
+
+impl Client {
+    /// Connect to an update server by IP using the original TCP protocol
+    pub fn new(ip: IpAddr) -> Self {
+        Self::with_host(ip.to_string())
+    }
+
+    /// Connect to an update server by host name or IP address
+    pub fn with_host(host: impl Into<String>) -> Self {
+        Self {
+            hosts: vec![host.into()],
+            transport: Transport::default(),
+            game: current_game(),
+            connect_timeout: Some(DEFAULT_CONNECT_TIMEOUT),
+            read_timeout: Some(DEFAULT_READ_TIMEOUT),
+            deadline: None,
+            retries: DEFAULT_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            auth_token: None,
+
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Add a server to fall back to when the servers before it can't be reached. Files are
+    /// downloaded from the server which offered the update, or from any mirror serving a file
+    /// with the same hash if that fails. Every server is used with the same transport.
+    pub fn mirror(mut self, host: impl Into<String>) -> Self {
+        self.hosts.push(host.into());
+        self
+    }
+
+    /// Retry transient failures, like a refused connection or a download being cut short, up to
+    /// `retries` times on each server before moving on to the next. The first retry waits
+    /// around `backoff`, doubling for every retry after it. Defaults to [`DEFAULT_RETRIES`]
+    /// and [`DEFAULT_BACKOFF`].
+    pub fn retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    /// Set how to communicate with the update server
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Give up connecting, or waiting for data from the server, after `timeout`. Shorthand for
+    /// setting both [`Client::connect_timeout`] and [`Client::read_timeout`].
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.connect_timeout(Some(timeout)).read_timeout(Some(timeout))
+    }
+
+    /// Give up connecting to the server after `timeout`, or never if `None`. Defaults to
+    /// [`DEFAULT_CONNECT_TIMEOUT`].
+    pub fn connect_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Give up when the server stops sending (or accepting) data for `timeout`, or never if
+    /// `None`. Defaults to [`DEFAULT_READ_TIMEOUT`].
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// Give up on a check, or on installing an update, if it takes longer than `deadline` in
+    /// total, including every download. A check followed by an install each get the full
+    /// deadline. By default there is no deadline.
+    pub fn deadline(mut self, deadline: Option<Duration>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Set the game sent to the server, which only offers builds compatible with it. On the
+    /// Switch this defaults to the running game, elsewhere to `None`, which accepts any build.
+    pub fn game(mut self, game: Option<GameInfo>) -> Self {
+        self.game = game;
+        self
+    }
+
+    /// Send an access token with every request and download, for plugins (or beta channels)
+    /// which the server only serves to clients with a token
+    pub fn auth_token(mut self, token: impl Into<String>) -> Self {
+        self.auth_token = valid_token(token.into());
+        self
+    }
+
+    /// Load the access token from a file, such as `sd:/atmosphere/skyline-update/plugin.token`.
+    /// Surrounding whitespace is ignored. If the file is missing or empty no token is sent, so
+    /// the same build of a plugin works for users with and without access.
+    pub fn auth_token_file(mut self, path: impl AsRef<Path>) -> Self {
+        self.auth_token = std::fs::read_to_string(path)
+            .ok()
+            .map(|token| token.trim().to_owned())
+            .filter(|token| !token.is_empty())
+            .and_then(valid_token);
+        self
+    }
+
+    /// Connect using TLS, for both the TCP and HTTP transports. When using the TCP transport,
+    /// requests (and framed protocol connections) are sent to [`TLS_PORT`] and files downloaded
+    /// from the port after it.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, options: TlsOptions) -> Self {
+        self.tls = Some(options.client_config());
+        self
+    }
+
+    fn use_tls(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.tls.is_some();
+
+        #[cfg(not(feature = "tls"))]
+        return false;
+    }
+
+    /// Port of the TCP protocol's request port, downloads use the port after it
+    fn tcp_port(&self) -> u16 {
+        #[cfg(feature = "tls")]
+        if self.use_tls() {
+            return TLS_PORT
+        }
+
+        PORT
+    }
+
+    /// Start a check or install, with its deadline starting now
+    fn session(&self, allow_beta: bool) -> Session {
+        Session {
+            framed: None,
+            allow_beta,
+            deadline: self.deadline.map(|deadline| Instant::now() + deadline),
+            sources: HashMap::new(),
+        }
+    }
+
+    fn connect(&self, session: &Session, server: usize, port: u16) -> Result<Box<dyn Stream>, Error> {
+        let host = &self.hosts[server];
+        let stream = TimeoutStream::connect(host, port, self.connect_timeout, self.read_timeout, session.deadline)?;
+
+        #[cfg(feature = "tls")]
+        if let Some(config) = &self.tls {
+            return Ok(Box::new(tls::connect(config, host, stream)?))
+        }
+
+        Ok(Box::new(stream))
+    }
+
+    /// Value of the HTTP `Host` header
+    fn http_host(&self, server: usize, port: u16) -> String {
+        let host = match self.hosts[server].parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{}]", ip),
+            _ => self.hosts[server].clone(),
+        };
+
+        let default_port = if self.use_tls() { HTTPS_PORT } else { HTTP_PORT };
+        if port == default_port {
+            host
+        } else {
+            format!("{}:{}", host, port)
+        }
+    }
+
+    /// Send a frame over the session's framed connection to `server`, connecting first if
+    /// needed, and read the response frame
+    fn framed_exchange(&self, session: &mut Session, server: usize, kind: FrameKind, payload: &[u8], expected: FrameKind, max_len: u32) -> Result<Vec<u8>, Error> {
+        if !matches!(session.framed, Some((connected, _)) if connected == server) {
+            session.framed = None;
+            let mut stream = self.connect(session, server, self.tcp_port())?;
+            frame::write_handshake(&mut stream)?;
+            if frame::read_handshake(&mut stream)? != frame::VERSION {
+                return Err(Error::InvalidResponse("unsupported framed protocol version"))
+            }
+            session.framed = Some((server, stream));
+        }
+
+        let (_, stream) = session.framed.as_mut().unwrap();
+        let response = frame::write_frame(stream, kind, payload)
+            .and_then(|_| frame::read_frame(stream, max_len));
+
+        match response {
+            Ok(Some(response)) if response.kind == expected => Ok(response.payload),
+            Ok(Some(response)) if response.kind == FrameKind::Error => {
+                Err(Error::Server(String::from_utf8_lossy(&response.payload).into_owned()))
+            }
+            result => {
+                // the connection can't be trusted to be in sync anymore
+                session.framed = None;
+                match result {
+                    Err(e) => Err(e.into()),
+                    Ok(None) => Err(Error::InvalidResponse("connection closed")),
+                    Ok(Some(_)) => Err(Error::InvalidResponse("unexpected frame")),
+                }
+            }
+        }
+    }
+
+    /// Send a request to a single server, returning the raw response
+    fn send_request_to(&self, session: &mut Session, server: usize, request: &Request) -> Result<Vec<u8>, Error> {
+        let packet = serde_json::to_string(request)?;
+
+        let response = match self.transport {
+            Transport::Tcp => {
+                let mut stream = self.connect(session, server, self.tcp_port())?;
+                stream.write_fmt(format_args!("{}\n", packet))?;
+                let mut response = vec![];
+                stream.read_to_end(&mut response)?;
+                response
+            }
+            Transport::Http { port } => {
+                let stream = self.connect(session, server, port)?;
+                let response = http::request(stream, "POST", &self.http_host(server, port), "/v1/update", Some(packet.as_bytes()), None)?;
+                if response.status != 200 {
+                    return Err(Error::HttpStatus(response.status))
+                }
+                response.body
+            }
+            Transport::Framed => {
+                self.framed_exchange(session, server, FrameKind::Request, packet.as_bytes(), FrameKind::Response, frame::MAX_RESPONSE_FRAME)?
+            }
+        };
+
+        Ok(response)
+    }
+
+    /// Send a request to each server in turn until one responds, retrying transient failures,
+    /// returning which server responded along with the raw response
+    fn send_request(&self, session: &mut Session, request: &Request) -> Result<(usize, Vec<u8>), Error> {
+        let deadline = session.deadline;
+        let mut last_error = None;
+
+        for server in 0..self.hosts.len() {
+            if let Some(e) = &last_error {
+                warn!("Update server {} failed ({}), trying {}", self.hosts[server - 1], e, self.hosts[server]);
+            }
+
+            let response = retry::retry(self.retries, self.backoff, deadline, || {
+                self.send_request_to(session, server, request)
+            });
+
+            match response {
+                Ok(response) => return Ok((server, response)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(last_error.unwrap_or(Error::InvalidResponse("no update servers")))
+    }
+
+    /// Download a file from a single server, checking its size and hash
+    fn download_from(&self, session: &mut Session, server: usize, file: &UpdateFile) -> Result<Vec<u8>, Error> {
+        let data = match self.transport {
+            Transport::Tcp => {
+                let mut stream = self.connect(session, server, self.tcp_port() + 1)?;
+                stream.write_all(&update_protocol::download_request(file.download_index, self.auth_token.as_deref()))?;
+                let mut buf = vec![];
+                stream.read_to_end(&mut buf)?;
+                buf
+            }
+            Transport::Http { port } => {
+                let stream = self.connect(session, server, port)?;
+                let path = format!("/v1/files/{}", file.download_index);
+                let response = http::request(stream, "GET", &self.http_host(server, port), &path, None, self.auth_token.as_deref())?;
+                if response.status != 200 {
+                    return Err(Error::HttpStatus(response.status))
+                }
+                response.body
+            }
+            Transport::Framed => {
+                let mut request = u64::to_be_bytes(file.download_index).to_vec();
+                request.extend(self.auth_token.iter().flat_map(|token| token.bytes()));
+                self.framed_exchange(session, server, FrameKind::FileRequest, &request, FrameKind::FileData, frame::max_file_frame(file.size))?
             }
+        };
+
+        if data.len() != file.size {
+            return Err(Error::Truncated { expected: file.size as u64, received: data.len() as u64 })
+        }
+
+        if let Some(expected) = &file.sha256 {
+            let actual = sha256_hex(&data);
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Err(Error::HashMismatch { expected: expected.clone(), actual })
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// List the releases hosted by a server, which unlike an update check isn't counted in its
+    /// statistics
+    fn list_releases(&self, session: &mut Session, server: usize) -> Result<ListResponse, Error> {
+        let response = match self.transport {
+            Transport::Http { port } => {
+                let stream = self.connect(session, server, port)?;
+                let response = http::request(stream, "GET", &self.http_host(server, port), "/v1/releases", None, None)?;
+                if response.status != 200 {
+                    return Err(Error::HttpStatus(response.status))
+                }
+                response.body
+            }
+            _ => self.send_request_to(session, server, &Request::List {})?,
+        };
+
+        Ok(serde_json::from_slice(&response)?)
+    }
+
+    /// Find the file with the same hash as `file` in the same release on another server, so it
+    /// can be downloaded from there instead
+    fn find_on_mirror(&self, session: &mut Session, server: usize, update: &UpdateResponse, file: &UpdateFile) -> Result<UpdateFile, Error> {
+        let deadline = session.deadline;
+        match retry::retry(self.retries, self.backoff, deadline, || self.list_releases(session, server)) {
+            Ok(list) => list.releases.into_iter()
+                .filter(|release| release.plugin_name == update.plugin_name && release.version == update.new_plugin_version)
+                .flat_map(|release| release.files)
+                .find(|mirrored| mirrored.sha256.is_some() && mirrored.sha256 == file.sha256)
+                .ok_or(Error::InvalidResponse("file not found on mirror")),
+            // servers which can't list their releases are asked for their newest build instead
+            Err(e) if !e.is_transient() => self.find_in_newest(session, server, update, file),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Find the file with the same hash as `file` in the newest build of the plugin on another
+    /// server, for servers which predate [`Request::List`]
+    fn find_in_newest(&self, session: &mut Session, server: usize, update: &UpdateResponse, file: &UpdateFile) -> Result<UpdateFile, Error> {
+        let request = Request::Update {
+            beta: Some(session.allow_beta),
+            plugin_name: update.plugin_name.clone(),
+            plugin_version: "0.0.0".to_owned(),
+            options: Some(UpdateRequestOptions::new(CAPABILITIES.to_vec()).game(self.game.clone())),
+            auth_token: self.auth_token.clone(),
+        };
+
+        let deadline = session.deadline;
+        let response = retry::retry(self.retries, self.backoff, deadline, || {
+            self.send_request_to(session, server, &request)
+        })?;
+
+        serde_json::from_slice::<UpdateResponse>(&response)?
+            .required_files
+            .into_iter()
+            .find(|mirrored| mirrored.sha256.is_some() && mirrored.sha256 == file.sha256)
+            .ok_or(Error::InvalidResponse("file not found on mirror"))
+    }
+
+    /// Download a file of an update from the server the update came from, falling back to any
+    /// other server with a file of the same hash. Files without a hash are only downloaded from
+    /// the server the update came from.
+    fn download(&self, session: &mut Session, update: &UpdateResponse, file: &UpdateFile) -> Result<Vec<u8>, Error> {
+        let source = session.sources.get(&update.plugin_name).copied().unwrap_or(0);
+        let deadline = session.deadline;
+
+        let mut error = match retry::retry(self.retries, self.backoff, deadline, || self.download_from(session, source, file)) {
+            Ok(data) => return Ok(data),
+            Err(e) => e,
+        };
+
+        if file.sha256.is_none() {
+            return Err(error)
+        }
+
+        for server in (0..self.hosts.len()).filter(|&server| server != source) {
+            warn!("Error downloading file ({}), trying mirror {}", error, self.hosts[server]);
+
+            let data = self.find_on_mirror(session, server, update, file).and_then(|mirrored| {
+                retry::retry(self.retries, self.backoff, deadline, || self.download_from(session, server, &mirrored))
+            });
+
+            match data {
+                Ok(data) => return Ok(data),
+                Err(e) => error = e,
+            }
+        }
+
+        Err(error)
+    }
+
+    /// Install an update along with any missing or outdated dependencies, dependencies first
+    fn update<I>(&self, session: &mut Session, response: &UpdateResponse, installer: &I) -> Result<(), Error>
+        where I: Installer,
+    {
+        let allow_beta = session.allow_beta;
+        let updates = dependencies::resolve(
+            response,
+            |name| {
+                installer.installed_version(name)
+                    .and_then(|version| normalize_version(&version).ok())
+                    .and_then(|version| version.parse().ok())
+            },
+            |name, installed| {
+                let installed = installed.map(ToString::to_string).unwrap_or_else(|| "0.0.0".to_owned());
+                self.request_update(session, name, &installed, allow_beta)
+            },
+        )?;
+
+        for update in &updates {
+            if update.plugin_name != response.plugin_name {
+                info!("Installing dependency {} (Ver. {})", update.plugin_name, update.new_plugin_version);
+            }
+            self.install_files(session, update, installer)?;
+            installer.set_installed_version(&update.plugin_name, &update.new_plugin_version);
+        }
+
+        Ok(())
+    }
+
+    fn install_files<I>(&self, session: &mut Session, response: &UpdateResponse, installer: &I) -> Result<(), Error>
+        where I: Installer,
+    {
+        for file in &response.required_files {
             let path: PathBuf = match &file.install_location {
                 update_protocol::InstallLocation::AbsolutePath(path) => path.into(),
-                _ => return false
+                _ => return Err(Error::InvalidResponse("unsupported install location"))
             };
 
-            if installer.install_file(path, buf).is_err() {
-                return false
-            }
-        } else {
-            println!("[updater] Failed to connect to port {}", PORT + 1);
-            return false
+            let buf = self.download(session, response, file)?;
+            installer.install_file(path.clone(), buf).map_err(|()| Error::Install(path))?;
         }
+        info!("Finished updating {}", response.plugin_name);
+        Ok(())
     }
-    println!("[updater] finished updating plugin.");
-    true
-}
 
-/// Install an update with a custom installer implementation
-pub fn custom_check_update<I>(ip: IpAddr, name: &str, version: &str, allow_beta: bool, installer: &I) -> bool
-    where I: Installer,
-{
-    match TcpStream::connect((ip, PORT)) {
-        Ok(mut stream) =>  {
-            if let Ok(packet) = serde_json::to_string(&Request::Update {
-                beta: Some(allow_beta),
-                plugin_name: name.to_owned(),
-                plugin_version: version.to_owned(),
-                options: None,
-            }) {
-                let _ = stream.write_fmt(format_args!("{}\n", packet));
-                let mut string = String::new();
-                let _ = stream.read_to_string(&mut string);
-
-                if let Ok(response) = serde_json::from_str::<UpdateResponse>(&string) {
-                    match response.code {
-                        ResponseCode::NoUpdate => return false,
-                        ResponseCode::Update => {
-                            if installer.should_update(&response) {
-                                let success = update(ip, &response, installer);
-
-                                if !success {
-                                    println!("[{} updater] Failed to install update, files may be left in a broken state.", name);
-                                }
-
-                                success
-                            } else {
-                                false
-                            }
-                        }
-                        ResponseCode::InvalidRequest => {
-                            println!("[{} updater] Failed to send a valid request to the server", name);
-                            false
-                        }
-                        ResponseCode::PluginNotFound => {
-                            println!("Plugin '{}' could not be found on the update server", name);
-                            false
-                        }
-                        _ => {
-                            println!("Unexpected response");
-                            false
-                        }
-                    }
-                } else {
-                    println!("[{} updater] Failed to parse update server response: {:?}", name, string);
-                    false
+    fn request_update(&self, session: &mut Session, name: &str, version: &str, allow_beta: bool) -> Result<UpdateResponse, Error> {
+        let (server, response) = self.send_request(session, &Request::Update {
+            beta: Some(allow_beta),
+            plugin_name: name.to_owned(),
+            plugin_version: normalize_version(version)?,
+            options: Some(UpdateRequestOptions::new(CAPABILITIES.to_vec()).game(self.game.clone())),
+            auth_token: self.auth_token.clone(),
+        })?;
+
+        session.sources.insert(name.to_owned(), server);
+        Ok(serde_json::from_slice(&response)?)
+    }
+
+    /// Check several plugins at once, falling back to checking them one at a time for servers
+    /// which don't support batch checks
+    fn request_updates(&self, session: &mut Session, plugins: &[UpdateCheck]) -> Result<Vec<UpdateResponse>, Error> {
+        let queries = plugins.iter()
+            .map(|plugin| {
+                Ok(UpdateQuery {
+                    plugin_name: plugin.name.clone(),
+                    plugin_version: normalize_version(&plugin.version)?,
+                    beta: Some(plugin.allow_beta),
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        let (server, response) = self.send_request(session, &Request::UpdateMany {
+            plugins: queries,
+            options: Some(UpdateRequestOptions::new(CAPABILITIES.to_vec()).game(self.game.clone())),
+            auth_token: self.auth_token.clone(),
+        })?;
+
+        match serde_json::from_slice::<UpdateManyResponse>(&response) {
+            Ok(response) if response.responses.len() == plugins.len() => {
+                for plugin in plugins {
+                    session.sources.insert(plugin.name.clone(), server);
                 }
-            } else {
-                println!("[{} updater] Failed to encode packet", name);
+                Ok(response.responses)
+            }
+            Ok(_) => Err(Error::InvalidResponse("wrong number of responses to batch update check")),
+            Err(_) => {
+                // older servers respond to unknown requests with an invalid request response
+                plugins.iter()
+                    .map(|plugin| self.request_update(session, &plugin.name, &plugin.version, plugin.allow_beta))
+                    .collect()
+            }
+        }
+    }
+
+    /// Report why a plugin isn't being updated, returning false
+    fn report_no_update(name: &str, code: &ResponseCode) -> bool {
+        match code {
+            ResponseCode::NoUpdate | ResponseCode::Update => {}
+            ResponseCode::InvalidRequest => {
+                error!("[{}] Failed to send a valid request to the server", name);
+            }
+            ResponseCode::PluginNotFound => {
+                warn!("[{}] Plugin could not be found on the update server", name);
+            }
+            ResponseCode::IncompatibleGame => {
+                info!("[{}] No build compatible with this version of the game is available", name);
+            }
+            ResponseCode::Unauthorized => {
+                warn!("[{}] The update server requires a valid access token for this plugin", name);
+            }
+            ResponseCode::Unknown => {
+                warn!("[{}] The update server sent a response this version of the updater doesn't understand", name);
+            }
+            _ => {
+                warn!("[{}] Unexpected response {:?}", name, code);
+            }
+        }
+
+        false
+    }
+
+    /// Report an error checking for or installing an update
+    fn report_error(&self, name: &str, error: &Error) {
+        match error {
+            Error::Io(e) => {
+                error!("[{}] Failed to connect to update server {}: {:?}", name, self.hosts.join(", "), e);
+            }
+            Error::Timeout => {
+                error!("[{}] Timed out waiting for update server {}", name, self.hosts.join(", "));
+            }
+            Error::InvalidVersion(e) => {
+                warn!("[{}] Not checking for updates, {}", name, e);
+            }
+            Error::DependencyNotFound(_) | Error::DependencyConflict { .. } | Error::DependencyCycle(_) => {
+                error!("[{}] Failed to resolve dependencies: {}", name, error);
+            }
+            Error::Install(_) => {
+                error!("[{}] {}", name, error);
+            }
+            e => {
+                error!("[{}] Failed to get a response from the update server: {}", name, e);
+            }
+        }
+    }
+
+    /// Check a plugin for an update, reporting why if there isn't one available
+    fn check<I>(&self, session: &mut Session, name: &str, version: &str, installer: &I) -> Result<Option<UpdateResponse>, Error>
+        where I: Installer,
+    {
+        if let Ok(version) = normalize_version(version) {
+            installer.set_installed_version(name, &version);
+        }
+
+        let response = self.request_update(session, name, version, session.allow_beta)?;
+        match response.code {
+            ResponseCode::Update => Ok(Some(response)),
+            code => {
+                Self::report_no_update(name, &code);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Install an update, restarting the session's deadline since the user may have taken a
+    /// while to answer the prompt
+    fn install_after_prompt<I>(&self, session: &mut Session, response: &UpdateResponse, installer: &I) -> Result<(), Error>
+        where I: Installer,
+    {
+        session.deadline = self.session(session.allow_beta).deadline;
+        self.update(session, response, installer).inspect_err(|_| {
+            error!("[{}] Failed to install update, files may be left in a broken state.", response.plugin_name);
+        })
+    }
+
+    /// Ask the user whether to install an update found by [`Client::check`], and install it if so
+    fn prompt_and_update<I>(&self, session: &mut Session, response: &UpdateResponse, installer: &I) -> Result<bool, Error>
+        where I: Installer,
+    {
+        if !installer.should_update(response) {
+            return Ok(false)
+        }
+
+        self.install_after_prompt(session, response, installer)?;
+        Ok(true)
+    }
+
+    /// Check for an update on a background thread with a custom installer, see
+    /// [`BackgroundUpdate`] for when the user is prompted
+    pub fn custom_check_update_in_background<I>(&self, name: &str, version: &str, allow_beta: bool, installer: I) -> BackgroundUpdate
+        where I: Installer + Send + 'static,
+    {
+        BackgroundUpdate::spawn(self.clone(), name.to_owned(), version.to_owned(), allow_beta, installer)
+    }
+
+    /// Check for an update on a background thread using the default installer
+    ///
+    /// ```rust,no_run
+    /// use std::time::Duration;
+    /// use skyline_update::Client;
+    ///
+    /// let update = Client::new("127.0.0.1".parse().unwrap())
+    ///     .timeout(Duration::from_secs(5))
+    ///     .check_update_in_background("plugin_name", env!("CARGO_PKG_VERSION"), false);
+    ///
+    /// // later, once it's safe to show a dialog
+    /// update.prompt();
+    /// ```
+    pub fn check_update_in_background(&self, name: &str, version: &str, allow_beta: bool) -> BackgroundUpdate {
+        self.custom_check_update_in_background(name, version, allow_beta, DefaultInstaller)
+    }
+
+    /// Install an update with a custom installer implementation, returning the error if the
+    /// check or install failed so the plugin can fall back, for example when
+    /// [`Error::is_timeout`] because the console is offline
+    ///
+    /// ```rust,no_run
+    /// use std::time::Duration;
+    /// use skyline_update::{Client, DefaultInstaller};
+    ///
+    /// let result = Client::new("127.0.0.1".parse().unwrap())
+    ///     .deadline(Some(Duration::from_secs(10)))
+    ///     .try_custom_check_update("plugin_name", env!("CARGO_PKG_VERSION"), false, &DefaultInstaller);
+    ///
+    /// if matches!(result, Err(ref e) if e.is_timeout()) {
+    ///     // offline, carry on with the installed version
+    /// }
+    /// ```
+    pub fn try_custom_check_update<I>(&self, name: &str, version: &str, allow_beta: bool, installer: &I) -> Result<bool, Error>
+        where I: Installer,
+    {
+        let mut session = self.session(allow_beta);
+        match self.check(&mut session, name, version, installer)? {
+            Some(response) => self.prompt_and_update(&mut session, &response, installer),
+            None => Ok(false),
+        }
+    }
+
+    /// Install an update with a custom installer implementation
+    pub fn custom_check_update<I>(&self, name: &str, version: &str, allow_beta: bool, installer: &I) -> bool
+        where I: Installer,
+    {
+        self.try_custom_check_update(name, version, allow_beta, installer)
+            .unwrap_or_else(|e| {
+                self.report_error(name, &e);
                 false
+            })
+    }
+
+    /// Install an update using the default installer, returning the error if the check or
+    /// install failed
+    pub fn try_check_update(&self, name: &str, version: &str, allow_beta: bool) -> Result<bool, Error> {
+        self.try_custom_check_update(name, version, allow_beta, &DefaultInstaller)
+    }
+
+    /// Install an update using the default installer
+    pub fn check_update(&self, name: &str, version: &str, allow_beta: bool) -> bool {
+        self.custom_check_update(name, version, allow_beta, &DefaultInstaller)
+    }
+
+    /// Check several plugins for updates in a single request, showing one prompt for every
+    /// update found. Returns whether each plugin was updated, in the same order as `plugins`.
+    pub fn custom_check_updates<I>(&self, plugins: &[UpdateCheck], installer: &I) -> Vec<bool>
+        where I: Installer,
+    {
+        for plugin in plugins {
+            if let Ok(version) = normalize_version(&plugin.version) {
+                installer.set_installed_version(&plugin.name, &version);
             }
         }
-        Err(e) => {
-            println!("[{} updater] Failed to connect to update server {}", name, ip);
-            println!("[{} updater] {:?}", name, e);
-            false
+
+        let mut session = self.session(false);
+        let responses = match self.request_updates(&mut session, plugins) {
+            Ok(responses) => responses,
+            Err(e) => {
+                error!("Failed to check for updates on update server {}: {}", self.hosts.join(", "), e);
+                return vec![false; plugins.len()]
+            }
+        };
+
+        let updates: Vec<UpdateResponse> = responses.iter()
+            .filter(|response| matches!(response.code, ResponseCode::Update))
+            .cloned()
+            .collect();
+
+        if updates.is_empty() || !installer.should_update_many(&updates) {
+            return plugins.iter()
+                .zip(&responses)
+                .map(|(plugin, response)| Self::report_no_update(&plugin.name, &response.code))
+                .collect()
         }
+
+        plugins.iter()
+            .zip(&responses)
+            .map(|(plugin, response)| match response.code {
+                ResponseCode::Update => {
+                    session.allow_beta = plugin.allow_beta;
+                    self.install_after_prompt(&mut session, response, installer)
+                        .map_err(|e| self.report_error(&plugin.name, &e))
+                        .is_ok()
+                }
+                ref code => Self::report_no_update(&plugin.name, code),
+            })
+            .collect()
+    }
+
+    /// Check several plugins for updates using the default installer
+    pub fn check_updates(&self, plugins: &[UpdateCheck]) -> Vec<bool> {
+        self.custom_check_updates(plugins, &DefaultInstaller)
+    }
+
+    pub fn get_updates_info(&self, plugins: &[UpdateCheck]) -> Option<Vec<UpdateResponse>> {
+        self.request_updates(&mut self.session(false), plugins).ok()
+    }
+
+    /// Check for an update without installing it, returning the error if the check failed
+    pub fn try_get_update_info(&self, name: &str, version: &str, allow_beta: bool) -> Result<UpdateResponse, Error> {
+        self.request_update(&mut self.session(allow_beta), name, version, allow_beta)
+    }
+
+    pub fn get_update_info(&self, name: &str, version: &str, allow_beta: bool) -> Option<UpdateResponse> {
+        self.try_get_update_info(name, version, allow_beta).ok()
+    }
+
+    pub fn install_update(&self, info: &UpdateResponse) -> bool {
+        self.custom_install_update(info, &DefaultInstaller)
+    }
+
+    pub fn custom_install_update<I>(&self, info: &UpdateResponse, installer: &I) -> bool
+        where I: Installer,
+    {
+        self.update(&mut self.session(false), info, installer)
+            .map_err(|e| self.report_error(&info.plugin_name, &e))
+            .is_ok()
     }
 }
 
+/// Install an update with a custom installer implementation
+pub fn custom_check_update<I>(ip: IpAddr, name: &str, version: &str, allow_beta: bool, installer: &I) -> bool
+    where I: Installer,
+{
+    Client::new(ip).custom_check_update(name, version, allow_beta, installer)
+}
+
 /// Install an update using the default installer
 ///
 /// ## Args
@@ -155,43 +1095,169 @@ pub fn check_update(ip: IpAddr, name: &str, version: &str, allow_beta: bool) ->
     custom_check_update(ip, name, version, allow_beta, &DefaultInstaller)
 }
 
+/// Check for an update on a background thread using the default installer, only prompting the
+/// user once [`BackgroundUpdate::prompt`] is called
+pub fn check_update_in_background(ip: IpAddr, name: &str, version: &str, allow_beta: bool) -> BackgroundUpdate {
+    Client::new(ip).check_update_in_background(name, version, allow_beta)
+}
+
+/// Check several plugins for updates at once using the default installer, showing a single
+/// prompt for all of them
+pub fn check_updates(ip: IpAddr, plugins: &[UpdateCheck]) -> Vec<bool> {
+    Client::new(ip).check_updates(plugins)
+}
+
 pub fn get_update_info(ip: IpAddr, name: &str, version: &str, allow_beta: bool) -> Option<UpdateResponse> {
-    match TcpStream::connect((ip, PORT)) {
-        Ok(mut stream) =>  {
-            if let Ok(packet) = serde_json::to_string(&Request::Update {
-                beta: Some(allow_beta),
-                plugin_name: name.to_owned(),
-                plugin_version: version.to_owned(),
-                options: None,
-            }) {
-                let _ = stream.write_fmt(format_args!("{}\n", packet));
-                let mut string = String::new();
-                let _ = stream.read_to_string(&mut string);
-
-                if let Ok(response) = serde_json::from_str::<UpdateResponse>(&string) {
-                    Some(response)
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
-        }
-        Err(_) => None,
-    }
+    Client::new(ip).get_update_info(name, version, allow_beta)
 }
 
 pub fn install_update(ip: IpAddr, info: &UpdateResponse) -> bool {
-    update(ip, info, &DefaultInstaller)
+    Client::new(ip).install_update(info)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn rejects_invalid_tokens() {
+        assert_eq!(valid_token("secret".to_owned()).as_deref(), Some("secret"));
+        assert_eq!(valid_token("secret\r\nX-Injected: 1".to_owned()), None);
+        assert_eq!(valid_token("a".repeat(update_protocol::MAX_TOKEN_LEN + 1)), None);
+        assert!(Client::new([127, 0, 0, 1].into()).auth_token("a\nb").auth_token.is_none());
+    }
 
     #[test]
     fn test_install() {
-        println!("{}", serde_json::to_string(&Request::Update { plugin_name: "test_name".into(), plugin_version: "1.0.0".into(), beta: None, options: None }).unwrap());
+        println!("{}", serde_json::to_string(&Request::Update { plugin_name: "test_name".into(), plugin_version: "1.0.0".into(), beta: None, options: None, auth_token: None }).unwrap());
         check_update("127.0.0.1".parse().unwrap(), "test_plugin", "0.9.0", true);
     }
+
+    const FILE: &[u8] = b"plugin contents";
+
+    /// Serve HTTP requests on `ip`, responding to `GET /v1/files/<index>` with `files(index)`,
+    /// to `GET /v1/releases` with version 2.0.0 if `list` is set, and to anything else with an
+    /// update to version 2.0.0 containing [`FILE`] at `index`. Returns the port along with the
+    /// number of update checks received.
+    fn serve_http(ip: &str, port: u16, index: u64, files: fn(u64) -> Option<&'static [u8]>, list: bool) -> (u16, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind((ip, port)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let checks = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&checks);
+
+        let file = UpdateFile {
+            install_location: update_protocol::InstallLocation::AbsolutePath("sd:/plugin.nro".into()),
+            download_index: index,
+            size: FILE.len(),
+            sha256: Some(sha256_hex(FILE)),
+        };
+
+        thread::spawn(move || {
+            for socket in listener.incoming() {
+                let mut socket = socket.unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                    let read = socket.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..read]);
+                }
+                let request = String::from_utf8_lossy(&request).into_owned();
+                let path = request.split(' ').nth(1).unwrap();
+
+                let (status, body) = match path.strip_prefix("/v1/files/") {
+                    Some(requested) => match files(requested.parse().unwrap()) {
+                        Some(data) => (200, data.to_vec()),
+                        None => (500, vec![]),
+                    },
+                    None if path == "/v1/releases" && !list => (404, vec![]),
+                    None if path == "/v1/releases" => {
+                        let release = update_protocol::Release {
+                            id: "plugin-2.0.0".to_owned(),
+                            plugin_name: "plugin".to_owned(),
+                            version: "2.0.0".to_owned(),
+                            beta: false,
+                            skyline_version: None,
+                            files: vec![file.clone()],
+                            dependencies: vec![],
+                            title_ids: vec![],
+                            game_versions: None,
+                            name: None,
+                            description: None,
+                            images: vec![],
+                            changelog: None,
+                        };
+                        (200, serde_json::to_vec(&ListResponse { releases: vec![release] }).unwrap())
+                    }
+                    None => {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        let update = UpdateResponse {
+                            code: ResponseCode::Update,
+                            plugin_name: "plugin".to_owned(),
+                            new_plugin_version: "2.0.0".to_owned(),
+                            required_files: vec![file.clone()],
+                            ..Default::default()
+                        };
+                        (200, serde_json::to_vec(&update).unwrap())
+                    }
+                };
+
+                let _ = write!(socket, "HTTP/1.1 {} OK\r\nContent-Length: {}\r\n\r\n", status, body.len());
+                let _ = socket.write_all(&body);
+            }
+        });
+
+        (port, checks)
+    }
+
+    #[derive(Default)]
+    struct Recorder(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl Installer for Recorder {
+        fn should_update(&self, _: &UpdateResponse) -> bool {
+            true
+        }
+
+        fn install_file(&self, _: PathBuf, buf: Vec<u8>) -> Result<(), ()> {
+            self.0.lock().unwrap().push(buf);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn falls_back_to_mirrors() {
+        // mirrors share the transport's port, so each server listens on its own loopback address
+        let (port, _) = serve_http("127.0.0.1", 0, 0, |_| None, true);
+        let (_, mirror_checks) = serve_http("127.0.0.2", port, 7, |index| if index == 7 { Some(FILE) } else { None }, true);
+        let (_, old_mirror_checks) = serve_http("127.0.0.4", port, 7, |index| if index == 7 { Some(FILE) } else { None }, false);
+
+        // the primary's download fails, so the file comes from the mirror, found by listing
+        // its releases rather than checking for an update
+        for (mirror, checks, expected_checks) in &[("127.0.0.2", &mirror_checks, 0), ("127.0.0.4", &old_mirror_checks, 1)] {
+            let client = Client::with_host("127.0.0.1")
+                .mirror(*mirror)
+                .transport(Transport::Http { port })
+                .game(None)
+                .retries(1, Duration::from_millis(1));
+
+            let installer = Recorder::default();
+            assert!(client.try_custom_check_update("plugin", "1.0.0", false, &installer).unwrap());
+            assert_eq!(*installer.0.lock().unwrap(), [FILE]);
+            assert_eq!(checks.load(Ordering::SeqCst), *expected_checks, "{}", mirror);
+        }
+
+        // the primary is down, so the check goes to the mirror
+        let client = Client::with_host("127.0.0.3")
+            .mirror("127.0.0.2")
+            .transport(Transport::Http { port })
+            .game(None)
+            .retries(1, Duration::from_millis(1));
+
+        let installer = Recorder::default();
+        assert!(client.try_custom_check_update("plugin", "1.0.0", false, &installer).unwrap());
+        assert_eq!(*installer.0.lock().unwrap(), [FILE]);
+    }
 }
@@ -0,0 +1,35 @@
+//! A minimal logger for plugins which don't install one of their own.
+//!
+//! The updater logs through the `log` facade, so its messages go wherever the plugin's logger
+//! sends them (such as a file on the SD card). Without a logger they are discarded.
+
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+/// Logger printing every message to stdout (the skyline logger on the Switch)
+pub struct PrintLogger;
+
+static LOGGER: PrintLogger = PrintLogger;
+
+impl PrintLogger {
+    /// Install the logger, printing messages at `level` and above. Fails if a logger has already
+    /// been installed.
+    pub fn init(level: LevelFilter) -> Result<(), SetLoggerError> {
+        log::set_logger(&LOGGER)?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl Log for PrintLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            println!("[{}] {} {}", record.target(), record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
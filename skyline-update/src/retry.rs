@@ -0,0 +1,104 @@
+//! Retrying transient failures with exponential backoff
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::Error;
+
+/// Default number of times to retry a transient failure on each server
+pub const DEFAULT_RETRIES: u32 = 2;
+
+/// Default wait before the first retry, doubled for each retry after it
+pub const DEFAULT_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Longest wait between two attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A random duration between half of `backoff` and `backoff`, so plugins which failed at the
+/// same time don't all retry at the same time
+fn jitter(backoff: Duration) -> Duration {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(now.as_nanos());
+    }
+    let fraction = (hasher.finish() % 1024) as u32;
+    let backoff = backoff.min(MAX_BACKOFF);
+
+    backoff / 2 + backoff / 2 * fraction / 1024
+}
+
+/// Run `attempt`, retrying up to `retries` times while it fails with a transient error. Gives up
+/// early if waiting would run past `deadline`.
+pub(crate) fn retry<T, F>(retries: u32, backoff: Duration, deadline: Option<Instant>, mut attempt: F) -> Result<T, Error>
+    where F: FnMut() -> Result<T, Error>,
+{
+    let mut backoff = backoff.min(MAX_BACKOFF);
+    for _ in 0..retries {
+        match attempt() {
+            Err(e) if e.is_transient() => {
+                let wait = jitter(backoff);
+                if deadline.map(|deadline| Instant::now() + wait >= deadline).unwrap_or(false) {
+                    return Err(e)
+                }
+
+                thread::sleep(wait);
+                backoff = backoff.saturating_mul(2).min(MAX_BACKOFF);
+            }
+            result => return result,
+        }
+    }
+
+    attempt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn jitters_backoff() {
+        let backoff = Duration::from_millis(100);
+        for _ in 0..100 {
+            let wait = jitter(backoff);
+            assert!(wait >= backoff / 2 && wait <= backoff, "{:?}", wait);
+        }
+
+        let wait = jitter(Duration::MAX);
+        assert!(wait >= MAX_BACKOFF / 2 && wait <= MAX_BACKOFF, "{:?}", wait);
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        let backoff = Duration::from_millis(1);
+
+        let mut attempts = 0;
+        let result = retry(3, backoff, None, || {
+            attempts += 1;
+            if attempts < 3 {
+                Err(Error::Io(io::ErrorKind::ConnectionRefused.into()))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let mut attempts = 0;
+        let result: Result<(), _> = retry(3, backoff, None, || {
+            attempts += 1;
+            Err(Error::Timeout)
+        });
+        assert!(result.unwrap_err().is_timeout());
+        assert_eq!(attempts, 4);
+
+        let mut attempts = 0;
+        let result: Result<(), _> = retry(3, backoff, None, || {
+            attempts += 1;
+            Err(Error::InvalidResponse("not transient"))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
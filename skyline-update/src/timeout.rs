@@ -0,0 +1,117 @@
+//! Timeouts for connections to the update server
+
+use std::io::{self, prelude::*};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+/// Default time to wait for a connection to the update server
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default time to wait for the update server to send or accept any data
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The time left before `deadline`, or a timeout error if it has passed
+fn remaining(deadline: Instant) -> io::Result<Duration> {
+    match deadline.checked_duration_since(Instant::now()) {
+        Some(remaining) if remaining > Duration::ZERO => Ok(remaining),
+        _ => Err(io::ErrorKind::TimedOut.into()),
+    }
+}
+
+/// The shorter of a timeout and the time left before a deadline
+fn limit(timeout: Option<Duration>, deadline: Option<Instant>) -> io::Result<Option<Duration>> {
+    let remaining = deadline.map(remaining).transpose()?;
+
+    Ok(match (timeout, remaining) {
+        (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+        (timeout, remaining) => timeout.or(remaining),
+    })
+}
+
+/// A TCP connection which gives up on any single read or write after the read timeout, and on
+/// everything once the deadline for the check or install has passed
+pub(crate) struct TimeoutStream {
+    stream: TcpStream,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl TimeoutStream {
+    pub(crate) fn connect(
+        host: &str,
+        port: u16,
+        connect_timeout: Option<Duration>,
+        timeout: Option<Duration>,
+        deadline: Option<Instant>,
+    ) -> io::Result<Self> {
+        let mut last_error = None;
+        for addr in (host, port).to_socket_addrs()? {
+            let connected = match limit(connect_timeout, deadline)? {
+                Some(connect_timeout) => TcpStream::connect_timeout(&addr, connect_timeout),
+                None => TcpStream::connect(addr),
+            };
+
+            match connected {
+                Ok(stream) => return Ok(Self { stream, timeout, deadline }),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host not found")))
+    }
+}
+
+impl Read for TimeoutStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(limit(self.timeout, self.deadline)?)?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for TimeoutStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(limit(self.timeout, self.deadline)?)?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn limits_timeouts() {
+        let second = Duration::from_secs(1);
+        assert_eq!(limit(None, None).unwrap(), None);
+        assert_eq!(limit(Some(second), None).unwrap(), Some(second));
+
+        let deadline = Instant::now() + Duration::from_secs(60);
+        assert_eq!(limit(Some(second), Some(deadline)).unwrap(), Some(second));
+        assert!(limit(None, Some(deadline)).unwrap().unwrap() <= Duration::from_secs(60));
+
+        let passed = limit(Some(second), Some(Instant::now() - second)).unwrap_err();
+        assert_eq!(passed.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn times_out_stalled_reads() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let timeout = Some(Duration::from_millis(50));
+        let mut stream = TimeoutStream::connect("127.0.0.1", port, timeout, timeout, None).unwrap();
+        let e = stream.read_to_end(&mut vec![]).unwrap_err();
+        assert!(matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock));
+
+        let deadline = Some(Instant::now() + Duration::from_millis(50));
+        let mut stream = TimeoutStream::connect("127.0.0.1", port, None, None, deadline).unwrap();
+        let started = Instant::now();
+        assert!(stream.read_to_end(&mut vec![]).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
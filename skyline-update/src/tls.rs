@@ -0,0 +1,166 @@
+//! TLS support for connections to the update server, enabled with the `tls` feature
+
+use std::io;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, SignatureScheme, StreamOwned};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+
+use crate::Error;
+use crate::timeout::TimeoutStream;
+
+/// How to verify the update server's certificate
+///
+/// By default certificates are verified against the Mozilla root certificates. If any
+/// fingerprints are pinned, only a certificate matching one of them is accepted, which also
+/// allows for self-signed certificates.
+///
+/// ```rust
+/// use skyline_update::TlsOptions;
+///
+/// // fingerprint as printed by `update-server fingerprint cert.pem`
+/// let tls = TlsOptions::new()
+///     .pin("3f2a6c0d9b1e4f5a7c8d2e0b1a3f5c7e9d0b2a4c6e8f1a3b5c7d9e0f2a4b6c8d")
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    pins: Vec<[u8; 32]>,
+}
+
+impl TlsOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only accept a server certificate with the given SHA-256 fingerprint, as a hex string
+    /// (optionally `:` separated). Can be called multiple times to allow rotating certificates.
+    pub fn pin(mut self, fingerprint: &str) -> Result<Self, Error> {
+        let hex: Vec<u8> = fingerprint.bytes().filter(|&byte| byte != b':').collect();
+        if hex.len() != 64 {
+            return Err(Error::Tls("certificate fingerprint must be 32 bytes of hex".into()))
+        }
+
+        let mut pin = [0; 32];
+        for (byte, digits) in pin.iter_mut().zip(hex.chunks(2)) {
+            *byte = std::str::from_utf8(digits).ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| Error::Tls("certificate fingerprint must be hex".into()))?;
+        }
+
+        self.pins.push(pin);
+        Ok(self)
+    }
+
+    pub(crate) fn client_config(&self) -> Arc<ClientConfig> {
+        let provider = Arc::new(crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions");
+
+        let config = if self.pins.is_empty() {
+            let roots = rustls::RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            builder.with_root_certificates(roots).with_no_client_auth()
+        } else {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                    pins: self.pins.clone(),
+                    provider,
+                }))
+                .with_no_client_auth()
+        };
+
+        Arc::new(config)
+    }
+}
+
+/// Accepts only certificates matching a pinned fingerprint, regardless of issuer or host name
+#[derive(Debug)]
+struct PinnedVerifier {
+    pins: Vec<[u8; 32]>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint: [u8; 32] = Sha256::digest(end_entity.as_ref()).into();
+        if self.pins.contains(&fingerprint) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General("server certificate does not match pinned fingerprint".into()))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+pub(crate) type TlsStream = StreamOwned<ClientConnection, TimeoutStream>;
+
+/// Perform the TLS handshake over an established connection
+pub(crate) fn connect(config: &Arc<ClientConfig>, host: &str, sock: TimeoutStream) -> Result<TlsStream, Error> {
+    let server_name = ServerName::try_from(host.to_owned())
+        .map_err(|_| Error::Tls(format!("invalid server name '{}'", host)))?;
+
+    let conn = ClientConnection::new(Arc::clone(config), server_name)
+        .map_err(|e| Error::Tls(e.to_string()))?;
+
+    let mut stream = StreamOwned::new(conn, sock);
+    while stream.conn.is_handshaking() {
+        stream.conn.complete_io(&mut stream.sock).map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidData => Error::Tls(e.to_string()),
+            _ => e.into(),
+        })?;
+    }
+
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_pins() {
+        let hex = "00112233445566778899aabbccddeeff00112233445566778899AABBCCDDEEFF";
+        let options = TlsOptions::new().pin(hex).unwrap();
+        assert_eq!(options.pins[0][1], 0x11);
+        assert_eq!(options.pins[0][31], 0xff);
+
+        assert!(TlsOptions::new().pin("00:11:22").is_err());
+        assert!(TlsOptions::new().pin(&"zz".repeat(32)).is_err());
+    }
+}
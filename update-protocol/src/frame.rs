@@ -0,0 +1,171 @@
+//! The framed protocol: a single connection to the request port which can carry any number of
+//! requests and file downloads.
+//!
+//! The client opens the connection by sending [`MAGIC`] followed by the frame protocol version,
+//! which the server echoes back if it supports it. After that, both sides send frames, each
+//! being a one byte [`FrameKind`] tag, a big endian `u32` payload length, and the payload. The
+//! legacy protocol is still served on the same port, as [`MAGIC`] can never start a JSON request.
+//!
+//! | Client sends                          | Server responds with                          |
+//! |---------------------------------------|-----------------------------------------------|
+//! | `Request` (JSON `Request`)            | `Response` (JSON response) or `Error`         |
+//! | `FileRequest` (big endian `u64` index)| `FileData` (contents of the file) or `Error`  |
+//!
+//! A `FileRequest` for a file which isn't public has the access token appended to the index.
+
+use std::io::{self, prelude::*};
+use std::convert::TryFrom;
+
+/// Sent by the client at the start of a connection to select the framed protocol
+pub const MAGIC: [u8; 4] = *b"\0SKY";
+
+/// Version of the framed protocol
+pub const VERSION: u8 = 1;
+
+/// Maximum size of frames sent by the client
+pub const MAX_CLIENT_FRAME: u32 = 64 * 1024;
+
+/// Maximum size of `Response` frames sent by the server
+pub const MAX_RESPONSE_FRAME: u32 = 64 * 1024 * 1024;
+
+/// Maximum size of `Error` frames sent by the server
+pub const MAX_ERROR_FRAME: u32 = 4096;
+
+/// Maximum size of the frame sent in response to a `FileRequest` for a file of `size` bytes,
+/// either the file or an error
+pub fn max_file_frame(size: usize) -> u32 {
+    u32::try_from(size).unwrap_or(u32::MAX).max(MAX_ERROR_FRAME)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Request,
+    Response,
+    FileRequest,
+    FileData,
+    Error,
+    Unknown(u8),
+}
+
+impl From<u8> for FrameKind {
+    fn from(tag: u8) -> Self {
+        match tag {
+            1 => FrameKind::Request,
+            2 => FrameKind::Response,
+            3 => FrameKind::FileRequest,
+            4 => FrameKind::FileData,
+            5 => FrameKind::Error,
+            tag => FrameKind::Unknown(tag),
+        }
+    }
+}
+
+impl From<FrameKind> for u8 {
+    fn from(kind: FrameKind) -> Self {
+        match kind {
+            FrameKind::Request => 1,
+            FrameKind::Response => 2,
+            FrameKind::FileRequest => 3,
+            FrameKind::FileData => 4,
+            FrameKind::Error => 5,
+            FrameKind::Unknown(tag) => tag,
+        }
+    }
+}
+
+pub struct Frame {
+    pub kind: FrameKind,
+    pub payload: Vec<u8>,
+}
+
+/// Write the header of a frame, the payload of `len` bytes must be written immediately after
+pub fn write_header<W: Write>(writer: &mut W, kind: FrameKind, len: u32) -> io::Result<()> {
+    let mut header = [0; 5];
+    header[0] = kind.into();
+    header[1..].copy_from_slice(&len.to_be_bytes());
+    writer.write_all(&header)
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, kind: FrameKind, payload: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+
+    write_header(writer, kind, len)?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+/// Read a frame, returning `None` if the connection was closed cleanly between frames
+pub fn read_frame<R: Read>(reader: &mut R, max_len: u32) -> io::Result<Option<Frame>> {
+    let mut header = [0; 5];
+    match reader.read(&mut header[..1])? {
+        0 => return Ok(None),
+        _ => reader.read_exact(&mut header[1..])?,
+    }
+
+    let mut len = [0; 4];
+    len.copy_from_slice(&header[1..]);
+    let len = u32::from_be_bytes(len);
+    if len > max_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"))
+    }
+
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+
+    Ok(Some(Frame { kind: header[0].into(), payload }))
+}
+
+/// Client side of the handshake, sent before any frames
+pub fn write_handshake<W: Write>(writer: &mut W) -> io::Result<()> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&[VERSION])?;
+    writer.flush()
+}
+
+/// Read the handshake, returning the version the other side sent
+pub fn read_handshake<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut handshake = [0; 5];
+    reader.read_exact(&mut handshake)?;
+    if handshake[..4] != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid framed protocol handshake"))
+    }
+
+    Ok(handshake[4])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut buf = vec![];
+        write_handshake(&mut buf).unwrap();
+        write_frame(&mut buf, FrameKind::Request, b"{}").unwrap();
+        write_frame(&mut buf, FrameKind::FileRequest, &7u64.to_be_bytes()).unwrap();
+
+        let mut reader = &buf[..];
+        assert_eq!(read_handshake(&mut reader).unwrap(), VERSION);
+
+        let frame = read_frame(&mut reader, MAX_CLIENT_FRAME).unwrap().unwrap();
+        assert_eq!(frame.kind, FrameKind::Request);
+        assert_eq!(frame.payload, b"{}");
+
+        let frame = read_frame(&mut reader, MAX_CLIENT_FRAME).unwrap().unwrap();
+        assert_eq!(frame.kind, FrameKind::FileRequest);
+        assert_eq!(frame.payload, 7u64.to_be_bytes());
+
+        assert!(read_frame(&mut reader, MAX_CLIENT_FRAME).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_large_frames() {
+        let mut buf = vec![];
+        write_frame(&mut buf, FrameKind::Request, &[0; 16]).unwrap();
+        assert!(read_frame(&mut &buf[..], 8).is_err());
+
+        assert_eq!(max_file_frame(10), MAX_ERROR_FRAME);
+        assert_eq!(max_file_frame(1 << 20), 1 << 20);
+    }
+}
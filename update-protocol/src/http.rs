@@ -0,0 +1,139 @@
+//! Parsing HTTP/1.1 responses, shared by the client's HTTP transport and the server's importer
+
+use std::fmt;
+
+/// A response read in full, up to the server closing the connection
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// Get the value of a header, ignoring the case of its name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// The error returned when a response can't be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The connection was closed before the full body given by `Content-Length` was received
+    Truncated { expected: u64, received: u64 },
+    Invalid(&'static str),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Truncated { expected, received } => {
+                write!(f, "connection closed after {} of {} bytes", received, expected)
+            }
+            ParseError::Invalid(reason) => write!(f, "invalid HTTP response: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parse a raw response, decoding a chunked body
+pub fn parse_response(raw: &[u8]) -> Result<Response, ParseError> {
+    let head_end = raw.windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or(ParseError::Invalid("truncated headers"))?;
+
+    let head = std::str::from_utf8(&raw[..head_end])
+        .map_err(|_| ParseError::Invalid("non-UTF-8 headers"))?;
+    let mut lines = head.split("\r\n");
+
+    let status = lines.next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or(ParseError::Invalid("malformed status line"))?;
+
+    let headers = lines
+        .filter_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            Some((name.trim().to_owned(), value.trim().to_owned()))
+        })
+        .collect();
+
+    let mut response = Response { status, headers, body: vec![] };
+    let body = &raw[head_end + 4..];
+
+    let chunked = response.header("Transfer-Encoding")
+        .map(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+        .unwrap_or(false);
+
+    response.body = if chunked {
+        decode_chunked(body)?
+    } else if let Some(length) = response.header("Content-Length") {
+        let length: usize = length.parse()
+            .map_err(|_| ParseError::Invalid("invalid Content-Length"))?;
+        if body.len() < length {
+            return Err(ParseError::Truncated { expected: length as u64, received: body.len() as u64 })
+        }
+        body[..length].to_vec()
+    } else {
+        body.to_vec()
+    };
+
+    Ok(response)
+}
+
+fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>, ParseError> {
+    let mut decoded = vec![];
+    loop {
+        let line_end = body.windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or(ParseError::Invalid("truncated chunked body"))?;
+
+        let size = std::str::from_utf8(&body[..line_end]).ok()
+            .and_then(|line| line.split(';').next())
+            .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+            .ok_or(ParseError::Invalid("invalid chunk size"))?;
+
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(decoded)
+        }
+        let chunk_end = size.checked_add(2).ok_or(ParseError::Invalid("invalid chunk size"))?;
+        if body.len() < chunk_end {
+            return Err(ParseError::Invalid("truncated chunked body"))
+        }
+
+        decoded.extend_from_slice(&body[..size]);
+        body = &body[chunk_end..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_responses() {
+        let response = parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"hello");
+
+        let response = parse_response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n1;ext\r\n!\r\n0\r\n\r\n").unwrap();
+        assert_eq!(response.body, b"hello!");
+
+        let response = parse_response(b"HTTP/1.1 302 Found\r\nlocation: /next\r\nContent-Length: 0\r\n\r\n").unwrap();
+        assert_eq!((response.status, response.header("Location")), (302, Some("/next")));
+
+        assert_eq!(
+            parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello").unwrap_err(),
+            ParseError::Truncated { expected: 10, received: 5 }
+        );
+        assert_eq!(
+            parse_response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\nhello\r\n").unwrap_err(),
+            ParseError::Invalid("invalid chunk size")
+        );
+    }
+}
@@ -1,6 +1,39 @@
 use std::fmt;
+use std::collections::BTreeMap;
 use serde::{Serializer, Deserializer};
-use serde::{Serialize, Deserialize, de::{self, Visitor}};
+use serde::{Serialize, Deserialize, de::{self, Visitor}, ser};
+use sha2::{Digest, Sha256};
+
+pub mod frame;
+pub mod http;
+pub mod version;
+
+/// Version of the update protocol implemented by this crate. Clients which predate versioning
+/// don't send one, and are treated as version 0.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Lowercase hex SHA-256 of a file, as sent in [`UpdateFile::sha256`] and [`Asset::sha256`]
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// An optional protocol feature supported by a client or server
+#[non_exhaustive]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Capability {
+    /// Understands response codes added after the original four. Clients without it are only
+    /// sent the closest legacy code (see [`ResponseCode::legacy`]).
+    ExtendedResponseCodes,
+    /// Can speak the framed protocol (see [`frame`])
+    Framed,
+    /// Supports [`Request::UpdateMany`]
+    UpdateMany,
+    /// Supports [`Request::List`]
+    List,
+    /// A capability added in a newer version of the protocol
+    #[serde(other)]
+    Unknown,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct VersionInfo {
@@ -16,6 +49,35 @@ pub enum ResponseCode {
     Update,
     PluginNotFound,
     InvalidRequest,
+    /// The plugin exists, but no build is compatible with the client's game title and version
+    IncompatibleGame,
+    /// The plugin (or the requested channel of it) requires an access token, and the request
+    /// didn't include a token which is granted access
+    Unauthorized,
+    /// A response code added in a newer version of the protocol
+    #[serde(other)]
+    Unknown,
+}
+
+impl ResponseCode {
+    /// Whether the code was part of the original protocol, and so is understood by every client
+    pub fn is_legacy(&self) -> bool {
+        matches!(
+            self,
+            ResponseCode::NoUpdate | ResponseCode::Update | ResponseCode::PluginNotFound | ResponseCode::InvalidRequest
+        )
+    }
+
+    /// The closest equivalent code understood by clients without
+    /// [`Capability::ExtendedResponseCodes`]
+    pub fn legacy(&self) -> ResponseCode {
+        match self {
+            code if code.is_legacy() => code.clone(),
+            ResponseCode::IncompatibleGame => ResponseCode::NoUpdate,
+            ResponseCode::Unauthorized => ResponseCode::PluginNotFound,
+            _ => ResponseCode::InvalidRequest,
+        }
+    }
 }
 
 impl Default for ResponseCode {
@@ -33,6 +95,16 @@ pub struct UpdateResponse {
     pub new_plugin_version: String,
     pub new_skyline_version: Option<String>,
     pub required_files: Vec<UpdateFile>,
+
+    /// Plugins which must be installed for the update to work, resolved by the client
+    #[serde(default)]
+    pub dependencies: Vec<Dependency>,
+
+    /// Protocol version of the server, 0 for servers which predate versioning
+    #[serde(default)]
+    pub protocol_version: u32,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
 }
 
 impl UpdateResponse {
@@ -55,6 +127,40 @@ impl UpdateResponse {
     }
 }
 
+/// Set in the download index sent to the download port when it is followed by an access token,
+/// as a big endian `u16` length and the token itself. Download indices never have it set.
+pub const DOWNLOAD_TOKEN_FLAG: u64 = 1 << 63;
+
+/// Longest access token in bytes, as its length is sent as a `u16` to the download port
+pub const MAX_TOKEN_LEN: usize = u16::MAX as usize;
+
+/// The bytes sent to the download port to request a file, with an access token if given
+///
+/// # Panics
+///
+/// If the token is longer than [`MAX_TOKEN_LEN`]
+pub fn download_request(index: u64, auth_token: Option<&str>) -> Vec<u8> {
+    match auth_token {
+        Some(token) => {
+            assert!(token.len() <= MAX_TOKEN_LEN, "access token longer than {} bytes", MAX_TOKEN_LEN);
+            let token = token.as_bytes();
+            let mut request = (index | DOWNLOAD_TOKEN_FLAG).to_be_bytes().to_vec();
+            request.extend_from_slice(&(token.len() as u16).to_be_bytes());
+            request.extend_from_slice(token);
+            request
+        }
+        None => index.to_be_bytes().to_vec(),
+    }
+}
+
+/// A plugin required by another plugin, at a version matching a requirement
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Dependency {
+    pub plugin_name: String,
+    /// A semver version requirement, such as `>=0.2.0`
+    pub version_req: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PluginMetadata {
     pub name: Option<String>,
@@ -71,12 +177,66 @@ pub struct UpdateFile {
 
     pub download_index: u64,
     pub size: usize,
+
+    /// Lowercase hex SHA-256 of the file, used to check downloads and to find the same file on
+    /// mirrors. Not sent by older servers.
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+/// The game a plugin is running in
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GameInfo {
+    pub title_id: u64,
+    /// The game's display version, such as `13.0.1`
+    pub version: String,
 }
 
 #[non_exhaustive]
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct UpdateRequestOptions {
+    /// Protocol version of the client
+    #[serde(default)]
+    pub protocol_version: u32,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    /// The running game, so the server only offers compatible builds
+    #[serde(default)]
+    pub game: Option<GameInfo>,
+}
 
+impl UpdateRequestOptions {
+    /// Options declaring the given protocol capabilities at the current protocol version
+    pub fn new(capabilities: Vec<Capability>) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities,
+            game: None,
+        }
+    }
+
+    pub fn game(mut self, game: Option<GameInfo>) -> Self {
+        self.game = game;
+        self
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
+/// A single plugin to check in a [`Request::UpdateMany`]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpdateQuery {
+    pub plugin_name: String,
+    pub plugin_version: String,
+    pub beta: Option<bool>,
+}
+
+/// Response to a [`Request::UpdateMany`], with one response per plugin in the order requested
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UpdateManyResponse {
+    pub responses: Vec<UpdateResponse>,
 }
 
 #[non_exhaustive]
@@ -87,11 +247,88 @@ pub enum Request {
         plugin_version: String,
         beta: Option<bool>,
         options: Option<UpdateRequestOptions>,
+        /// Access token for plugins which aren't public
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        auth_token: Option<String>,
     },
     Metadata {
         plugin_name: String,
         beta: Option<bool>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        auth_token: Option<String>,
+        /// The game the client is running in, so it's sent the metadata of a build compatible
+        /// with it. Any build if `None`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        game: Option<GameInfo>,
+    },
+    Stats {
+        plugin_name: Option<String>,
     },
+    /// Check several plugins for updates at once
+    UpdateMany {
+        plugins: Vec<UpdateQuery>,
+        options: Option<UpdateRequestOptions>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        auth_token: Option<String>,
+    },
+    /// List every release hosted by the server, used by mirrors to replicate it
+    List {},
+}
+
+/// A file hosted by the server which isn't installed by clients, such as a metadata image
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Asset {
+    pub download_index: u64,
+    pub size: usize,
+    /// Lowercase hex SHA-256 of the file
+    pub sha256: String,
+}
+
+/// A single release of a plugin, with everything needed to host a copy of it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Release {
+    /// Identifies the release on the server, unique among the releases it hosts. A single path
+    /// component, such as the name of the folder or bundle it was loaded from.
+    pub id: String,
+    pub plugin_name: String,
+    pub version: String,
+    pub beta: bool,
+    pub skyline_version: Option<String>,
+    /// Files installed by the release, all of which include a hash
+    pub files: Vec<UpdateFile>,
+    pub dependencies: Vec<Dependency>,
+    /// Title IDs of the games the release supports, any game if empty
+    pub title_ids: Vec<u64>,
+    /// A semver requirement on the game version, any version if `None`
+    pub game_versions: Option<String>,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub images: Vec<Asset>,
+    pub changelog: Option<Asset>,
+}
+
+/// Response to [`Request::List`]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ListResponse {
+    pub releases: Vec<Release>,
+}
+
+/// Usage statistics the server has recorded for a single plugin
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PluginStats {
+    /// Number of update checks, keyed by the version the client reported
+    pub update_checks: BTreeMap<String, u64>,
+    /// Number of times an update was offered, keyed by the version offered
+    pub updates_offered: BTreeMap<String, u64>,
+    pub files_downloaded: u64,
+    pub bytes_served: u64,
+    /// Invalid requests and interrupted downloads
+    pub failures: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StatsResponse {
+    pub plugins: BTreeMap<String, PluginStats>,
 }
 
 // For allowing deserialization of unknown
@@ -117,7 +354,7 @@ impl Serialize for InstallLocation {
             S: Serializer {
         match self {
             InstallLocation::AbsolutePath(path) => serializer.serialize_str(path),
-            _ => todo!()
+            _ => Err(ser::Error::custom("unknown install locations can't be serialized")),
         }
     }
 }
@@ -143,3 +380,56 @@ impl<'de> Deserialize<'de> for InstallLocation {
         deserializer.deserialize_string(InstallLocationVisitor)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_variants() {
+        let code: ResponseCode = serde_json::from_str("\"SomeNewCode\"").unwrap();
+        assert!(matches!(code, ResponseCode::Unknown));
+        assert!(matches!(code.legacy(), ResponseCode::InvalidRequest));
+
+        let options: UpdateRequestOptions = serde_json::from_str(
+            r#"{"protocol_version":9,"capabilities":["Framed","SomeNewCapability"],"new_field":1}"#
+        ).unwrap();
+        assert_eq!(options.protocol_version, 9);
+        assert_eq!(options.capabilities, [Capability::Framed, Capability::Unknown]);
+
+        assert!(serde_json::to_string(&InstallLocation::Unknown).is_err());
+    }
+
+    #[test]
+    fn legacy_messages() {
+        // responses and options from before protocol versioning
+        let response: UpdateResponse = serde_json::from_str(
+            r#"{"code":"NoUpdate","update_plugin":false,"update_skyline":false,"plugin_name":"","new_plugin_version":"","new_skyline_version":null,"required_files":[]}"#
+        ).unwrap();
+        assert_eq!(response.protocol_version, 0);
+
+        let options: UpdateRequestOptions = serde_json::from_str("{}").unwrap();
+        assert_eq!(options.protocol_version, 0);
+        assert!(!options.supports(Capability::ExtendedResponseCodes));
+
+        // requests without a token are unchanged, so older servers still understand them
+        let request = Request::Metadata { plugin_name: "a".into(), beta: None, auth_token: None, game: None };
+        assert_eq!(serde_json::to_string(&request).unwrap(), r#"{"Metadata":{"plugin_name":"a","beta":null}}"#);
+        let request: Request = serde_json::from_str(r#"{"Metadata":{"plugin_name":"a","beta":null}}"#).unwrap();
+        assert!(matches!(request, Request::Metadata { auth_token: None, game: None, .. }));
+        assert!(matches!(ResponseCode::Unauthorized.legacy(), ResponseCode::PluginNotFound));
+    }
+
+    #[test]
+    fn download_requests() {
+        assert_eq!(download_request(5, None), [0, 0, 0, 0, 0, 0, 0, 5]);
+        assert_eq!(download_request(5, Some("ab")), [0x80, 0, 0, 0, 0, 0, 0, 5, 0, 2, b'a', b'b']);
+        assert_eq!(download_request(5, Some(&"a".repeat(MAX_TOKEN_LEN))).len(), 10 + MAX_TOKEN_LEN);
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_long_tokens() {
+        download_request(5, Some(&"a".repeat(MAX_TOKEN_LEN + 1)));
+    }
+}
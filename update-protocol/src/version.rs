@@ -0,0 +1,312 @@
+//! Plugin versions as reported by clients.
+//!
+//! Clients usually report `env!("CARGO_PKG_VERSION")`, but builds made from a git checkout often
+//! use the output of `git describe` instead. [`Version`] accepts both, along with a leading `v`
+//! and missing minor or patch components, and normalizes them to a semver version:
+//!
+//! | Reported              | Normalized            |
+//! |-----------------------|-----------------------|
+//! | `1.2.0`               | `1.2.0`               |
+//! | `v1.2`                | `1.2.0`               |
+//! | `1.3.0-beta.2+abc`    | `1.3.0-beta.2+abc`    |
+//! | `v1.2.0-14-gabcdef`   | `1.2.0+14.gabcdef`    |
+//!
+//! Versions are ordered by semver precedence:
+//!
+//! * Build metadata is ignored, so a `git describe` build compares equal to the tag it was built
+//!   after. It is offered the next release, but not the release it was built from.
+//! * A pre-release is older than the release of the same version, so `1.3.0-beta.2` is offered
+//!   `1.3.0` on either channel.
+//! * Pre-release versions on the server are only offered on the beta channel, even if the
+//!   `plugin.toml` doesn't set `beta = true`. Clients on the stable channel which are running a
+//!   pre-release are still offered any newer release.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer, de};
+
+/// A single dot-separated pre-release identifier
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Prerelease {
+    Numeric(u64),
+    Alphanumeric(String),
+}
+
+impl Ord for Prerelease {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Prerelease::Numeric(a), Prerelease::Numeric(b)) => a.cmp(b),
+            (Prerelease::Numeric(_), Prerelease::Alphanumeric(_)) => Ordering::Less,
+            (Prerelease::Alphanumeric(_), Prerelease::Numeric(_)) => Ordering::Greater,
+            (Prerelease::Alphanumeric(a), Prerelease::Alphanumeric(b)) => a.cmp(b),
+        }
+    }
+}
+
+impl PartialOrd for Prerelease {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Prerelease {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Prerelease::Numeric(n) => write!(f, "{}", n),
+            Prerelease::Alphanumeric(s) => f.write_str(s),
+        }
+    }
+}
+
+/// A normalized plugin version, see the [module documentation](self) for the accepted forms
+#[derive(Debug, Clone)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    pub pre: Vec<Prerelease>,
+    /// Build metadata, including the commit count and hash of a `git describe` version
+    pub build: Vec<String>,
+}
+
+impl Version {
+    pub fn new(major: u64, minor: u64, patch: u64) -> Self {
+        Self { major, minor, patch, pre: vec![], build: vec![] }
+    }
+
+    pub fn is_prerelease(&self) -> bool {
+        !self.pre.is_empty()
+    }
+}
+
+/// The error returned when a version string can't be understood
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    version: String,
+    reason: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid version `{}`: {}", self.version, self.reason)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn is_identifier(identifier: &str) -> bool {
+    !identifier.is_empty() && identifier.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Split the `<count>-g<hash>[-dirty]` suffix `git describe` adds after a tag off of the
+/// pre-release part of a version, returning the remaining pre-release and the suffix as build
+/// metadata
+fn split_describe(pre: &str) -> (&str, Vec<String>) {
+    let mut parts: Vec<&str> = pre.rsplitn(4, '-').collect();
+    parts.reverse();
+
+    let dirty = parts.last() == Some(&"dirty");
+    let end = if dirty { parts.len() - 1 } else { parts.len() };
+    if end < 2 {
+        return (pre, vec![])
+    }
+
+    let (count, hash) = (parts[end - 2], parts[end - 1]);
+    let is_describe = !count.is_empty()
+        && count.chars().all(|c| c.is_ascii_digit())
+        && hash.len() > 4
+        && hash.starts_with('g')
+        && hash[1..].chars().all(|c| c.is_ascii_hexdigit());
+
+    if !is_describe {
+        return (pre, vec![])
+    }
+
+    let mut build = vec![count.to_owned(), hash.to_owned()];
+    if dirty {
+        build.push("dirty".to_owned());
+    }
+
+    let remaining_len = parts[..end - 2].join("-").len();
+    (&pre[..remaining_len], build)
+}
+
+impl FromStr for Version {
+    type Err = ParseError;
+
+    fn from_str(version: &str) -> Result<Self, Self::Err> {
+        let error = |reason| ParseError { version: version.to_owned(), reason };
+
+        let trimmed = version.trim();
+        let trimmed = trimmed.strip_prefix(|c| c == 'v' || c == 'V').unwrap_or(trimmed);
+
+        let (rest, build) = match trimmed.find('+') {
+            Some(i) => (&trimmed[..i], Some(&trimmed[i + 1..])),
+            None => (trimmed, None),
+        };
+        let (core, pre) = match rest.find('-') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => (rest, ""),
+        };
+
+        let mut numbers = [0; 3];
+        let components: Vec<&str> = core.split('.').collect();
+        if components.len() > 3 {
+            return Err(error("too many version components"))
+        }
+        for (number, component) in numbers.iter_mut().zip(&components) {
+            if component.is_empty() || !component.chars().all(|c| c.is_ascii_digit()) {
+                return Err(error("version components must be numbers"))
+            }
+            *number = component.parse().map_err(|_| error("version component too large"))?;
+        }
+
+        let has_pre = rest.contains('-');
+        let (pre, mut describe) = split_describe(pre);
+        if has_pre && pre.is_empty() && describe.is_empty() {
+            return Err(error("empty pre-release"))
+        }
+
+        let pre = if pre.is_empty() {
+            vec![]
+        } else {
+            pre.split('.')
+                .map(|identifier| {
+                    if !is_identifier(identifier) {
+                        Err(error("invalid pre-release identifier"))
+                    } else if identifier.chars().all(|c| c.is_ascii_digit()) {
+                        identifier.parse()
+                            .map(Prerelease::Numeric)
+                            .map_err(|_| error("pre-release number too large"))
+                    } else {
+                        Ok(Prerelease::Alphanumeric(identifier.to_owned()))
+                    }
+                })
+                .collect::<Result<_, _>>()?
+        };
+
+        let mut build: Vec<String> = match build {
+            Some(build) => build.split('.')
+                .map(|identifier| {
+                    if is_identifier(identifier) {
+                        Ok(identifier.to_owned())
+                    } else {
+                        Err(error("invalid build metadata"))
+                    }
+                })
+                .collect::<Result<_, _>>()?,
+            None => vec![],
+        };
+        describe.append(&mut build);
+
+        Ok(Version {
+            major: numbers[0],
+            minor: numbers[1],
+            patch: numbers[2],
+            pre,
+            build: describe,
+        })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        for (i, identifier) in self.pre.iter().enumerate() {
+            f.write_str(if i == 0 { "-" } else { "." })?;
+            write!(f, "{}", identifier)?;
+        }
+        for (i, identifier) in self.build.iter().enumerate() {
+            f.write_str(if i == 0 { "+" } else { "." })?;
+            f.write_str(identifier)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| match (self.pre.is_empty(), other.pre.is_empty()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => self.pre.cmp(&other.pre),
+            })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl Serialize for Version {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Version {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de>
+    {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(version: &str) -> String {
+        version.parse::<Version>().unwrap().to_string()
+    }
+
+    #[test]
+    fn parses_versions() {
+        assert_eq!(normalize("1.2.0"), "1.2.0");
+        assert_eq!(normalize(" v1.2 "), "1.2.0");
+        assert_eq!(normalize("3"), "3.0.0");
+        assert_eq!(normalize("1.3.0-beta.2+abc.def"), "1.3.0-beta.2+abc.def");
+        assert_eq!(normalize("v1.2.0-14-gabcdef1"), "1.2.0+14.gabcdef1");
+        assert_eq!(normalize("1.2.0-14-gabcdef1-dirty"), "1.2.0+14.gabcdef1.dirty");
+        assert_eq!(normalize("1.3.0-rc.1-2-g0123abcd"), "1.3.0-rc.1+2.g0123abcd");
+        assert_eq!(normalize("1.0.0-x-y"), "1.0.0-x-y");
+
+        for invalid in &["", "latest", "1.2.3.4", "1..2", "1.2.0-", "1.2.0+", "1.2.0-beta..1", "1.2.0-é"] {
+            assert!(invalid.parse::<Version>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn orders_versions() {
+        let ordered = [
+            "1.0.0-alpha", "1.0.0-alpha.1", "1.0.0-alpha.beta", "1.0.0-beta",
+            "1.0.0-beta.2", "1.0.0-beta.11", "1.0.0-rc.1", "1.0.0", "1.0.1", "1.10.0",
+        ];
+        for pair in ordered.windows(2) {
+            let (a, b): (Version, Version) = (pair[0].parse().unwrap(), pair[1].parse().unwrap());
+            assert!(a < b, "{} < {}", a, b);
+        }
+
+        let describe: Version = "1.2.0-14-gabcdef1".parse().unwrap();
+        assert_eq!(describe, "1.2.0".parse().unwrap());
+        assert!(describe < "1.2.1".parse().unwrap());
+    }
+}
@@ -0,0 +1,70 @@
+//! Access control for plugins which aren't public, such as private or early-access builds.
+//!
+//! A release is public unless an access rule in `update-server.toml` covers it. A client can see
+//! and download a protected release if it sends a token listed in any of the rules covering it.
+
+use crate::config::{AccessRule, Channel};
+use crate::plugin_store::Plugin;
+
+#[derive(Default)]
+pub struct AccessControl {
+    rules: Vec<AccessRule>,
+}
+
+/// Compare tokens in time independent of where they differ, so tokens can't be guessed a byte
+/// at a time from response times
+fn tokens_match(expected: &str, token: &str) -> bool {
+    expected.len() == token.len()
+        && expected.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+impl AccessRule {
+    fn covers(&self, plugin: &Plugin) -> bool {
+        let plugin_covered = self.plugins.as_ref()
+            .map(|plugins| plugins.contains(&plugin.name))
+            .unwrap_or(true);
+
+        let channel_covered = match self.channel {
+            Channel::All => true,
+            Channel::Beta => plugin.is_beta(),
+        };
+
+        plugin_covered && channel_covered
+    }
+
+    fn grants(&self, token: &str) -> bool {
+        self.tokens.iter().any(|expected| tokens_match(expected, token))
+    }
+}
+
+impl AccessControl {
+    pub fn new(rules: Vec<AccessRule>) -> Self {
+        Self { rules }
+    }
+
+    /// Whether a client sending `token` may see and download a release
+    pub fn allows(&self, plugin: &Plugin, token: Option<&str>) -> bool {
+        let mut rules = self.rules.iter().filter(|rule| rule.covers(plugin)).peekable();
+        if rules.peek().is_none() {
+            return true
+        }
+
+        match token {
+            Some(token) => rules.any(|rule| rule.grants(token)),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_tokens() {
+        assert!(tokens_match("secret", "secret"));
+        assert!(!tokens_match("secret", "secreT"));
+        assert!(!tokens_match("secret", "secret2"));
+        assert!(!tokens_match("secret", ""));
+    }
+}
@@ -0,0 +1,191 @@
+//! The access log, a record of every request and download along with the client which made it,
+//! for looking into reports such as a console not being offered an update.
+//!
+//! Requests and downloads are logged as `tracing` events with the [`TARGET`] target. The
+//! [`AccessLog`] layer writes each one as a line of JSON to a rotating file, merged with the
+//! fields of the spans it occurred in: the client's address and protocol, and the request type
+//! and plugin.
+
+use std::fmt;
+use std::io::Write;
+
+use color_eyre::eyre::{self, eyre};
+use serde_json::{Map, Value};
+use tracing::{Event, Metadata, Subscriber};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::config::{AccessLogConfig, LogRotation};
+
+/// Target of the events recorded in the access log
+pub const TARGET: &str = "access_log";
+
+/// Layer writing access log events to a file
+pub struct AccessLog<W> {
+    writer: W,
+}
+
+/// The fields of a span, recorded for the events logged within it
+struct SpanFields(Map<String, Value>);
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_owned(), format!("{:?}", value).into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+}
+
+/// Whether the access log needs to see a span or event. It needs every span, for their fields,
+/// but only access log events.
+pub fn filter(metadata: &Metadata) -> bool {
+    metadata.is_span() || metadata.target() == TARGET
+}
+
+impl AccessLog<RollingFileAppender> {
+    /// Open the access log in the configured directory
+    pub fn open(config: &AccessLogConfig) -> eyre::Result<Self> {
+        let rotation = match config.rotation {
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        };
+
+        let writer = RollingFileAppender::builder()
+            .rotation(rotation)
+            .filename_prefix("access")
+            .filename_suffix("log")
+            .max_log_files(config.max_files)
+            .build(&config.directory)
+            .map_err(|e| eyre!("failed to open the access log in {}: {}", config.directory.display(), e))?;
+
+        Ok(Self::new(writer))
+    }
+}
+
+impl<W> AccessLog<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl<S, W> Layer<S> for AccessLog<W>
+    where S: Subscriber + for<'a> LookupSpan<'a>,
+          W: for<'w> MakeWriter<'w> + 'static,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Map::new();
+        attrs.record(&mut JsonVisitor(&mut fields));
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<SpanFields>() {
+                values.record(&mut JsonVisitor(&mut fields.0));
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if event.metadata().target() != TARGET {
+            return
+        }
+
+        let mut timestamp = String::new();
+        let _ = SystemTime.format_time(&mut Writer::new(&mut timestamp));
+
+        let mut entry = Map::new();
+        entry.insert("timestamp".to_owned(), timestamp.into());
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(fields) = span.extensions().get::<SpanFields>() {
+                    entry.extend(fields.0.clone());
+                }
+            }
+        }
+        event.record(&mut JsonVisitor(&mut entry));
+
+        let line = format!("{}\n", Value::Object(entry));
+        let _ = self.writer.make_writer().write_all(line.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use tracing::{debug, info, info_span};
+    use tracing_subscriber::filter::filter_fn;
+    use tracing_subscriber::prelude::*;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn logs_access_events() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let layer = AccessLog::new(move || writer.clone()).with_filter(filter_fn(filter));
+
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            let _connection = info_span!("connection", peer = %"127.0.0.1:5000", protocol = "tcp").entered();
+            info!("not an access log event");
+
+            let _request = info_span!("request", kind = "Update", plugin = "example").entered();
+            debug!(target: TARGET, client_version = "1.0.0", channel = "stable", code = "Update", "checked for update");
+        });
+
+        let log = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 1, "{}", log);
+
+        let mut entry: Map<String, Value> = serde_json::from_str(lines[0]).unwrap();
+        assert!(entry.remove("timestamp").unwrap().as_str().unwrap().starts_with("20"));
+        assert_eq!(Value::Object(entry), serde_json::json!({
+            "peer": "127.0.0.1:5000",
+            "protocol": "tcp",
+            "kind": "Update",
+            "plugin": "example",
+            "client_version": "1.0.0",
+            "channel": "stable",
+            "code": "Update",
+            "message": "checked for update",
+        }));
+    }
+}
@@ -0,0 +1,142 @@
+//! Shutting down and reloading the server, on a signal or a command sent to the admin socket.
+//!
+//! SIGTERM and SIGINT shut the server down: it stops accepting connections and waits for open
+//! ones to finish, up to the drain timeout. A second signal exits immediately. SIGHUP reloads
+//! every plugin.
+//!
+//! The admin socket accepts a single line command per connection and responds with a single
+//! line: `reload`, `shutdown` or `status`.
+
+use std::io::{self, prelude::*, BufReader};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{self, eyre};
+use tracing::info;
+
+/// Shutdown and reload requests, set from signal handlers and the admin socket and acted on by
+/// the main loop
+#[derive(Default)]
+pub struct Control {
+    shutdown: Arc<AtomicBool>,
+    reload: Arc<AtomicBool>,
+}
+
+impl Control {
+    /// Handle SIGTERM, SIGINT and SIGHUP
+    #[cfg(unix)]
+    pub fn handle_signals(&self) -> io::Result<()> {
+        use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+        use signal_hook::flag;
+
+        for &signal in &[SIGTERM, SIGINT] {
+            // exit straight away if already shutting down
+            flag::register_conditional_shutdown(signal, 1, Arc::clone(&self.shutdown))?;
+            flag::register(signal, Arc::clone(&self.shutdown))?;
+        }
+        flag::register(SIGHUP, Arc::clone(&self.reload))?;
+
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn handle_signals(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn request_shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+
+    pub fn request_reload(&self) {
+        self.reload.store(true, Ordering::SeqCst);
+    }
+
+    pub fn shutdown_requested(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Sleep for `timeout`, waking early if a shutdown is requested. Returns whether one was.
+    pub fn wait_for_shutdown(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !self.shutdown_requested() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false
+            }
+            std::thread::sleep((deadline - now).min(Duration::from_millis(100)));
+        }
+
+        true
+    }
+
+    /// Whether a reload has been requested since the last call
+    pub fn take_reload(&self) -> bool {
+        self.reload.swap(false, Ordering::SeqCst)
+    }
+
+    /// Carry out an admin command, returning the response
+    fn command(&self, command: &str, status: impl FnOnce() -> String) -> String {
+        match command {
+            "reload" => {
+                info!("reload requested over the admin socket");
+                self.request_reload();
+                "ok".to_owned()
+            }
+            "shutdown" => {
+                info!("shutdown requested over the admin socket");
+                self.request_shutdown();
+                "ok".to_owned()
+            }
+            "status" => status(),
+            _ => format!("unknown command {:?}, expected reload, shutdown or status", command),
+        }
+    }
+}
+
+/// Handle a single connection to the admin socket, `status` describing the server's state
+pub fn serve(control: &Control, socket: TcpStream, status: impl FnOnce() -> String) {
+    let mut socket = BufReader::new(socket);
+    let mut command = String::new();
+    if socket.read_line(&mut command).is_err() {
+        return
+    }
+
+    let response = control.command(command.trim(), status);
+    let _ = writeln!(socket.get_mut(), "{}", response);
+}
+
+/// Send a command to a running server's admin socket, returning its response
+pub fn send(address: SocketAddr, command: &str) -> eyre::Result<String> {
+    let mut socket = TcpStream::connect(address)
+        .map_err(|e| eyre!("failed to connect to the admin socket at {}: {}", address, e))?;
+    writeln!(socket, "{}", command)?;
+
+    let mut response = String::new();
+    BufReader::new(socket).read_line(&mut response)?;
+    Ok(response.trim_end().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handles_commands() {
+        let control = Control::default();
+        let status = || "1 plugin(s), 0 connection(s)".to_owned();
+
+        assert_eq!(control.command("status", status), "1 plugin(s), 0 connection(s)");
+        assert!(control.command("restart", status).starts_with("unknown command"));
+        assert!(!control.take_reload() && !control.shutdown_requested());
+
+        assert_eq!(control.command("reload", status), "ok");
+        assert!(control.take_reload());
+        assert!(!control.take_reload());
+
+        assert_eq!(control.command("shutdown", status), "ok");
+        assert!(control.shutdown_requested());
+    }
+}
@@ -0,0 +1,283 @@
+//! Release bundles: a single `.zip` or `.tar.zst` archive containing a `plugin.toml` and the
+//! files it references, which can be dropped into the `plugins` folder in place of a plugin folder.
+
+use std::fs::{self, File};
+use std::io::{self, prelude::*};
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+
+use color_eyre::eyre;
+
+use crate::hosted_plugins::{self, PluginToml};
+
+/// Most memory reserved up front for a file in a bundle. Sizes come from the archive, so larger
+/// files grow their buffer as they're read instead of trusting it.
+const MAX_PREALLOCATION: u64 = 16 * 1024 * 1024;
+
+/// Most bytes read out of a bundle, over every file it references once decompressed
+pub const MAX_BUNDLE_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Archive formats a release bundle can be stored as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Zip,
+    TarZst,
+}
+
+impl Format {
+    /// Determine the bundle format from a file name, if it is a bundle at all
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        if name.ends_with(".zip") {
+            Some(Format::Zip)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(Format::TarZst)
+        } else {
+            None
+        }
+    }
+}
+
+/// Normalize a path inside of a bundle, rejecting anything which could escape the bundle
+pub fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+
+    if normalized.as_os_str().is_empty() {
+        None
+    } else {
+        Some(normalized)
+    }
+}
+
+/// Read the `plugin.toml` of a bundle and every file it references into memory, keyed by their
+/// normalized paths within the bundle. Other files in the bundle are skipped, and an error is
+/// returned if the files read add up to more than [`MAX_BUNDLE_SIZE`].
+pub fn read(path: &Path) -> eyre::Result<HashMap<PathBuf, Vec<u8>>> {
+    read_limited(path, MAX_BUNDLE_SIZE)
+}
+
+fn read_limited(path: &Path, limit: u64) -> eyre::Result<HashMap<PathBuf, Vec<u8>>> {
+    let format = Format::from_path(path)
+        .ok_or_else(|| eyre::eyre!("{} is not a .zip or .tar.zst bundle", path.display()))?;
+
+    let manifest_name = Path::new("plugin.toml");
+    let mut remaining = limit;
+    let manifest = match read_entries(path, format, &mut remaining, |name| name == manifest_name)?.remove(manifest_name) {
+        Some(manifest) => manifest,
+        None => return Ok(HashMap::new()),
+    };
+
+    // an invalid manifest is reported when the plugin is loaded
+    let referenced: HashSet<PathBuf> = std::str::from_utf8(&manifest).ok()
+        .and_then(|manifest| toml::from_str::<PluginToml>(manifest).ok())
+        .map(|plugin| referenced_paths(&plugin).filter_map(|path| normalize(path)).collect())
+        .unwrap_or_default();
+
+    let mut files = read_entries(path, format, &mut remaining, |name| name != manifest_name && referenced.contains(name))?;
+    files.insert(manifest_name.to_owned(), manifest);
+    Ok(files)
+}
+
+/// Read the files in a bundle for which `wanted` returns true, taking their size from `remaining`
+fn read_entries(
+    path: &Path,
+    format: Format,
+    remaining: &mut u64,
+    wanted: impl Fn(&Path) -> bool,
+) -> eyre::Result<HashMap<PathBuf, Vec<u8>>> {
+    let mut files = HashMap::new();
+    match format {
+        Format::Zip => {
+            let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+            for i in 0..archive.len() {
+                let mut entry = archive.by_index(i)?;
+                if entry.is_dir() {
+                    continue
+                }
+                let name = entry.enclosed_name()
+                    .as_deref()
+                    .and_then(normalize)
+                    .ok_or_else(|| eyre::eyre!("Invalid path in bundle: {}", entry.name()))?;
+
+                if wanted(&name) {
+                    let size = entry.size();
+                    files.insert(name, read_entry(&mut entry, size, remaining)?);
+                }
+            }
+        }
+        Format::TarZst => {
+            let mut archive = tar::Archive::new(zstd::Decoder::new(File::open(path)?)?);
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                if !entry.header().entry_type().is_file() {
+                    continue
+                }
+                let entry_path = entry.path()?.into_owned();
+                let name = normalize(&entry_path)
+                    .ok_or_else(|| eyre::eyre!("Invalid path in bundle: {}", entry_path.display()))?;
+
+                if wanted(&name) {
+                    let size = entry.size();
+                    files.insert(name, read_entry(&mut entry, size, remaining)?);
+                }
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// Read a file in a bundle, `size` being the size given by the archive
+fn read_entry(entry: impl Read, size: u64, remaining: &mut u64) -> eyre::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(size.min(MAX_PREALLOCATION).min(*remaining) as usize);
+    entry.take(remaining.saturating_add(1)).read_to_end(&mut data)?;
+
+    *remaining = remaining.checked_sub(data.len() as u64)
+        .ok_or_else(|| eyre::eyre!("bundle is too large, its files add up to more than {} bytes", MAX_BUNDLE_SIZE))?;
+    Ok(data)
+}
+
+/// Paths of every file and metadata asset referenced by a `plugin.toml`
+fn referenced_paths(plugin: &PluginToml) -> impl Iterator<Item = &PathBuf> {
+    let metadata_paths = plugin.metadata.iter()
+        .flat_map(|metadata| metadata.images.iter().flatten().chain(metadata.changelog.iter()));
+
+    plugin.files.iter().map(|file| &file.filename).chain(metadata_paths)
+}
+
+/// Create a release bundle at `out` from a plugin folder, containing the `plugin.toml` and every
+/// file and metadata asset it references. The format is picked from the extension of `out`.
+pub fn create(dir: &Path, out: &Path) -> eyre::Result<()> {
+    let format = Format::from_path(out)
+        .ok_or_else(|| eyre::eyre!("Bundle name must end in .zip or .tar.zst: {}", out.display()))?;
+
+    let manifest = fs::read_to_string(dir.join("plugin.toml"))?;
+    let plugin: PluginToml = toml::from_str(&manifest)?;
+
+    let mut entries = vec![(PathBuf::from("plugin.toml"), manifest.into_bytes())];
+    for path in referenced_paths(&plugin) {
+        let name = normalize(path)
+            .ok_or_else(|| eyre::eyre!("Bundled files must be relative to the plugin folder: {}", path.display()))?;
+
+        if entries.iter().all(|(existing, _)| *existing != name) {
+            let data = fs::read(hosted_plugins::resolve_path(dir, path))?;
+            entries.push((name, data));
+        }
+    }
+
+    match format {
+        Format::Zip => {
+            let mut zip = zip::ZipWriter::new(File::create(out)?);
+            let options = zip::write::SimpleFileOptions::default()
+                .compression_method(zip::CompressionMethod::Deflated);
+            for (name, data) in &entries {
+                zip.start_file(bundle_name(name), options)?;
+                zip.write_all(data)?;
+            }
+            zip.finish()?;
+        }
+        Format::TarZst => {
+            let encoder = zstd::Encoder::new(File::create(out)?, 0)?;
+            let mut tar = tar::Builder::new(encoder);
+            for (name, data) in &entries {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                header.set_cksum();
+                tar.append_data(&mut header, bundle_name(name), io::Cursor::new(data))?;
+            }
+            tar.into_inner()?.finish()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Bundle entries always use `/` as a separator, regardless of host platform
+fn bundle_name(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin_folder(root: &Path) -> PathBuf {
+        let dir = root.join("my_plugin");
+        fs::create_dir_all(dir.join("assets")).unwrap();
+        fs::write(dir.join("plugin.toml"), r#"
+            version = "1.2.0"
+            name = "my_plugin"
+            files = [{ install_location = "sd:/my_plugin.nro", filename = "my_plugin.nro" }]
+            metadata = { changelog = "assets/changelog.md" }
+        "#).unwrap();
+        fs::write(dir.join("my_plugin.nro"), b"nro").unwrap();
+        fs::write(dir.join("assets/changelog.md"), b"changes").unwrap();
+        fs::write(dir.join("unrelated.txt"), b"not bundled").unwrap();
+        dir
+    }
+
+    #[test]
+    fn round_trip() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = plugin_folder(root.path());
+
+        for name in &["my_plugin.zip", "my_plugin.tar.zst"] {
+            let out = root.path().join(name);
+            create(&dir, &out).unwrap();
+
+            let files = read(&out).unwrap();
+            assert_eq!(files.len(), 3, "{}", name);
+            assert_eq!(files[Path::new("my_plugin.nro")], b"nro");
+            assert_eq!(files[Path::new("assets/changelog.md")], b"changes");
+            assert!(files.contains_key(Path::new("plugin.toml")));
+        }
+    }
+
+    #[test]
+    fn reads_only_referenced_files() {
+        let root = tempfile::tempdir().unwrap();
+        let out = root.path().join("my_plugin.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&out).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        for (name, data) in &[
+            ("padding.bin", &[0; 64][..]),
+            ("plugin.toml", &br#"
+                version = "1.2.0"
+                name = "my_plugin"
+                files = [{ install_location = "sd:/my_plugin.nro", filename = "./my_plugin.nro" }]
+            "#[..]),
+            ("my_plugin.nro", b"nro"),
+        ] {
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let files = read(&out).unwrap();
+        let mut names: Vec<_> = files.keys().collect();
+        names.sort();
+        assert_eq!(names, [Path::new("my_plugin.nro"), Path::new("plugin.toml")]);
+
+        let size = files.values().map(|data| data.len() as u64).sum();
+        assert!(read_limited(&out, size).is_ok());
+        assert!(read_limited(&out, size - 1).is_err());
+    }
+
+    #[test]
+    fn rejects_escaping_paths() {
+        assert_eq!(normalize(Path::new("./a/b.txt")), Some(PathBuf::from("a/b.txt")));
+        assert_eq!(normalize(Path::new("../b.txt")), None);
+        assert_eq!(normalize(Path::new("/b.txt")), None);
+    }
+}
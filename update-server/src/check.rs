@@ -0,0 +1,419 @@
+use std::fmt;
+use std::fs;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use semver::{Version, VersionReq};
+use update_protocol::InstallLocation;
+
+use crate::hosted_plugins::{GameConstraints, PluginRoot, PluginToml};
+
+const PLUGIN_KEYS: &[&str] = &["version", "name", "beta", "files", "skyline_version", "metadata", "dependencies", "game"];
+const FILE_KEYS: &[&str] = &["install_location", "filename"];
+const METADATA_KEYS: &[&str] = &["name", "images", "description", "changelog"];
+const GAME_KEYS: &[&str] = &["title_ids", "versions"];
+
+/// A single problem found while validating the plugins directory
+pub struct Problem {
+    pub path: PathBuf,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.path.display(), line, self.message),
+            None => write!(f, "{}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+/// A plugin which parsed successfully, kept around for the cross-plugin checks
+struct Checked {
+    toml_path: PathBuf,
+    source: String,
+    plugin: PluginToml,
+}
+
+#[derive(Default)]
+struct Checker {
+    problems: Vec<Problem>,
+}
+
+impl Checker {
+    fn report(&mut self, path: &Path, line: Option<usize>, message: impl Into<String>) {
+        self.problems.push(Problem {
+            path: path.to_owned(),
+            line,
+            message: message.into(),
+        });
+    }
+
+    fn check_keys(&mut self, path: &Path, source: &str, table: &toml::Value, known: &[&str], context: &str) {
+        if let Some(table) = table.as_table() {
+            for key in table.keys().filter(|key| !known.contains(&key.as_str())) {
+                self.report(path, line_of_key(source, key), format!("unknown key `{}` in {}", key, context));
+            }
+        }
+    }
+
+    fn check_plugin(&mut self, root: &PluginRoot) -> Option<Checked> {
+        let toml_path = root.location(Path::new("plugin.toml"));
+        let source = match root.read_to_string(Path::new("plugin.toml")) {
+            Ok(source) => source,
+            Err(e) => {
+                self.report(&toml_path, None, format!("failed to read plugin.toml: {}", e));
+                return None
+            }
+        };
+
+        let value: toml::Value = match toml::from_str(&source) {
+            Ok(value) => value,
+            Err(e) => {
+                self.report(&toml_path, e.line_col().map(|(line, _)| line + 1), format!("invalid TOML: {}", e));
+                return None
+            }
+        };
+
+        self.check_keys(&toml_path, &source, &value, PLUGIN_KEYS, "plugin.toml");
+        if let Some(files) = value.get("files").and_then(|files| files.as_array()) {
+            for file in files {
+                self.check_keys(&toml_path, &source, file, FILE_KEYS, "`files` entry");
+            }
+        }
+        if let Some(metadata) = value.get("metadata") {
+            self.check_keys(&toml_path, &source, metadata, METADATA_KEYS, "`metadata`");
+        }
+        if let Some(game) = value.get("game") {
+            self.check_keys(&toml_path, &source, game, GAME_KEYS, "`game`");
+        }
+
+        // `skyline_version` is silently dropped by the loader if it fails to parse
+        if let Some(skyline_version) = value.get("skyline_version") {
+            let valid = skyline_version.as_str()
+                .map(|version| version.parse::<Version>().is_ok())
+                .unwrap_or(false);
+            if !valid {
+                self.report(
+                    &toml_path,
+                    line_of_key(&source, "skyline_version"),
+                    format!("`skyline_version` is not a valid semver version: {}", skyline_version)
+                );
+            }
+        }
+
+        let plugin: PluginToml = match toml::from_str(&source) {
+            Ok(plugin) => plugin,
+            Err(e) => {
+                self.report(&toml_path, e.line_col().map(|(line, _)| line + 1), e.to_string());
+                return None
+            }
+        };
+
+        if let Some(Err(e)) = plugin.game.as_ref().map(GameConstraints::parse) {
+            self.report(&toml_path, line_of_key(&source, "game"), e.to_string());
+        }
+
+        for (dependency, version_req) in &plugin.dependencies {
+            if let Err(e) = VersionReq::parse(version_req) {
+                self.report(
+                    &toml_path,
+                    line_of_key(&source, dependency),
+                    format!("invalid version requirement `{}` for dependency `{}`: {}", version_req, dependency, e)
+                );
+            }
+        }
+
+        for file in &plugin.files {
+            if !root.contains(&file.filename) {
+                self.report(
+                    &toml_path,
+                    line_of(&source, &file.filename.to_string_lossy()),
+                    format!("file `{}` does not exist", root.location(&file.filename).display())
+                );
+            }
+        }
+
+        if let Some(metadata) = &plugin.metadata {
+            let assets = metadata.images.iter()
+                .flatten()
+                .map(|image| ("image", image))
+                .chain(metadata.changelog.iter().map(|changelog| ("changelog", changelog)));
+
+            for (kind, asset) in assets {
+                if root.legacy_location(asset).is_some() {
+                    self.report(
+                        &toml_path,
+                        line_of(&source, &asset.to_string_lossy()),
+                        format!(
+                            "metadata {} `{}` is relative to the working directory, but paths are relative to the plugin folder",
+                            kind, asset.display()
+                        )
+                    );
+                } else if !root.contains(asset) {
+                    self.report(
+                        &toml_path,
+                        line_of(&source, &asset.to_string_lossy()),
+                        format!("metadata {} `{}` does not exist", kind, root.location(asset).display())
+                    );
+                }
+            }
+        }
+
+        Some(Checked { toml_path, source, plugin })
+    }
+
+    fn check_conflicts(&mut self, plugins: &[Checked]) {
+        // builds of the same release for different games are allowed
+        let mut releases: HashMap<(&str, &Version, bool, GameConstraints), &Path> = HashMap::new();
+        let mut locations: HashMap<&str, (&str, &Path)> = HashMap::new();
+
+        for Checked { toml_path, source, plugin } in plugins {
+            let beta = plugin.beta.unwrap_or(false);
+            let channel = if beta { "beta" } else { "stable" };
+            let game = plugin.game.as_ref()
+                .and_then(|game| GameConstraints::parse(game).ok())
+                .unwrap_or_default();
+            let key = (plugin.name.as_str(), &plugin.version, beta, game);
+            if let Some(other) = releases.insert(key, toml_path) {
+                self.report(
+                    toml_path,
+                    line_of_key(source, "version"),
+                    format!(
+                        "{} {} ({}) is also provided by {}",
+                        plugin.name, plugin.version, channel, other.display()
+                    )
+                );
+            }
+
+            for file in &plugin.files {
+                let location = match &file.install_location {
+                    InstallLocation::AbsolutePath(location) => location.as_str(),
+                    _ => continue,
+                };
+
+                match locations.get(location) {
+                    Some((name, other)) if *name != plugin.name => {
+                        self.report(
+                            toml_path,
+                            line_of(source, location),
+                            format!(
+                                "install location `{}` is also used by plugin `{}` ({})",
+                                location, name, other.display()
+                            )
+                        );
+                    }
+                    Some(_) => {}
+                    None => {
+                        locations.insert(location, (&plugin.name, toml_path));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Check every dependency is hosted on this server at a version satisfying its requirement.
+    /// Beta plugins may depend on beta versions, stable plugins only on stable versions.
+    fn check_dependencies(&mut self, plugins: &[Checked]) {
+        for Checked { toml_path, source, plugin } in plugins {
+            let beta = plugin.beta.unwrap_or(false);
+
+            for (dependency, version_req) in &plugin.dependencies {
+                let requirement = match VersionReq::parse(version_req) {
+                    Ok(requirement) => requirement,
+                    Err(_) => continue,
+                };
+
+                let mut versions = plugins.iter()
+                    .map(|checked| &checked.plugin)
+                    .filter(|other| other.name == *dependency)
+                    .filter(|other| beta || !(other.beta.unwrap_or(false) || other.version.is_prerelease()))
+                    .peekable();
+
+                let message = if versions.peek().is_none() {
+                    format!("dependency `{}` is not hosted on this server", dependency)
+                } else if !versions.any(|other| requirement.matches(&other.version)) {
+                    format!("no version of dependency `{}` matches `{}`", dependency, version_req)
+                } else {
+                    continue
+                };
+
+                self.report(toml_path, line_of_key(source, dependency), message);
+            }
+        }
+    }
+}
+
+/// Find the (1-based) line of the first occurrence of `needle` in a source file
+fn line_of(source: &str, needle: &str) -> Option<usize> {
+    source.lines().position(|line| line.contains(needle)).map(|line| line + 1)
+}
+
+/// Find the (1-based) line a key is defined on, preferring lines which start with the key
+fn line_of_key(source: &str, key: &str) -> Option<usize> {
+    source.lines()
+        .position(|line| {
+            line.trim_start()
+                .strip_prefix(key)
+                .map(|rest| rest.trim_start().starts_with('='))
+                .unwrap_or(false)
+        })
+        .map(|line| line + 1)
+        .or_else(|| line_of(source, key))
+}
+
+/// Validate every plugin in `dir`, returning all problems found
+pub fn check(dir: &Path) -> Vec<Problem> {
+    let mut checker = Checker::default();
+
+    let mut entries = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect::<Vec<_>>(),
+        Err(e) => {
+            checker.report(dir, None, format!("failed to read plugins directory: {}", e));
+            return checker.problems
+        }
+    };
+    entries.sort();
+
+    let mut plugins = vec![];
+    for path in &entries {
+        match PluginRoot::open(path) {
+            Ok(Some(root)) => plugins.extend(checker.check_plugin(&root)),
+            Ok(None) => {}
+            Err(e) => checker.report(path, None, format!("failed to open bundle: {}", e)),
+        }
+    }
+
+    checker.check_conflicts(&plugins);
+    checker.check_dependencies(&plugins);
+
+    checker.problems
+}
+
+/// Entrypoint for `update-server check [dir]`, exits with a non-zero status if any problems are found
+pub fn run(dir: &Path) -> ! {
+    let problems = check(dir);
+
+    for problem in &problems {
+        println!("{}", problem);
+    }
+
+    if problems.is_empty() {
+        println!("{}: no problems found", dir.display());
+        std::process::exit(0)
+    } else {
+        println!("{} problem(s) found", problems.len());
+        std::process::exit(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin(root: &Path, folder: &str, toml: &str, files: &[&str]) {
+        let dir = root.join(folder);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("plugin.toml"), toml).unwrap();
+        for file in files {
+            fs::write(dir.join(file), "data").unwrap();
+        }
+    }
+
+    fn messages(dir: &Path) -> Vec<String> {
+        check(dir).into_iter().map(|problem| problem.to_string()).collect()
+    }
+
+    #[test]
+    fn valid_plugins() {
+        let root = tempfile::tempdir().unwrap();
+        plugin(root.path(), "a", r#"
+            version = "1.0.0"
+            name = "a"
+            files = [{ install_location = "sd:/a.nro", filename = "a.nro" }]
+        "#, &["a.nro"]);
+        plugin(root.path(), "a_beta", r#"
+            version = "1.1.0"
+            name = "a"
+            beta = true
+            files = [{ install_location = "sd:/a.nro", filename = "a.nro" }]
+        "#, &["a.nro"]);
+        plugin(root.path(), "b_13.0.1", r#"
+            version = "1.0.0"
+            name = "b"
+            files = [{ install_location = "sd:/b.nro", filename = "b.nro" }]
+            game = { title_ids = ["01006A800016E000"], versions = "=13.0.1" }
+        "#, &["b.nro"]);
+        plugin(root.path(), "b_13.0.2", r#"
+            version = "1.0.0"
+            name = "b"
+            files = [{ install_location = "sd:/b.nro", filename = "b.nro" }]
+            game = { title_ids = ["01006A800016E000"], versions = ">=13.0.2" }
+        "#, &["b.nro"]);
+
+        let messages = messages(root.path());
+        assert!(messages.is_empty(), "{:#?}", messages);
+    }
+
+    #[test]
+    fn checks_bundles() {
+        let root = tempfile::tempdir().unwrap();
+        let src = tempfile::tempdir().unwrap();
+        plugin(src.path(), "bundled", r#"
+            version = "1.0.0"
+            name = "bundled"
+            files = [{ install_location = "sd:/bundled.nro", filename = "bundled.nro" }]
+        "#, &["bundled.nro"]);
+        crate::bundle::create(&src.path().join("bundled"), &root.path().join("bundled.zip")).unwrap();
+        fs::write(root.path().join("corrupt.tar.zst"), "not an archive").unwrap();
+
+        let messages = messages(root.path());
+        assert_eq!(messages.len(), 1, "{:#?}", messages);
+        assert!(messages[0].contains("corrupt.tar.zst: failed to open bundle"), "{:#?}", messages);
+    }
+
+    #[test]
+    fn reports_problems() {
+        let root = tempfile::tempdir().unwrap();
+        plugin(root.path(), "broken", "version = \n", &[]);
+        plugin(root.path(), "one", r#"
+            version = "1.0.0"
+            name = "one"
+            skyline_version = "latest"
+            colour = "blue"
+            files = [{ install_location = "sd:/shared.txt", filename = "missing.txt" }]
+            metadata = { images = ["image.png"] }
+            dependencies = { hook = "1" }
+            game = { title_ids = ["smash"] }
+        "#, &[]);
+        plugin(root.path(), "two", r#"
+            version = "1.0.0"
+            name = "two"
+            files = [{ install_location = "sd:/shared.txt", filename = "two.txt" }]
+            dependencies = { one = ">=2.0.0" }
+            metadata = { changelog = "Cargo.toml" }
+        "#, &["two.txt"]);
+        plugin(root.path(), "two_copy", r#"
+            version = "1.0.0"
+            name = "two"
+            files = [{ install_location = "sd:/shared.txt", filename = "two.txt" }]
+        "#, &["two.txt"]);
+
+        let messages = messages(root.path());
+        let has = |needle: &str| messages.iter().any(|message| message.contains(needle));
+
+        assert!(has("broken/plugin.toml:1: invalid TOML"), "{:#?}", messages);
+        assert!(has("one/plugin.toml:4: `skyline_version` is not a valid semver version"), "{:#?}", messages);
+        assert!(has("one/plugin.toml:5: unknown key `colour`"), "{:#?}", messages);
+        assert!(has("missing.txt` does not exist"), "{:#?}", messages);
+        assert!(has("metadata image"), "{:#?}", messages);
+        assert!(has("one/plugin.toml:8: dependency `hook` is not hosted on this server"), "{:#?}", messages);
+        assert!(has("one/plugin.toml:9: invalid title ID `smash`"), "{:#?}", messages);
+        assert!(has("two/plugin.toml:5: no version of dependency `one` matches `>=2.0.0`"), "{:#?}", messages);
+        // tests run in the crate directory
+        assert!(has("two/plugin.toml:6: metadata changelog `Cargo.toml` is relative to the working directory"), "{:#?}", messages);
+        assert!(has("two/plugin.toml:4: install location `sd:/shared.txt` is also used by plugin `one`"), "{:#?}", messages);
+        assert!(has("two_copy/plugin.toml:2: two 1.0.0 (stable) is also provided by"), "{:#?}", messages);
+    }
+}
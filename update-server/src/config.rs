@@ -0,0 +1,298 @@
+use std::fs;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::net::{IpAddr, SocketAddr};
+
+use serde::Deserialize;
+use color_eyre::eyre;
+use update_protocol::InstallLocation;
+
+use crate::hosted_plugins::TomlGame;
+
+/// Path of the optional server configuration file, relative to the working directory
+pub const CONFIG_PATH: &str = "update-server.toml";
+
+/// Server configuration, loaded from `update-server.toml` if present
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address to serve Prometheus metrics on, disabled if not set
+    pub metrics_address: Option<SocketAddr>,
+
+    /// Address to serve the HTTP API on, disabled if not set
+    pub http_address: Option<SocketAddr>,
+
+    /// Address to accept admin commands on, such as `127.0.0.1:45100`, disabled if not set. It
+    /// isn't authenticated, so it must be a loopback address.
+    pub admin_address: Option<SocketAddr>,
+
+    /// Seconds to wait for open connections to finish when shutting down, defaults to
+    /// [`DEFAULT_DRAIN_TIMEOUT`]
+    pub drain_timeout: Option<u64>,
+
+    /// TLS settings, TLS is disabled if not set
+    pub tls: Option<TlsConfig>,
+
+    /// Serve plugins from a content-addressed blob store in this directory instead of the
+    /// `plugins` directory, releases being added with `update-server store`
+    pub blob_store: Option<PathBuf>,
+
+    /// Replicate the plugins of another update server, disabled if not set
+    pub mirror: Option<MirrorConfig>,
+
+    /// Release feeds to import plugins from
+    #[serde(rename = "import")]
+    pub imports: Vec<ImportConfig>,
+
+    /// Plugins or channels which require an access token, every plugin is public if empty
+    #[serde(rename = "access")]
+    pub access_rules: Vec<AccessRule>,
+
+    /// Per-IP limits protecting the server from misbehaving clients
+    pub limits: LimitsConfig,
+
+    /// Log format and verbosity
+    pub logging: LoggingConfig,
+
+    /// Record every request and download to a rotating file, disabled if not set
+    pub access_log: Option<AccessLogConfig>,
+}
+
+/// Default number of seconds to wait for open connections to finish when shutting down
+pub const DEFAULT_DRAIN_TIMEOUT: u64 = 30;
+
+/// Default port for TLS connections to the request port, TLS downloads use the port after it
+pub const DEFAULT_TLS_PORT: u16 = 45443;
+
+fn default_tls_port() -> u16 {
+    DEFAULT_TLS_PORT
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file containing the certificate chain
+    pub cert: PathBuf,
+
+    /// PEM file containing the private key
+    pub key: PathBuf,
+
+    /// Port to accept TLS requests on, TLS downloads are served from `port + 1`
+    #[serde(default = "default_tls_port")]
+    pub port: u16,
+
+    /// Address to serve the HTTP API over TLS on, disabled if not set
+    pub https_address: Option<SocketAddr>,
+}
+
+/// Default number of seconds between syncs with the upstream server
+pub const DEFAULT_SYNC_INTERVAL: u64 = 300;
+
+fn default_sync_interval() -> u64 {
+    DEFAULT_SYNC_INTERVAL
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorConfig {
+    /// Request port of the server to replicate, such as `updates.example.com:45000`. It must
+    /// support the framed protocol.
+    pub upstream: String,
+
+    /// Seconds between syncs with the upstream server
+    #[serde(default = "default_sync_interval")]
+    pub interval: u64,
+}
+
+/// Default number of seconds between checks of a release feed
+pub const DEFAULT_IMPORT_INTERVAL: u64 = 3600;
+
+fn default_import_interval() -> u64 {
+    DEFAULT_IMPORT_INTERVAL
+}
+
+fn default_tag_prefix() -> String {
+    "v".to_owned()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImportConfig {
+    /// URL of a GitHub-style release listing, such as
+    /// `https://api.github.com/repos/owner/repo/releases`, or the path of a JSON file in the
+    /// same format
+    pub feed: String,
+
+    /// Name of the plugin the feed publishes
+    pub name: String,
+
+    /// Prefix removed from release tags before parsing them as versions
+    #[serde(default = "default_tag_prefix")]
+    pub tag_prefix: String,
+
+    /// Seconds between checks of the feed
+    #[serde(default = "default_import_interval")]
+    pub interval: u64,
+
+    /// Import pre-releases, as beta releases
+    #[serde(default)]
+    pub prereleases: bool,
+
+    /// Release assets to install, every one must be present for a release to be imported
+    pub files: Vec<ImportFile>,
+
+    pub skyline_version: Option<String>,
+
+    pub description: Option<String>,
+
+    pub game: Option<TomlGame>,
+
+    /// Other plugins required by this one, mapped to a semver version requirement
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImportFile {
+    /// Name of the release asset, `*` matching any run of characters
+    pub asset: String,
+
+    pub install_location: InstallLocation,
+}
+
+/// Which releases of a plugin an access rule protects
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    /// Every release
+    #[default]
+    All,
+    /// Beta releases, including pre-release versions
+    Beta,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessRule {
+    /// Names of the plugins the rule protects, every plugin if not set
+    pub plugins: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub channel: Channel,
+
+    /// Tokens granted access to the protected releases
+    pub tokens: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Requests, such as update checks, each IP may send per minute
+    pub requests_per_minute: u32,
+
+    /// Files each IP may download per minute
+    pub downloads_per_minute: u32,
+
+    /// Connections each IP may have open at once
+    pub max_connections_per_ip: usize,
+
+    /// Largest request accepted, in bytes
+    pub max_request_size: usize,
+
+    /// Number of times an IP may exceed the limits within a minute before it is banned
+    pub ban_after: u32,
+
+    /// Seconds a banned IP is refused for
+    pub ban_seconds: u64,
+
+    /// IPs the limits don't apply to, such as mirrors
+    pub exempt: Vec<IpAddr>,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_minute: 60,
+            downloads_per_minute: 120,
+            max_connections_per_ip: 16,
+            max_request_size: 64 * 1024,
+            ban_after: 10,
+            ban_seconds: 600,
+            exempt: vec![],
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log aggregation
+    Json,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+
+    /// Which events to log, as a `tracing_subscriber` filter such as `info` or
+    /// `info,update_server::requests=debug`. The `RUST_LOG` environment variable takes
+    /// precedence.
+    pub level: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Text,
+            level: "info".to_owned(),
+        }
+    }
+}
+
+/// How often a new access log file is started
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogConfig {
+    /// Directory the log files are written to, named `access.<date>.log`
+    pub directory: PathBuf,
+
+    pub rotation: LogRotation,
+
+    /// Number of log files kept, older files are deleted
+    pub max_files: usize,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("logs"),
+            rotation: LogRotation::Daily,
+            max_files: 14,
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> eyre::Result<Self> {
+        let path = Path::new(CONFIG_PATH);
+        if path.exists() {
+            Ok(toml::from_str(&fs::read_to_string(path)?)?)
+        } else {
+            Ok(Config::default())
+        }
+    }
+}
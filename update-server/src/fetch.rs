@@ -0,0 +1,194 @@
+//! A minimal HTTP client, for fetching release feeds and their assets
+
+use std::convert::TryFrom;
+use std::io::{self, prelude::*};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::{self, eyre};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use rustls::pki_types::ServerName;
+use update_protocol::http::{parse_response, Response};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Release asset downloads are usually redirected to a CDN
+const MAX_REDIRECTS: usize = 5;
+
+/// Largest response accepted, headers included
+const MAX_RESPONSE_SIZE: u64 = 512 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Url {
+    tls: bool,
+    host: String,
+    port: u16,
+    /// Path and query, starting with `/`
+    path: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> eyre::Result<Self> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            eyre::bail!("unsupported URL `{}`, expected http:// or https://", url)
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                (host, port.parse().map_err(|_| eyre!("invalid port in URL `{}`", url))?)
+            }
+            _ => (authority, if tls { 443 } else { 80 }),
+        };
+        if host.is_empty() {
+            eyre::bail!("missing host in URL `{}`", url)
+        }
+
+        Ok(Url { tls, host: host.to_owned(), port, path: path.to_owned() })
+    }
+
+    /// Resolve the target of a redirect, which may be relative to this URL
+    fn join(&self, location: &str) -> eyre::Result<Self> {
+        if location.starts_with('/') {
+            Ok(Url { path: location.to_owned(), ..self.clone() })
+        } else {
+            Url::parse(location)
+        }
+    }
+
+    fn host_header(&self) -> String {
+        if self.port == if self.tls { 443 } else { 80 } {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+fn tls_config() -> Arc<ClientConfig> {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+
+    let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions")
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Arc::new(config)
+}
+
+fn connect(url: &Url) -> eyre::Result<TcpStream> {
+    let addr = (url.host.trim_matches(|c| c == '[' || c == ']'), url.port).to_socket_addrs()?
+        .next()
+        .ok_or_else(|| eyre!("host {} not found", url.host))?;
+
+    let socket = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+    socket.set_write_timeout(Some(TIMEOUT))?;
+    Ok(socket)
+}
+
+fn request(url: &Url) -> eyre::Result<Response> {
+    let head = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: update-server\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        url.path, url.host_header()
+    );
+
+    let socket = connect(url)?;
+    let mut raw = vec![];
+    if url.tls {
+        let name = ServerName::try_from(url.host.clone())
+            .map_err(|_| eyre!("invalid server name `{}`", url.host))?;
+        let mut stream = StreamOwned::new(ClientConnection::new(tls_config(), name)?, socket);
+        stream.write_all(head.as_bytes())?;
+        match stream.take(MAX_RESPONSE_SIZE + 1).read_to_end(&mut raw) {
+            // some servers close the connection without a TLS close_notify, which is fine since
+            // the length of the body is checked when parsing the response
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            result => { result?; }
+        }
+    } else {
+        let mut socket = socket;
+        socket.write_all(head.as_bytes())?;
+        socket.take(MAX_RESPONSE_SIZE + 1).read_to_end(&mut raw)?;
+    }
+
+    if raw.len() as u64 > MAX_RESPONSE_SIZE {
+        eyre::bail!("response from {} is larger than {} bytes", url.host, MAX_RESPONSE_SIZE)
+    }
+
+    Ok(parse_response(&raw)?)
+}
+
+/// Fetch a URL, following redirects
+pub fn get(url: &str) -> eyre::Result<Vec<u8>> {
+    let mut url = Url::parse(url)?;
+    for _ in 0..=MAX_REDIRECTS {
+        let response = request(&url)?;
+        match (response.status, response.header("Location")) {
+            (200, _) => return Ok(response.body),
+            (301 | 302 | 303 | 307 | 308, Some(location)) => url = url.join(location)?,
+            (status, _) => eyre::bail!("{}{} responded with HTTP status {}", url.host, url.path, status),
+        }
+    }
+
+    Err(eyre!("too many redirects fetching {}", url.host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn parses_urls() {
+        let url = Url::parse("https://api.github.com/repos/a/b/releases?per_page=10").unwrap();
+        assert_eq!(url, Url { tls: true, host: "api.github.com".into(), port: 443, path: "/repos/a/b/releases?per_page=10".into() });
+
+        let url = Url::parse("http://127.0.0.1:8080").unwrap();
+        assert_eq!((url.host.as_str(), url.port, url.path.as_str()), ("127.0.0.1", 8080, "/"));
+        assert_eq!(url.join("/file").unwrap().path, "/file");
+        assert!(url.join("https://cdn.example.com/file").unwrap().tls);
+
+        assert!(Url::parse("ftp://example.com").is_err());
+        assert!(Url::parse("http://:80/").is_err());
+    }
+
+    #[test]
+    fn follows_redirects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        thread::spawn(move || {
+            for (i, socket) in listener.incoming().take(2).enumerate() {
+                let mut socket = socket.unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = socket.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..read]);
+                }
+
+                let response: &[u8] = if i == 0 {
+                    b"HTTP/1.1 302 Found\r\nLocation: /asset\r\nContent-Length: 0\r\n\r\n"
+                } else {
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nasset"
+                };
+                socket.write_all(response).unwrap();
+            }
+        });
+
+        assert_eq!(get(&format!("http://127.0.0.1:{}/release", port)).unwrap(), b"asset");
+    }
+}
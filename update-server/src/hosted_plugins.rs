@@ -1,10 +1,15 @@
 use std::{io, fs};
-use semver::Version;
+use semver::{Version, VersionReq};
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
-use update_protocol::InstallLocation;
+use update_protocol::{Dependency, GameInfo, InstallLocation};
 use serde::{Serialize, Deserialize};
 
-use color_eyre::eyre;
+use color_eyre::eyre::{self, eyre};
+use tracing::warn;
+
+use crate::bundle;
+use crate::source::Blob;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct PluginFile {
@@ -20,6 +25,14 @@ pub struct TomlMetadata {
     pub changelog: Option<PathBuf>,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TomlGame {
+    /// Title IDs of the supported games, as hex strings
+    pub title_ids: Option<Vec<String>>,
+    /// A semver requirement on the game's display version
+    pub versions: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct PluginToml {
     #[serde(with = "version_parse")]
@@ -35,6 +48,20 @@ pub struct PluginToml {
     pub skyline_version: Option<Version>,
 
     pub metadata: Option<TomlMetadata>,
+
+    pub game: Option<TomlGame>,
+
+    /// Other plugins required by this one, mapped to a semver version requirement
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dependencies: BTreeMap<String, String>,
+}
+
+impl PluginToml {
+    /// Serialize as the contents of a `plugin.toml`
+    pub fn to_toml_string(&self) -> eyre::Result<String> {
+        // going through `toml::Value` puts plain values before tables, as TOML requires
+        Ok(toml::to_string(&toml::Value::try_from(self)?)?)
+    }
 }
 
 mod version_parse {
@@ -91,76 +118,235 @@ mod version_parse_opt {
 #[derive(Default)]
 pub struct Metadata {
     pub name: Option<String>,
-    pub images: Option<Vec<Vec<u8>>>,
+    pub images: Option<Vec<Blob>>,
     pub description: Option<String>,
-    pub changelog: Option<String>,
+    pub changelog: Option<Blob>,
+}
+
+/// The games, and versions of them, a build of a plugin is compatible with
+#[derive(Default, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct GameConstraints {
+    /// Supported title IDs, any title if empty
+    pub title_ids: Vec<u64>,
+    /// Supported game versions, any version if `None`
+    pub versions: Option<VersionReq>,
+}
+
+impl GameConstraints {
+    pub fn parse(game: &TomlGame) -> eyre::Result<Self> {
+        let title_ids = game.title_ids.iter()
+            .flatten()
+            .map(|title_id| {
+                u64::from_str_radix(title_id.trim_start_matches("0x"), 16)
+                    .map_err(|_| eyre!("invalid title ID `{}`, expected a hex string such as 01006A800016E000", title_id))
+            })
+            .collect::<eyre::Result<_>>()?;
+
+        let versions = game.versions.as_ref()
+            .map(|versions| {
+                VersionReq::parse(versions)
+                    .map_err(|e| eyre!("invalid game version requirement `{}`: {}", versions, e))
+            })
+            .transpose()?;
+
+        Ok(Self { title_ids, versions })
+    }
+
+    /// Whether a build with these constraints can run in the given game
+    pub fn supports(&self, game: &GameInfo) -> bool {
+        let title_supported = self.title_ids.is_empty() || self.title_ids.contains(&game.title_id);
+        let version_supported = match &self.versions {
+            Some(versions) => parse_game_version(&game.version)
+                .map(|version| versions.matches(&version))
+                .unwrap_or(false),
+            None => true,
+        };
+
+        title_supported && version_supported
+    }
+}
+
+/// Parse a game's display version as a semver version, allowing missing minor and patch
+/// components (`13.0` is treated as `13.0.0`)
+pub fn parse_game_version(version: &str) -> Option<Version> {
+    let version = version.trim();
+    let components = version.split('.').count();
+    let padded = match components {
+        1 => format!("{}.0.0", version),
+        2 => format!("{}.0", version),
+        _ => version.to_owned(),
+    };
+
+    padded.parse().ok()
 }
 
 pub struct Plugin {
     pub name: String,
     pub plugin_version: Version,
-    pub files: Vec<(InstallLocation, Vec<u8>)>,
+    pub files: Vec<(InstallLocation, Blob)>,
     pub skyline_version: Version,
     pub beta: bool,
     pub metadata: Metadata,
+    pub dependencies: Vec<Dependency>,
+    pub game: GameConstraints,
 }
 
-fn to_file(PluginFile { install_location, filename }: PluginFile, dir: &Path) -> eyre::Result<(InstallLocation, Vec<u8>)> {
-    let path = if filename.is_absolute() {
-        filename
+/// Resolve a path from a `plugin.toml`, relative paths being relative to the plugin folder
+pub fn resolve_path(dir: &Path, path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        path.to_owned()
     } else {
-        dir.join(filename)
-    };
+        dir.join(path)
+    }
+}
 
-    Ok((install_location, fs::read(path)?))
+/// The location a plugin is loaded from, either a plugin folder or a release bundle
+pub enum PluginRoot {
+    Folder(PathBuf),
+    Bundle {
+        path: PathBuf,
+        files: HashMap<PathBuf, Vec<u8>>,
+    },
 }
 
-pub fn folder_to_plugin(dir: io::Result<fs::DirEntry>) -> eyre::Result<Option<Plugin>> {
-    let path = dir?.path();
-    if !path.is_dir() {
-        return Ok(None)
+impl PluginRoot {
+    /// Open an entry of the plugins directory, returning `None` if it is neither a folder nor a bundle
+    pub fn open(path: &Path) -> eyre::Result<Option<Self>> {
+        if path.is_dir() {
+            Ok(Some(PluginRoot::Folder(path.to_owned())))
+        } else if bundle::Format::from_path(path).is_some() {
+            Ok(Some(PluginRoot::Bundle {
+                path: path.to_owned(),
+                files: bundle::read(path)?,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Path of the folder or bundle
+    pub fn path(&self) -> &Path {
+        match self {
+            PluginRoot::Folder(path) | PluginRoot::Bundle { path, .. } => path,
+        }
+    }
+
+    /// Path of a file within the plugin, for reading or displaying to the user
+    pub fn location(&self, file: &Path) -> PathBuf {
+        resolve_path(self.path(), file)
+    }
+
+    /// Read a file referenced by the `plugin.toml`
+    pub fn read(&self, file: &Path) -> io::Result<Vec<u8>> {
+        match self {
+            PluginRoot::Folder(dir) => fs::read(resolve_path(dir, file)),
+            PluginRoot::Bundle { path, files } => {
+                bundle::normalize(file)
+                    .and_then(|file| files.get(&file))
+                    .cloned()
+                    .ok_or_else(|| io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("{} not found in bundle {}", file.display(), path.display())
+                    ))
+            }
+        }
+    }
+
+    pub fn read_to_string(&self, file: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(file)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Metadata assets of plugin folders used to be read relative to the working directory. The
+    /// path to read `file` from if it is a relative path which only exists there.
+    pub fn legacy_location(&self, file: &Path) -> Option<PathBuf> {
+        match self {
+            PluginRoot::Folder(_) if file.is_relative() && !self.contains(file) && file.is_file() => {
+                std::env::current_dir().ok().map(|dir| dir.join(file))
+            }
+            _ => None,
+        }
     }
-    let toml_path = path.join("plugin.toml");
 
-    let plugin: PluginToml = toml::from_str(&fs::read_to_string(toml_path)?)?;
+    /// Whether a file referenced by the `plugin.toml` exists
+    pub fn contains(&self, file: &Path) -> bool {
+        match self {
+            PluginRoot::Folder(dir) => resolve_path(dir, file).is_file(),
+            PluginRoot::Bundle { files, .. } => {
+                bundle::normalize(file).map(|file| files.contains_key(&file)).unwrap_or(false)
+            }
+        }
+    }
+}
+
+/// Load a plugin from an entry of the plugins directory, returning `None` if the entry is
+/// neither a plugin folder nor a release bundle
+pub fn folder_to_plugin(path: &Path) -> eyre::Result<Option<Plugin>> {
+    match PluginRoot::open(path)? {
+        Some(root) => root_to_plugin(&root).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Load a plugin from a folder or bundle, holding its files in memory
+pub fn root_to_plugin(root: &PluginRoot) -> eyre::Result<Plugin> {
+    let mut plugin: PluginToml = toml::from_str(&root.read_to_string(Path::new("plugin.toml"))?)?;
+    if let Some(metadata) = &mut plugin.metadata {
+        for asset in metadata.images.iter_mut().flatten().chain(&mut metadata.changelog) {
+            if let Some(legacy) = root.legacy_location(asset) {
+                warn!(
+                    plugin = %root.path().display(),
+                    "metadata asset {} is relative to the working directory, move it into the plugin folder",
+                    asset.display()
+                );
+                *asset = legacy;
+            }
+        }
+    }
+
+    to_plugin(plugin, |file| Ok(Blob::from_bytes(root.read(file)?)))
+}
 
-    let PluginToml { version, name, files, skyline_version, beta, metadata } =  plugin;
+/// Load a plugin from its parsed `plugin.toml`, `open` giving the contents of each file it
+/// references
+pub fn to_plugin(plugin: PluginToml, open: impl Fn(&Path) -> eyre::Result<Blob>) -> eyre::Result<Plugin> {
+    let PluginToml { version, name, files, skyline_version, beta, metadata, game, dependencies } =  plugin;
 
-    let files = files.into_iter().map(|file| to_file(file, &path)).collect::<eyre::Result<_>>()?;
+    let game = game.as_ref().map(GameConstraints::parse).transpose()?.unwrap_or_default();
+
+    let dependencies = dependencies.into_iter()
+        .map(|(plugin_name, version_req)| {
+            VersionReq::parse(&version_req)
+                .map_err(|e| eyre!("invalid version requirement `{}` for dependency `{}`: {}", version_req, plugin_name, e))?;
+            Ok(Dependency { plugin_name, version_req })
+        })
+        .collect::<eyre::Result<_>>()?;
+
+    let files = files.into_iter()
+        .map(|PluginFile { install_location, filename }| Ok((install_location, open(&filename)?)))
+        .collect::<eyre::Result<_>>()?;
 
     let metadata = metadata.map(|metadata| {
         Metadata {
             name: metadata.name,
-            images: metadata.images.map(|x| x.iter().map(|path| fs::read(path).unwrap_or_default()).collect()),
+            images: metadata.images.map(|x| {
+                x.iter().map(|image| open(image).unwrap_or_else(|_| Blob::from_bytes(vec![]))).collect()
+            }),
             description: metadata.description,
-            changelog: metadata.changelog.map(|path| fs::read_to_string(path).ok()).flatten()
+            changelog: metadata.changelog.and_then(|changelog| open(&changelog).ok())
         }
     }).unwrap_or_default();
 
-    Ok(Some(Plugin {
+    Ok(Plugin {
         name,
         plugin_version: version,
         files,
         skyline_version: skyline_version.unwrap_or("0.0.0".parse().unwrap()),
         beta: beta.unwrap_or(false),
         metadata,
-    }))
-}
-
-pub fn get() -> eyre::Result<Vec<Plugin>> {
-    Ok(
-        fs::read_dir("plugins")?
-            .filter_map(|entry| {
-                match folder_to_plugin(entry) {
-                    Ok(x) => x,
-                    Err(e) => {
-                        println!("{}", e);
-                        None
-                    }
-                }
-            })
-            .collect()
-    )
+        dependencies,
+        game,
+    })
 }
 
 /*pub fn print_default() {
@@ -171,5 +357,7 @@ pub fn get() -> eyre::Result<Vec<Plugin>> {
         skyline_version: None,
         beta: Some(false),
         metadata: None,
+        game: None,
+        dependencies: BTreeMap::new(),
     }).unwrap());
 }*/
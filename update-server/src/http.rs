@@ -0,0 +1,352 @@
+//! A minimal HTTP/1.1 server exposing the update protocol, for clients behind proxies or
+//! firewalls which can't reach the raw TCP ports.
+//!
+//! Routes:
+//! * `POST /v1/update` - body is a JSON `Request::Update`, responds with an `UpdateResponse`, or
+//!   a `Request::UpdateMany`, responding with an `UpdateManyResponse`
+//! * `GET /v1/plugins/<name>/metadata[?beta=true]` - responds with a `PluginMetadata`
+//! * `GET /v1/files/<id>` - the file with the given download index, supports `Range` requests
+//!
+//! Access tokens are sent in the body of update requests, and as an `Authorization: Bearer`
+//! header for the other routes.
+
+use std::io::{self, prelude::*, BufReader};
+
+use update_protocol::{GameInfo, Request};
+
+use crate::limits::{Action, Connection};
+use crate::stream::Stream;
+use crate::requests::{Context, DownloadError};
+
+/// Maximum size of the request line and headers
+const MAX_HEADER_SIZE: u64 = 16 * 1024;
+
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The access token from an `Authorization: Bearer` header
+    pub fn bearer_token(&self) -> Option<&str> {
+        let (scheme, token) = self.header("Authorization")?.split_once(' ')?;
+        Some(token.trim()).filter(|_| scheme.eq_ignore_ascii_case("Bearer"))
+    }
+
+    /// Get a query parameter, such as `beta` in `?beta=true`
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query.split('&')
+            .filter_map(|pair| {
+                let mut pair = pair.splitn(2, '=');
+                Some((pair.next()?, pair.next().unwrap_or("")))
+            })
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Read a single HTTP request from a socket, failing with `InvalidInput` if the body is larger
+/// than `max_body_size`
+pub fn read_request(socket: &mut impl BufRead, max_body_size: usize) -> io::Result<HttpRequest> {
+    let mut head = socket.take(MAX_HEADER_SIZE);
+
+    let mut request_line = String::new();
+    head.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_owned(), target),
+        _ => return Err(invalid_data("malformed request line")),
+    };
+
+    let (path, query) = match target.find('?') {
+        Some(i) => (target[..i].to_owned(), target[i + 1..].to_owned()),
+        None => (target.to_owned(), String::new()),
+    };
+
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        if head.read_line(&mut line)? == 0 {
+            return Err(invalid_data("headers too large or connection closed"))
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break
+        }
+        if let Some(i) = line.find(':') {
+            headers.push((line[..i].trim().to_owned(), line[i + 1..].trim().to_owned()));
+        }
+    }
+
+    let mut request = HttpRequest { method, path, query, headers, body: vec![] };
+
+    if let Some(length) = request.header("Content-Length") {
+        let length: usize = length.parse().map_err(|_| invalid_data("invalid Content-Length"))?;
+        if length > max_body_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "request body too large"))
+        }
+        request.body = vec![0; length];
+        head.into_inner().read_exact(&mut request.body)?;
+    }
+
+    Ok(request)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        206 => "Partial Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        416 => "Range Not Satisfiable",
+        429 => "Too Many Requests",
+        _ => "",
+    }
+}
+
+/// Write the status line and headers of a response, the body is written separately
+pub fn write_head(socket: &mut impl Write, status: u16, headers: &[(&str, String)]) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", status, reason(status));
+    for (name, value) in headers {
+        head += &format!("{}: {}\r\n", name, value);
+    }
+    head += "Connection: close\r\n\r\n";
+
+    socket.write_all(head.as_bytes())
+}
+
+/// Write a complete response with a small body
+pub fn write_response(socket: &mut impl Write, status: u16, content_type: &str, body: &[u8]) -> io::Result<()> {
+    write_head(socket, status, &[
+        ("Content-Type", content_type.to_owned()),
+        ("Content-Length", body.len().to_string()),
+    ])?;
+    socket.write_all(body)
+}
+
+/// Parse a `Range` header for a file of length `len` into a half-open byte range.
+///
+/// Returns `Ok(None)` if the whole file should be sent (including for multi-range requests,
+/// which aren't supported) and `Err(())` if the range can't be satisfied.
+pub fn parse_range(header: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+
+    let mut bounds = spec.splitn(2, '-');
+    let (start, end) = match (bounds.next(), bounds.next()) {
+        (Some(start), Some(end)) => (start.trim(), end.trim()),
+        _ => return Ok(None),
+    };
+
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // bytes=start-end, end inclusive
+        (Ok(start), Ok(end)) if start <= end => (start, end.saturating_add(1).min(len)),
+        // bytes=start-
+        (Ok(start), Err(_)) if end.is_empty() => (start, len),
+        // bytes=-suffix_length
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => (len.saturating_sub(suffix), len),
+        _ => return Ok(None),
+    };
+
+    if range.0 >= len {
+        Err(())
+    } else {
+        Ok(Some(range))
+    }
+}
+
+/// Handle a single connection to the HTTP API
+pub fn serve(ctx: &Context, conn: &Connection, socket: impl Stream) {
+    let mut socket = BufReader::new(socket);
+
+    let request = match read_request(&mut socket, conn.max_request_size()) {
+        Ok(request) => request,
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+            conn.request_too_large();
+            let _ = write_response(socket.get_mut(), 413, "text/plain", b"request too large");
+            return
+        }
+        Err(_) => {
+            let _ = write_response(socket.get_mut(), 400, "text/plain", b"bad request");
+            return
+        }
+    };
+
+    let mut socket = socket.into_inner();
+    let action = if request.path.trim_start_matches('/').starts_with("v1/files/") {
+        Action::Download
+    } else {
+        Action::Request
+    };
+
+    let _ = if conn.allow(action) {
+        respond(ctx, &mut socket, &request)
+    } else {
+        write_head(&mut socket, 429, &[
+            ("Retry-After", "60".to_owned()),
+            ("Content-Length", "0".to_owned()),
+        ])
+    };
+    socket.close();
+}
+
+fn respond(ctx: &Context, socket: &mut impl Write, request: &HttpRequest) -> io::Result<()> {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+
+    match (request.method.as_str(), segments.as_slice()) {
+        ("POST", ["v1", "update"]) => {
+            let update = match serde_json::from_slice::<Request>(&request.body) {
+                Ok(update @ Request::Update { .. }) | Ok(update @ Request::UpdateMany { .. }) => Some(update),
+                _ => None,
+            };
+
+            let response = ctx.handle(update).unwrap_or_default();
+            write_response(socket, 200, "application/json", response.as_bytes())
+        }
+        ("GET", ["v1", "releases"]) => {
+            let response = ctx.handle(Some(Request::List {})).unwrap_or_default();
+            write_response(socket, 200, "application/json", response.as_bytes())
+        }
+        ("GET", ["v1", "plugins", name, "metadata"]) => {
+            let beta = request.query_param("beta").map(|beta| beta == "true" || beta == "1");
+            let title_id = request.query_param("title_id").and_then(|id| u64::from_str_radix(id, 16).ok());
+            let game = title_id.zip(request.query_param("game_version"))
+                .map(|(title_id, version)| GameInfo { title_id, version: percent_decode(version) });
+            let metadata = Request::Metadata {
+                plugin_name: percent_decode(name),
+                beta,
+                auth_token: request.bearer_token().map(str::to_owned),
+                game,
+            };
+
+            match ctx.handle(Some(metadata)) {
+                Some(response) => write_response(socket, 200, "application/json", response.as_bytes()),
+                None => write_response(socket, 404, "text/plain", b"plugin not found"),
+            }
+        }
+        ("GET", ["v1", "files", id]) | ("HEAD", ["v1", "files", id]) => {
+            let download = match id.parse() {
+                Ok(index) => ctx.download(index, request.bearer_token()),
+                Err(_) => Err(DownloadError::NotFound),
+            };
+            let download = match download {
+                Ok(download) => download,
+                Err(DownloadError::NotFound) => return write_response(socket, 404, "text/plain", b"file not found"),
+                Err(DownloadError::Unauthorized) => return write_response(socket, 401, "text/plain", b"unauthorized"),
+            };
+
+            let len = download.data.len();
+            let range = match request.header("Range").map(|range| parse_range(range, len)) {
+                Some(Ok(range)) => range,
+                Some(Err(())) => {
+                    return write_head(socket, 416, &[
+                        ("Content-Range", format!("bytes */{}", len)),
+                        ("Content-Length", "0".to_owned()),
+                    ])
+                }
+                None => None,
+            };
+
+            let (status, (start, end)) = match range {
+                Some(range) => (206, range),
+                None => (200, (0, len)),
+            };
+
+            let mut headers = vec![
+                ("Content-Type", "application/octet-stream".to_owned()),
+                ("Content-Length", (end - start).to_string()),
+                ("Accept-Ranges", "bytes".to_owned()),
+            ];
+            if status == 206 {
+                headers.push(("Content-Range", format!("bytes {}-{}/{}", start, end - 1, len)));
+            }
+
+            write_head(socket, status, &headers)?;
+            if request.method == "GET" {
+                ctx.send(socket, &download, (start, end));
+            }
+
+            Ok(())
+        }
+        (_, ["v1", "update"]) | (_, ["v1", "plugins", _, "metadata"]) | (_, ["v1", "files", _]) => {
+            write_response(socket, 405, "text/plain", b"method not allowed")
+        }
+        _ => write_response(socket, 404, "text/plain", b"not found"),
+    }
+}
+
+/// Decode `%XX` escapes in a path segment
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes.get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match (bytes[i], escape) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("bytes=0-9", 100), Ok(Some((0, 10))));
+        assert_eq!(parse_range("bytes=90-", 100), Ok(Some((90, 100))));
+        assert_eq!(parse_range("bytes=-10", 100), Ok(Some((90, 100))));
+        assert_eq!(parse_range("bytes=50-500", 100), Ok(Some((50, 100))));
+        assert_eq!(parse_range("bytes=100-", 100), Err(()));
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), Ok(None));
+        assert_eq!(parse_range("items=0-1", 100), Ok(None));
+    }
+
+    #[test]
+    fn parses_requests() {
+        let raw = b"POST /v1/update?x=1&beta=true HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nbody";
+        let request = read_request(&mut &raw[..], 1024).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/v1/update");
+        assert_eq!(request.query_param("beta"), Some("true"));
+        assert_eq!(request.header("content-length"), Some("4"));
+        assert_eq!(request.body, b"body");
+        assert_eq!(read_request(&mut &raw[..], 3).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+
+        assert_eq!(percent_decode("my%20plugin"), "my plugin");
+
+        let raw = b"GET /v1/files/0 HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n";
+        assert_eq!(read_request(&mut &raw[..], 1024).unwrap().bearer_token(), Some("secret"));
+    }
+}
@@ -0,0 +1,351 @@
+//! Importing plugins from a GitHub-style release feed.
+//!
+//! The feed lists releases, newest first, each with a tag and downloadable assets. Tags are
+//! parsed as versions and the configured assets of each release are downloaded and written to the
+//! plugins directory as a plugin folder named `<name>-<version>`, which the file watcher then
+//! picks up like any other plugin. Releases already in the plugins directory are skipped, and
+//! releases which drop off the feed are kept, since feeds are usually paginated.
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use color_eyre::eyre::{self, eyre};
+use semver::Version;
+use serde::Deserialize;
+use tracing::{info, info_span, warn};
+
+use crate::admin::Control;
+use crate::config::{ImportConfig, ImportFile};
+use crate::fetch;
+use crate::hosted_plugins::{parse_game_version, PluginFile, PluginToml, TomlMetadata};
+use crate::mirror::{beside, is_valid_id};
+
+/// Where releases are downloaded to before being moved into the plugins directory, so the
+/// server never loads a partially downloaded release. It's next to the plugins directory, so
+/// releases can be renamed into place.
+const STAGING_DIR: &str = "import-staging";
+
+/// A release in the feed, only the fields used by the importer
+#[derive(Deserialize)]
+struct FeedRelease {
+    tag_name: String,
+    #[serde(default)]
+    body: Option<String>,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    prerelease: bool,
+    #[serde(default)]
+    assets: Vec<FeedAsset>,
+}
+
+#[derive(Deserialize)]
+struct FeedAsset {
+    name: String,
+    browser_download_url: String,
+    #[serde(default)]
+    size: Option<usize>,
+}
+
+fn is_url(location: &str) -> bool {
+    location.starts_with("http://") || location.starts_with("https://")
+}
+
+/// Read a URL, or a file path relative to `base`
+fn read(location: &str, base: &Path) -> eyre::Result<Vec<u8>> {
+    if is_url(location) {
+        fetch::get(location)
+    } else {
+        let path = location.strip_prefix("file://").unwrap_or(location);
+        fs::read(base.join(path)).map_err(|e| eyre!("failed to read {}: {}", path, e))
+    }
+}
+
+/// Read an asset of a release in the feed at `feed`.
+///
+/// Assets of a feed fetched over HTTP must be http(s) URLs too, so a remote feed can't make the
+/// server copy its own files into the plugins directory. Assets of a local feed may also be paths
+/// relative to the feed's directory, which can't leave it.
+fn read_asset(location: &str, feed: &str, feed_dir: &Path) -> eyre::Result<Vec<u8>> {
+    if is_url(location) {
+        return fetch::get(location)
+    }
+
+    if is_url(feed) {
+        eyre::bail!("asset {} of a remote feed isn't an http(s) URL", location)
+    }
+
+    let path = Path::new(location.strip_prefix("file://").unwrap_or(location));
+    if !path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+        eyre::bail!("asset {} isn't a path within the feed's directory", location)
+    }
+
+    read(&path.to_string_lossy(), feed_dir)
+}
+
+/// Whether an asset name matches a pattern, in which `*` matches any run of characters
+fn matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        None => return rest.is_empty(),
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+/// The version a release tag refers to, such as `1.2.0` for `v1.2.0` or `v1.2`
+fn parse_tag(tag: &str, prefix: &str) -> Option<Version> {
+    parse_game_version(tag.strip_prefix(prefix).unwrap_or(tag))
+}
+
+/// The `plugin.toml` of an imported release, with files named after their assets
+fn plugin_toml(config: &ImportConfig, version: Version, beta: bool, assets: &[(&ImportFile, &FeedAsset)], changelog: bool) -> eyre::Result<String> {
+    let skyline_version = config.skyline_version.as_ref()
+        .map(|version| version.parse().map_err(|_| eyre!("invalid skyline_version `{}`", version)))
+        .transpose()?;
+
+    let has_metadata = config.description.is_some() || changelog;
+
+    let plugin = PluginToml {
+        version,
+        name: config.name.clone(),
+        beta: Some(beta),
+        files: assets.iter()
+            .map(|(file, asset)| PluginFile {
+                install_location: file.install_location.clone(),
+                filename: PathBuf::from(&asset.name),
+            })
+            .collect(),
+        skyline_version,
+        metadata: if has_metadata {
+            Some(TomlMetadata {
+                name: None,
+                images: None,
+                description: config.description.clone(),
+                changelog: if changelog { Some("CHANGELOG.md".into()) } else { None },
+            })
+        } else {
+            None
+        },
+        game: config.game.clone(),
+        dependencies: config.dependencies.clone(),
+    };
+
+    plugin.to_toml_string()
+}
+
+/// Download a release into the staging directory
+fn stage(config: &ImportConfig, feed_dir: &Path, release: &FeedRelease, version: Version, staged: &Path) -> eyre::Result<()> {
+    let assets = config.files.iter()
+        .map(|file| {
+            release.assets.iter()
+                .find(|asset| matches(&file.asset, &asset.name))
+                .map(|asset| (file, asset))
+                .ok_or_else(|| eyre!("release {} has no asset matching `{}`", release.tag_name, file.asset))
+        })
+        .collect::<eyre::Result<Vec<_>>>()?;
+
+    if staged.exists() {
+        fs::remove_dir_all(staged)?;
+    }
+    fs::create_dir_all(staged)?;
+
+    for (_, asset) in &assets {
+        if !is_valid_id(&asset.name) || asset.name == "plugin.toml" || asset.name == "CHANGELOG.md" {
+            eyre::bail!("release {} has an asset with an invalid name {:?}", release.tag_name, asset.name)
+        }
+
+        let path = staged.join(&asset.name);
+        if path.exists() {
+            continue
+        }
+
+        let data = read_asset(&asset.browser_download_url, &config.feed, feed_dir)?;
+        if asset.size.map(|size| size != data.len()).unwrap_or(false) {
+            eyre::bail!("asset {} of release {} was truncated", asset.name, release.tag_name)
+        }
+        fs::write(&path, data)?;
+    }
+
+    let changelog = release.body.as_deref().map(str::trim).filter(|body| !body.is_empty());
+    if let Some(changelog) = changelog {
+        fs::write(staged.join("CHANGELOG.md"), changelog)?;
+    }
+
+    let toml = plugin_toml(config, version, release.prerelease, &assets, changelog.is_some())?;
+    fs::write(staged.join("plugin.toml"), toml)?;
+
+    Ok(())
+}
+
+/// Import every new release in the feed, returning the number of releases imported
+pub fn import(config: &ImportConfig, plugins_dir: &Path, staging_dir: &Path) -> eyre::Result<usize> {
+    let _span = info_span!("import", plugin = %config.name).entered();
+    let feed_dir = Path::new(&config.feed).parent().unwrap_or_else(|| Path::new(""));
+    let feed = read(&config.feed, Path::new(""))?;
+    let releases: Vec<FeedRelease> = serde_json::from_slice(&feed)
+        .map_err(|e| eyre!("invalid release feed {}: {}", config.feed, e))?;
+
+    let mut imported = 0;
+    for release in &releases {
+        if release.draft || (release.prerelease && !config.prereleases) {
+            continue
+        }
+
+        let version = match parse_tag(&release.tag_name, &config.tag_prefix) {
+            Some(version) => version,
+            None => {
+                info!(tag = %release.tag_name, "not importing release, its tag isn't a version");
+                continue
+            }
+        };
+
+        let id = format!("{}-{}", config.name, version);
+        if !is_valid_id(&id) {
+            eyre::bail!("invalid plugin name {:?}", config.name)
+        }
+
+        let dest = plugins_dir.join(&id);
+        if dest.exists() {
+            continue
+        }
+
+        let staged = staging_dir.join(&id);
+        if let Err(e) = stage(config, feed_dir, release, version, &staged) {
+            warn!(tag = %release.tag_name, "not importing release: {}", e);
+            let _ = fs::remove_dir_all(&staged);
+            continue
+        }
+
+        fs::rename(&staged, &dest)?;
+        info!(tag = %release.tag_name, "imported release");
+        imported += 1;
+    }
+
+    Ok(imported)
+}
+
+/// Where releases imported into `plugins_dir` are staged
+pub fn staging_dir(plugins_dir: &Path) -> PathBuf {
+    beside(plugins_dir, STAGING_DIR)
+}
+
+/// Import from the feed now and then every `interval` seconds until shut down
+pub fn run(config: &ImportConfig, plugins_dir: &Path, control: &Control) {
+    let staging_dir = &staging_dir(plugins_dir);
+
+    loop {
+        if let Err(e) = import(config, plugins_dir, staging_dir) {
+            warn!(plugin = %config.name, feed = %config.feed, "failed to import releases: {}", e);
+        }
+
+        if control.wait_for_shutdown(Duration::from_secs(config.interval)) {
+            break
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin_store::PluginStore;
+
+    const FIXTURE: &str = include_str!("../tests/fixtures/github_releases.json");
+
+    fn config(feed: &Path) -> ImportConfig {
+        toml::from_str(&format!(r#"
+            feed = {:?}
+            name = "example"
+            prereleases = true
+            skyline_version = "0.2.0"
+            game = {{ title_ids = ["01006A800016E000"] }}
+            files = [{{ asset = "libexample*.nro", install_location = "sd:/atmosphere/contents/01006A800016E000/romfs/skyline/plugins/libexample.nro" }}]
+        "#, feed.to_str().unwrap())).unwrap()
+    }
+
+    #[test]
+    fn matches_asset_names() {
+        assert!(matches("libexample.nro", "libexample.nro"));
+        assert!(!matches("libexample.nro", "libexample.nro.sig"));
+        assert!(matches("libexample*.nro", "libexample-1.2.0.nro"));
+        assert!(matches("*", "anything"));
+        assert!(matches("lib*-*.nro", "libexample-1.2.0.nro"));
+        assert!(!matches("lib*-*.nro", "libexample.nro"));
+        assert!(!matches("lib*.zip", "libexample.nro"));
+    }
+
+    #[test]
+    fn only_reads_local_assets_of_local_feeds() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("asset.nro"), "asset").unwrap();
+        let feed = root.path().join("releases.json");
+        let feed = feed.to_str().unwrap();
+
+        assert_eq!(read_asset("asset.nro", feed, root.path()).unwrap(), b"asset");
+        assert_eq!(read_asset("file://./asset.nro", feed, root.path()).unwrap(), b"asset");
+        assert!(read_asset("../asset.nro", feed, root.path()).is_err());
+        assert!(read_asset("/etc/passwd", feed, root.path()).is_err());
+        assert!(read_asset("asset.nro", "https://example.com/releases.json", root.path()).is_err());
+        assert!(read_asset("file:///etc/passwd", "http://example.com/releases.json", root.path()).is_err());
+    }
+
+    #[test]
+    fn parses_tags() {
+        assert_eq!(parse_tag("v1.2.0", "v"), Some(Version::new(1, 2, 0)));
+        assert_eq!(parse_tag("1.2", "v"), Some(Version::new(1, 2, 0)));
+        assert_eq!(parse_tag("release-2.0.0", "release-"), Some(Version::new(2, 0, 0)));
+        assert_eq!(parse_tag("v1.3.0-beta.1", "v").unwrap().to_string(), "1.3.0-beta.1");
+        assert_eq!(parse_tag("nightly", "v"), None);
+    }
+
+    #[test]
+    fn imports_releases() {
+        let root = tempfile::tempdir().unwrap();
+        let (plugins_dir, staging_dir) = (root.path().join("plugins"), root.path().join("staging"));
+        fs::create_dir_all(&plugins_dir).unwrap();
+
+        let feed = root.path().join("feed").join("releases.json");
+        fs::create_dir_all(feed.parent().unwrap()).unwrap();
+        fs::write(&feed, FIXTURE).unwrap();
+        for tag in &["v1.1.0", "v1.2.0", "v1.3.0-beta.1"] {
+            let dir = feed.parent().unwrap().join("download").join(tag);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(format!("libexample-{}.nro", &tag[1..])), tag.as_bytes()).unwrap();
+        }
+
+        let config = config(&feed);
+        assert_eq!(import(&config, &plugins_dir, &staging_dir).unwrap(), 3);
+        assert_eq!(import(&config, &plugins_dir, &staging_dir).unwrap(), 0);
+
+        let store = PluginStore::load(&plugins_dir).unwrap();
+        let mut releases: Vec<_> = store.releases(|_| true).into_iter()
+            .map(|release| (release.id, release.version, release.beta, release.files.len(), release.changelog.is_some()))
+            .collect();
+        releases.sort();
+        assert_eq!(releases, vec![
+            ("example-1.1.0".to_owned(), "1.1.0".to_owned(), false, 1, false),
+            ("example-1.2.0".to_owned(), "1.2.0".to_owned(), false, 1, true),
+            ("example-1.3.0-beta.1".to_owned(), "1.3.0-beta.1".to_owned(), true, 1, true),
+        ]);
+
+        let toml = fs::read_to_string(plugins_dir.join("example-1.2.0").join("plugin.toml")).unwrap();
+        assert!(toml.contains("skyline_version = \"0.2.0\""), "{}", toml);
+        assert_eq!(fs::read(plugins_dir.join("example-1.2.0").join("libexample-1.2.0.nro")).unwrap(), b"v1.2.0");
+    }
+}
@@ -0,0 +1,46 @@
+//! The skyline-update server, serving plugin updates over the TCP, framed and HTTP protocols.
+//!
+//! The `update-server` binary is a thin command line interface on top of this library, which can
+//! also be used to embed a server in other tools or start one on ephemeral ports in tests:
+//!
+//! ```no_run
+//! use update_server::Server;
+//!
+//! let server = Server::new("plugins")
+//!     .request_address("127.0.0.1:0".parse().unwrap())
+//!     .bind()
+//!     .unwrap();
+//!
+//! println!("Serving on {}", server.request_address());
+//! let handle = server.handle();
+//! let thread = server.spawn();
+//!
+//! handle.shutdown();
+//! thread.join().unwrap().unwrap();
+//! ```
+
+mod access;
+mod access_log;
+pub mod admin;
+pub mod bundle;
+pub mod check;
+pub mod config;
+mod fetch;
+mod hosted_plugins;
+mod http;
+pub mod import;
+mod limits;
+pub mod logging;
+mod metrics;
+pub mod mirror;
+mod plugin_store;
+mod requests;
+mod server;
+pub mod source;
+pub mod stats;
+mod stream;
+mod tcp;
+pub mod tls;
+
+pub use config::Config;
+pub use server::{BoundServer, Server, ServerHandle, DEFAULT_PORT, STATS_PATH};
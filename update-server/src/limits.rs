@@ -0,0 +1,338 @@
+//! Per-IP limits protecting the server from misbehaving clients, such as one stuck in a boot
+//! loop checking for updates and downloading every file again.
+//!
+//! Requests and downloads are counted per IP, or per /64 network for IPv6, over one minute
+//! windows. Every time an IP exceeds
+//! a limit the violation is logged and counted, and an IP exceeding the limits too often within
+//! a window is refused entirely for a while.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, Shutdown, TcpStream};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+use crate::config::LimitsConfig;
+use crate::metrics::Metrics;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Most clients tracked at once. Once reached, idle clients are forgotten at most once per
+/// window, and new clients are refused while there's still no room.
+const MAX_CLIENTS: usize = 65536;
+
+/// The address a client is limited by. IPv6 clients usually have a whole /64 to themselves, so
+/// they're limited by their network rather than by each address in it.
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u64::MAX as u128))),
+        },
+        v4 => v4,
+    }
+}
+
+/// Something a client does which is rate limited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Request,
+    Download,
+}
+
+#[derive(Default)]
+struct ClientState {
+    connections: usize,
+    window_start: Option<Instant>,
+    requests: u32,
+    downloads: u32,
+    violations: u32,
+    banned_until: Option<Instant>,
+}
+
+impl ClientState {
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.map(|until| now < until).unwrap_or(false)
+    }
+
+    /// Start a new window if the current one is over
+    fn update_window(&mut self, now: Instant) {
+        if self.window_start.map(|start| now.duration_since(start) >= WINDOW).unwrap_or(true) {
+            self.window_start = Some(now);
+            self.requests = 0;
+            self.downloads = 0;
+            self.violations = 0;
+        }
+    }
+
+    fn is_idle(&self, now: Instant) -> bool {
+        self.connections == 0
+            && !self.is_banned(now)
+            && self.window_start.map(|start| now.duration_since(start) >= WINDOW).unwrap_or(true)
+    }
+}
+
+#[derive(Default)]
+struct Clients {
+    states: HashMap<IpAddr, ClientState>,
+    pruned_at: Option<Instant>,
+}
+
+impl Clients {
+    fn get(&mut self, key: IpAddr) -> &mut ClientState {
+        self.states.entry(key).or_default()
+    }
+
+    /// Whether a new client can be tracked, forgetting idle ones if there are too many
+    fn has_room(&mut self, now: Instant) -> bool {
+        if self.states.len() < MAX_CLIENTS {
+            return true
+        }
+        if self.pruned_at.map(|pruned| now.duration_since(pruned) < WINDOW).unwrap_or(false) {
+            return false
+        }
+
+        self.states.retain(|_, client| !client.is_idle(now));
+        self.pruned_at = Some(now);
+        self.states.len() < MAX_CLIENTS
+    }
+}
+
+pub struct Limiter {
+    config: LimitsConfig,
+    metrics: Metrics,
+    clients: Mutex<Clients>,
+    /// Connections open from every client, including exempt ones, so they can be closed when
+    /// shutting down
+    open: Mutex<HashMap<u64, Option<TcpStream>>>,
+    next_id: AtomicU64,
+}
+
+/// An open connection from a client, counted towards its connection limit until dropped
+pub struct Connection<'a> {
+    limiter: &'a Limiter,
+    id: u64,
+    pub ip: IpAddr,
+    exempt: bool,
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        self.limiter.open.lock().unwrap().remove(&self.id);
+        if !self.exempt {
+            if let Some(client) = self.limiter.clients.lock().unwrap().states.get_mut(&client_key(self.ip)) {
+                client.connections = client.connections.saturating_sub(1);
+            }
+        }
+    }
+}
+
+impl Connection<'_> {
+    /// Whether the client may make a request or download, recording it if so
+    pub fn allow(&self, action: Action) -> bool {
+        self.exempt || self.limiter.allow(self.ip, action, Instant::now())
+    }
+
+    /// Record the client sending a request larger than [`Connection::max_request_size`]
+    pub fn request_too_large(&self) {
+        if !self.exempt {
+            let mut clients = self.limiter.clients.lock().unwrap();
+            let client = clients.get(client_key(self.ip));
+            self.limiter.violation(client, self.ip, "request_size", Instant::now());
+        }
+    }
+
+    pub fn max_request_size(&self) -> usize {
+        self.limiter.config.max_request_size
+    }
+}
+
+impl Limiter {
+    pub fn new(config: LimitsConfig, metrics: Metrics) -> Self {
+        Self {
+            config,
+            metrics,
+            clients: Mutex::new(Clients::default()),
+            open: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of connections currently open
+    pub fn open_connections(&self) -> usize {
+        self.open.lock().unwrap().len()
+    }
+
+    /// Close every open connection, making any reads or writes in progress fail
+    pub fn close_all(&self) {
+        for socket in self.open.lock().unwrap().values().flatten() {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Accept a connection, or `None` if the client is banned or has too many connections open
+    pub fn connect(&self, socket: &TcpStream) -> Option<Connection<'_>> {
+        let ip = socket.peer_addr().ok()?.ip();
+        let conn = self.connect_at(ip, Instant::now())?;
+        self.open.lock().unwrap().insert(conn.id, socket.try_clone().ok());
+        Some(conn)
+    }
+
+    fn connect_at(&self, ip: IpAddr, now: Instant) -> Option<Connection<'_>> {
+        if self.config.exempt.contains(&ip) {
+            return Some(self.open(ip, true))
+        }
+
+        let key = client_key(ip);
+        let mut clients = self.clients.lock().unwrap();
+        if !clients.states.contains_key(&key) && !clients.has_room(now) {
+            warn!(client = %ip, "refusing client, too many clients are being tracked");
+            return None
+        }
+
+        let client = clients.get(key);
+        if client.is_banned(now) {
+            return None
+        }
+
+        client.update_window(now);
+        if client.connections >= self.config.max_connections_per_ip {
+            self.violation(client, ip, "connections", now);
+            return None
+        }
+
+        client.connections += 1;
+        Some(self.open(ip, false))
+    }
+
+    fn open(&self, ip: IpAddr, exempt: bool) -> Connection<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.open.lock().unwrap().insert(id, None);
+        Connection { limiter: self, id, ip, exempt }
+    }
+
+    fn allow(&self, ip: IpAddr, action: Action, now: Instant) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        let client = clients.get(client_key(ip));
+        if client.is_banned(now) {
+            return false
+        }
+
+        client.update_window(now);
+        let (count, limit, name) = match action {
+            Action::Request => (&mut client.requests, self.config.requests_per_minute, "requests"),
+            Action::Download => (&mut client.downloads, self.config.downloads_per_minute, "downloads"),
+        };
+
+        if *count >= limit {
+            self.violation(client, ip, name, now);
+            return false
+        }
+
+        *count += 1;
+        true
+    }
+
+    /// Log and count a client exceeding a limit, banning it if it has done so too often
+    fn violation(&self, client: &mut ClientState, ip: IpAddr, limit: &'static str, now: Instant) {
+        client.update_window(now);
+        client.violations += 1;
+        self.metrics.limit_violation(limit);
+        warn!(client = %ip, limit, "client exceeded a limit");
+
+        if client.violations >= self.config.ban_after {
+            client.banned_until = Some(now + Duration::from_secs(self.config.ban_seconds));
+            self.metrics.ban();
+            warn!(client = %ip, seconds = self.config.ban_seconds, "banning client");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(config: &str) -> Limiter {
+        Limiter::new(toml::from_str(config).unwrap(), Metrics::default())
+    }
+
+    #[test]
+    fn limits_rates_and_bans() {
+        let limiter = limiter("requests_per_minute = 2\nban_after = 3\nban_seconds = 60");
+        let ip = "10.0.0.1".parse().unwrap();
+        let start = Instant::now();
+
+        assert!(limiter.allow(ip, Action::Request, start));
+        assert!(limiter.allow(ip, Action::Request, start));
+        assert!(!limiter.allow(ip, Action::Request, start));
+        assert!(limiter.allow(ip, Action::Download, start));
+
+        // a new window
+        let later = start + WINDOW;
+        assert!(limiter.allow(ip, Action::Request, later));
+        assert!(limiter.allow(ip, Action::Request, later));
+        for _ in 0..3 {
+            assert!(!limiter.allow(ip, Action::Request, later));
+        }
+
+        // banned, even once the window is over
+        assert!(!limiter.allow(ip, Action::Download, later + WINDOW / 2));
+        assert!(limiter.connect_at(ip, later + WINDOW / 2).is_none());
+        assert!(limiter.allow(ip, Action::Request, later + WINDOW * 2));
+
+        // other IPs are unaffected
+        assert!(limiter.allow("10.0.0.2".parse().unwrap(), Action::Request, later));
+    }
+
+    #[test]
+    fn limits_connections() {
+        let limiter = limiter("max_connections_per_ip = 2\nexempt = [\"127.0.0.1\"]");
+        let ip = "10.0.0.1".parse().unwrap();
+
+        let now = Instant::now();
+
+        let first = limiter.connect_at(ip, now).unwrap();
+        let _second = limiter.connect_at(ip, now).unwrap();
+        assert!(limiter.connect_at(ip, now).is_none());
+
+        drop(first);
+        assert!(limiter.connect_at(ip, now).is_some());
+        assert_eq!(limiter.open_connections(), 1);
+
+        let exempt = "127.0.0.1".parse().unwrap();
+        let connections: Vec<_> = (0..10).map(|_| limiter.connect_at(exempt, now).unwrap()).collect();
+        assert!(connections.iter().all(|connection| connection.allow(Action::Request)));
+        assert_eq!(limiter.open_connections(), 11);
+    }
+
+    #[test]
+    fn limits_ipv6_networks() {
+        let limiter = limiter("requests_per_minute = 1");
+        let now = Instant::now();
+
+        assert!(limiter.allow("2001:db8::1".parse().unwrap(), Action::Request, now));
+        assert!(!limiter.allow("2001:db8::ffff:2".parse().unwrap(), Action::Request, now));
+        assert!(limiter.allow("2001:db8:0:1::1".parse().unwrap(), Action::Request, now));
+
+        assert!(limiter.allow("10.0.0.1".parse().unwrap(), Action::Request, now));
+        assert!(!limiter.allow("::ffff:10.0.0.1".parse().unwrap(), Action::Request, now));
+    }
+
+    #[test]
+    fn caps_tracked_clients() {
+        let limiter = limiter("");
+        let now = Instant::now();
+
+        for i in 0..MAX_CLIENTS as u32 {
+            assert!(limiter.connect_at(IpAddr::from((i + 1).to_be_bytes()), now).is_some());
+        }
+        let new = "255.255.255.255".parse().unwrap();
+        assert!(limiter.connect_at(new, now).is_none());
+
+        // the others are idle once their window is over
+        assert!(limiter.connect_at(new, now + WINDOW).is_some());
+        assert_eq!(limiter.clients.lock().unwrap().states.len(), 1);
+    }
+}
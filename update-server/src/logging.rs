@@ -0,0 +1,47 @@
+//! Structured logging of the server's activity with `tracing`.
+//!
+//! Each client connection is logged within a span recording the peer address and protocol, and
+//! each request within a nested span recording the request type and plugin, so every event can
+//! be traced back to the client and request which caused it.
+
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+
+use color_eyre::eyre::{self, eyre};
+use tracing::{info_span, Span};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::prelude::*;
+
+use crate::access_log::{self, AccessLog};
+use crate::config::{AccessLogConfig, LogFormat, LoggingConfig};
+
+/// Install the global subscriber, writing events to stdout in the configured format and, if
+/// enabled, requests and downloads to the access log
+pub fn init(config: &LoggingConfig, access_log: Option<&AccessLogConfig>) -> eyre::Result<()> {
+    let filter = match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(_) => EnvFilter::from_default_env(),
+        Err(_) => EnvFilter::try_new(&config.level)
+            .map_err(|e| eyre!("invalid log level {:?}: {}", config.level, e))?,
+    };
+
+    let stdout = match config.format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().with_ansi(std::io::stdout().is_terminal()).boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().flatten_event(true).boxed(),
+    };
+
+    let access_log = access_log
+        .map(|config| AccessLog::open(config).map(|layer| layer.with_filter(filter_fn(access_log::filter))))
+        .transpose()?;
+
+    tracing_subscriber::registry()
+        .with(stdout.with_filter(filter))
+        .with(access_log)
+        .try_init()
+        .map_err(|e| eyre!("failed to initialize logging: {}", e))
+}
+
+/// The span a client connection is served in
+pub fn connection_span(protocol: &'static str, peer: SocketAddr) -> Span {
+    info_span!("connection", %peer, protocol)
+}
@@ -1,229 +1,112 @@
-mod hosted_plugins;
-
-use notify::{Watcher, RecursiveMode, watcher};
-use std::sync::mpsc::channel;
-use std::time::Duration;
-
 use std::fs;
-use std::sync::Arc;
 use std::path::Path;
-use std::net::TcpListener;
-use std::io::{prelude::*, BufReader};
 
 use color_eyre::eyre;
 
-use semver::Version;
-use update_protocol::{InstallLocation, Request, UpdateResponse, ResponseCode, UpdateFile, PluginMetadata};
+use update_server::{admin, bundle, check, config, import, logging, mirror, stats, tls};
+use update_server::source::BlobStore;
+use update_server::{Config, Server, STATS_PATH};
 
-struct PluginFile {
-    install: InstallLocation,
-    data: Arc<Vec<u8>>,
-    index: u64,
-}
+fn main() -> eyre::Result<()> {
+    color_eyre::install()?;
 
-impl From<&PluginFile> for UpdateFile {
-    fn from(file: &PluginFile) -> Self {
-        UpdateFile {
-            size: file.data.len(),
-            download_index: file.index.clone(),
-            install_location: file.install.clone()
+    //hosted_plugins::print_default();
+
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("check") => {
+            let dir = args.next().unwrap_or_else(|| "plugins".to_owned());
+            check::run(Path::new(&dir))
+        }
+        Some("bundle") => {
+            match (args.next(), args.next()) {
+                (Some(dir), Some(out)) => {
+                    bundle::create(Path::new(&dir), Path::new(&out))?;
+                    println!("Created bundle {}", out);
+                    Ok(())
+                }
+                _ => eyre::bail!("Usage: update-server bundle <plugin folder> <output.zip|output.tar.zst>"),
+            }
         }
+        Some("stats") => stats::print(Path::new(STATS_PATH), args.next().as_deref()),
+        Some("import") => import_once(),
+        Some("status") => mirror::print_status(&mirror::status_path(Path::new("plugins"))),
+        Some("store") => store(args.collect()),
+        Some("admin") => {
+            let command = args.next()
+                .ok_or_else(|| eyre::eyre!("Usage: update-server admin <reload|shutdown|status>"))?;
+            let address = Config::load()?.admin_address
+                .ok_or_else(|| eyre::eyre!("admin_address isn't set in {}", config::CONFIG_PATH))?;
+            println!("{}", admin::send(address, &command)?);
+            Ok(())
+        }
+        Some("fingerprint") => {
+            let cert = args.next()
+                .ok_or_else(|| eyre::eyre!("Usage: update-server fingerprint <cert.pem>"))?;
+            println!("{}", tls::fingerprint(Path::new(&cert))?);
+            Ok(())
+        }
+        Some(command) => eyre::bail!(
+            "Unknown command '{}', expected 'check', 'bundle', 'stats', 'import', 'status', 'store', 'admin', 'fingerprint' or no arguments",
+            command
+        ),
+        None => serve(),
     }
 }
 
-struct Plugin {
-    pub name: String,
-    pub plugin_version: Version,
-    pub files: Vec<PluginFile>,
-    pub metadata_files: Vec<Arc<Vec<u8>>>,
-    pub metadata: PluginMetadata,
-    pub skyline_version: Version,
-    pub beta: bool,
-}
-
-const PORT_NUM: u16 = 45000;
-
-fn setup_plugin_ports() -> eyre::Result<(Vec<Plugin>, Vec<Arc<Vec<u8>>>)> {
-    let plugins = hosted_plugins::get()?;
-
-    let mut i = 0;
-    let plugins: Vec<Plugin> = plugins.into_iter()
-        .map(|plugin|{
-            let hosted_plugins::Plugin {
-                name, plugin_version, files, skyline_version, beta, metadata
-            } = plugin;
-
-            let files = files.into_iter()
-                .map(|(install, data)|{
-                    let index = i;
-                    i += 1;
-                    Ok(PluginFile {
-                        install,
-                        index,
-                        data: Arc::new(data),
-                    })
-                })
-                .collect::<eyre::Result<_>>()?;
-
-            let hosted_plugins::Metadata {
-                name: meta_name, images, changelog, description
-            } = metadata;
-
-            let image_count = images.as_ref().map(|x| x.len() as _).unwrap_or(0);
-
-            let metadata = PluginMetadata {
-                name: meta_name,
-                description,
-                images_index: i,
-                image_count,
-                changelog_index: i + image_count,
-            };
-
-            let metadata_files = images.into_iter()
-                .map(|images| images.into_iter())
-                .flatten()
-                .map(|image| Arc::new(image))
-                .chain(changelog.into_iter().map(|x| Arc::new(x.into_bytes())))
-                .collect();
-
-            Ok(Plugin {
-                name,
-                plugin_version,
-                skyline_version,
-                files,
-                metadata_files,
-                metadata,
-                beta
-            })
-        })
-        .collect::<eyre::Result<_>>()?;
-
-    let files = plugins.iter()
-        .map(|plugin| plugin.files.iter().map(|file| Arc::clone(&file.data)))
-        .flatten()
-        .collect();
-
-    Ok((plugins, files))
-}
-
-fn main() -> eyre::Result<()> {
-    color_eyre::install()?;
-
-    //hosted_plugins::print_default();
+/// Entrypoint for `update-server import`, imports new releases from every configured feed once
+fn import_once() -> eyre::Result<()> {
+    let config = Config::load()?;
+    logging::init(&config.logging, None)?;
+    if config.imports.is_empty() {
+        println!("No release feeds configured in {}", config::CONFIG_PATH);
+        return Ok(())
+    }
 
     let plugins_dir = Path::new("plugins");
     if !plugins_dir.exists() {
         fs::create_dir(plugins_dir)?;
     }
 
-    let (tx, rx) = channel();
+    for feed in &config.imports {
+        let imported = import::import(feed, plugins_dir, &import::staging_dir(plugins_dir))?;
+        println!("Imported {} release(s) of {} from {}", imported, feed.name, feed.feed);
+    }
 
-    let mut watcher = watcher(tx, Duration::from_secs(10)).unwrap();
+    Ok(())
+}
 
-    watcher.watch("plugins", RecursiveMode::Recursive).unwrap();
+/// Entrypoint for `update-server store`, adds plugin folders or bundles to the blob store
+fn store(paths: Vec<String>) -> eyre::Result<()> {
+    if paths.is_empty() {
+        eyre::bail!("Usage: update-server store <plugin folder|bundle>...")
+    }
 
-    let (mut plugins, mut files) = setup_plugin_ports()?;
-    let main_port = TcpListener::bind(("0.0.0.0", PORT_NUM))?;
-    let download_port = TcpListener::bind(("0.0.0.0", PORT_NUM + 1))?;
-    main_port.set_nonblocking(true)?;
-    download_port.set_nonblocking(true)?;
+    let dir = Config::load()?.blob_store
+        .ok_or_else(|| eyre::eyre!("blob_store isn't set in {}", config::CONFIG_PATH))?;
+    let store = BlobStore::open(dir)?;
 
-    crossbeam::scope(move |scope|{
-        loop {
-            match rx.try_recv() {
-                Ok(notify::DebouncedEvent::Error(err, Some(path))) => {
-                    println!("File watch error at path {}: {}", path.display(), err);
-                }
-                Ok(notify::DebouncedEvent::Error(err, None)) => {
-                    println!("File watch error: {}", err);
-                }
-                Ok(_) => {
-                    println!("Change detected: refreshing plugins...");
-                    // clear plugins (close sockets)
-                    plugins = Vec::with_capacity(0);
-                    // setup new plugins
-                    let (x, y) = setup_plugin_ports()?;
-                    plugins = x;
-                    files = y;
-                },
-                Err(_) => {}
-            }
+    for path in paths {
+        let added = store.add(Path::new(&path))?;
+        println!("Stored {} ({} of {} file(s) new)", added.id, added.new_files, added.files);
+    }
 
-            while let Ok((socket, _)) = main_port.accept() {
-                let mut socket = BufReader::new(socket);
-                let plugins = &plugins;
-                let mut packet = String::new();
-                let _ = socket.read_line(&mut packet);
-                macro_rules! respond {
-                    ($expr:expr) => {{
-                        let response = $expr;
-                        let mut socket = socket.into_inner();
-                        let _ = socket.write(format!("{}\n", serde_json::to_string(&response).unwrap()).as_bytes());
-                        let _ = socket.shutdown(std::net::Shutdown::Both);
-                    }}
-                }
-                match serde_json::from_str::<Request>(&packet) {
-                    Ok(Request::Update { plugin_name, plugin_version, beta, .. }) => {
-                        let beta = beta.unwrap_or(false);
-                        let plugin = plugins.iter().filter(|plugin| {
-                            plugin.name == plugin_name && (beta || !plugin.beta)
-                        }).max_by_key(|plugin| &plugin.plugin_version);
-
-                        let response = if let Some(plugin) = plugin {
-                            if let Ok(current_version) = plugin_version.parse::<Version>() {
-                                if current_version < plugin.plugin_version {
-                                    UpdateResponse {
-                                        code: ResponseCode::Update,
-                                        update_plugin: true,
-                                        update_skyline: false,
-                                        plugin_name,
-                                        new_plugin_version: plugin.plugin_version.to_string(),
-                                        new_skyline_version: None,
-                                        required_files: plugin.files.iter().map(|file| file.into()).collect()
-                                    }
-                                } else {
-                                    UpdateResponse::no_update()
-                                }
-                            } else {
-                                UpdateResponse::invalid_request()
-                            }
-                        } else {
-                            UpdateResponse::plugin_not_found()
-                        };
-
-                        respond!(response);
-                    }
-                    Ok(Request::Metadata { plugin_name, beta, .. }) => {
-                        let beta = beta.unwrap_or(false);
-                        let plugin = plugins.iter().filter(|plugin| {
-                            plugin.name == plugin_name && (beta || !plugin.beta)
-                        }).max_by_key(|plugin| &plugin.plugin_version);
-
-                        if let Some(plugin) = plugin {
-                            respond!(&plugin.metadata)
-                        }
-                    }
-                    _ => respond!(UpdateResponse::invalid_request()),
-                }
-            }
+    Ok(())
+}
 
-            while let Ok((mut socket, _)) = download_port.accept() {
-                let mut buf = [0; 8];
-                if let Ok(_) = socket.read_exact(&mut buf) {
-                    let index = u64::from_be_bytes(buf) as usize;
-                    if let Some(file) = files.get(index) {
-                        let data = Arc::clone(&file);
-                        scope.spawn(move |_| {
-                            let _ = socket.write_all(&data);
-                        });
-                    }
-                } else {
-                    println!("Failed to read index");
-                    let _ = socket.shutdown(std::net::Shutdown::Both);
-                }
-            }
+/// Entrypoint for `update-server` with no arguments, serves the plugins in `plugins` until
+/// shut down
+fn serve() -> eyre::Result<()> {
+    let config = Config::load()?;
+    logging::init(&config.logging, config.access_log.as_ref())?;
 
-            std::thread::sleep(Duration::from_millis(10));
-        }
-    }).unwrap()
+    let blob_store = config.blob_store.clone();
+    let mut server = Server::with_config("plugins", config);
+    if let Some(dir) = blob_store {
+        server = server.plugin_source(BlobStore::open(dir)?);
+    }
+
+    let server = server.bind()?;
+    server.handle().handle_signals()?;
+    server.run()
 }
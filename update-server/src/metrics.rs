@@ -0,0 +1,165 @@
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use std::collections::BTreeMap;
+use std::net::TcpStream;
+use std::io::BufReader;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::http;
+use crate::plugin_store::PluginStore;
+
+#[derive(Default)]
+struct Inner {
+    /// Requests handled, keyed by (request type, response code)
+    requests: Mutex<BTreeMap<(&'static str, String), u64>>,
+    active_downloads: AtomicU64,
+    bytes_served: AtomicU64,
+    /// Times a client exceeded a limit, keyed by the limit
+    limit_violations: Mutex<BTreeMap<&'static str, u64>>,
+    bans: AtomicU64,
+}
+
+/// Server-wide counters exposed in the Prometheus text format.
+///
+/// Cheap to clone, clones share the same counters.
+#[derive(Clone, Default)]
+pub struct Metrics(Arc<Inner>);
+
+/// Tracks a download in progress, the active download gauge is decremented when dropped
+pub struct DownloadGuard(Metrics);
+
+impl Drop for DownloadGuard {
+    fn drop(&mut self) {
+        (self.0).0.active_downloads.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn request(&self, request: &'static str, code: impl Into<String>) {
+        *self.0.requests.lock().unwrap().entry((request, code.into())).or_default() += 1;
+    }
+
+    pub fn download_started(&self) -> DownloadGuard {
+        self.0.active_downloads.fetch_add(1, Ordering::Relaxed);
+        DownloadGuard(self.clone())
+    }
+
+    pub fn bytes_served(&self, bytes: u64) {
+        self.0.bytes_served.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn limit_violation(&self, limit: &'static str) {
+        *self.0.limit_violations.lock().unwrap().entry(limit).or_default() += 1;
+    }
+
+    pub fn ban(&self) {
+        self.0.bans.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Render all metrics in the Prometheus text exposition format
+    pub fn render(&self, store: &PluginStore) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "update_server_requests_total", "counter", "Requests handled by request type and response code");
+        for ((request, code), count) in self.0.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out, "update_server_requests_total{{request=\"{}\",code=\"{}\"}} {}",
+                request, escape(code), count
+            );
+        }
+
+        header(&mut out, "update_server_active_downloads", "gauge", "Downloads currently in progress");
+        let _ = writeln!(out, "update_server_active_downloads {}", self.0.active_downloads.load(Ordering::Relaxed));
+
+        header(&mut out, "update_server_bytes_served_total", "counter", "Bytes of plugin files sent to clients");
+        let _ = writeln!(out, "update_server_bytes_served_total {}", self.0.bytes_served.load(Ordering::Relaxed));
+
+        header(&mut out, "update_server_limit_violations_total", "counter", "Times a client exceeded a limit, by limit");
+        for (limit, count) in self.0.limit_violations.lock().unwrap().iter() {
+            let _ = writeln!(out, "update_server_limit_violations_total{{limit=\"{}\"}} {}", limit, count);
+        }
+
+        header(&mut out, "update_server_bans_total", "counter", "Clients temporarily banned for exceeding the limits");
+        let _ = writeln!(out, "update_server_bans_total {}", self.0.bans.load(Ordering::Relaxed));
+
+        let (reloads, reload_failures) = store.reload_counts();
+        header(&mut out, "update_server_plugin_reloads_total", "counter", "Plugins loaded successfully");
+        let _ = writeln!(out, "update_server_plugin_reloads_total {}", reloads);
+        header(&mut out, "update_server_plugin_reload_failures_total", "counter", "Plugins which failed to load");
+        let _ = writeln!(out, "update_server_plugin_reload_failures_total {}", reload_failures);
+
+        header(&mut out, "update_server_plugins_loaded", "gauge", "Plugins currently being served");
+        let _ = writeln!(out, "update_server_plugins_loaded {}", store.plugins().count());
+
+        header(&mut out, "update_server_plugin_memory_bytes", "gauge", "Size of the files held in memory for each plugin");
+        for (plugin, size) in store.memory_usage() {
+            let _ = writeln!(
+                out, "update_server_plugin_memory_bytes{{plugin=\"{}\",version=\"{}\",channel=\"{}\"}} {}",
+                escape(&plugin.name), plugin.plugin_version, if plugin.is_beta() { "beta" } else { "stable" }, size
+            );
+        }
+
+        out
+    }
+
+    /// Respond to a single connection on the metrics port with an already rendered body
+    pub fn respond(socket: TcpStream, body: String) {
+        let _ = socket.set_read_timeout(Some(Duration::from_secs(5)));
+        let mut socket = BufReader::new(socket);
+
+        let request = http::read_request(&mut socket, 0);
+        let mut socket = socket.into_inner();
+        let _ = match request {
+            Ok(request) if request.method == "GET" && request.path == "/metrics" => {
+                http::write_response(&mut socket, 200, "text/plain; version=0.0.4", body.as_bytes())
+            }
+            _ => http::write_response(&mut socket, 404, "text/plain", b"not found"),
+        };
+        let _ = socket.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escape a label value as required by the Prometheus text format
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_metrics() {
+        let dir = tempfile::tempdir().unwrap();
+        let plugin = dir.path().join("plugin");
+        std::fs::create_dir(&plugin).unwrap();
+        std::fs::write(plugin.join("plugin.toml"), r#"
+            version = "1.0.0"
+            name = "my \"plugin\""
+            files = [{ install_location = "sd:/file.txt", filename = "file.txt" }]
+        "#).unwrap();
+        std::fs::write(plugin.join("file.txt"), "12345").unwrap();
+        let store = PluginStore::load(dir.path()).unwrap();
+
+        let metrics = Metrics::default();
+        metrics.request("Update", "NoUpdate");
+        metrics.request("Update", "NoUpdate");
+        metrics.bytes_served(42);
+        metrics.limit_violation("requests");
+        let _download = metrics.download_started();
+
+        let text = metrics.render(&store);
+        assert!(text.contains("update_server_requests_total{request=\"Update\",code=\"NoUpdate\"} 2\n"), "{}", text);
+        assert!(text.contains("update_server_active_downloads 1\n"), "{}", text);
+        assert!(text.contains("update_server_bytes_served_total 42\n"), "{}", text);
+        assert!(text.contains("update_server_plugins_loaded 1\n"), "{}", text);
+        assert!(text.contains("update_server_limit_violations_total{limit=\"requests\"} 1\n"), "{}", text);
+        assert!(text.contains("update_server_plugin_memory_bytes{plugin=\"my \\\"plugin\\\"\",version=\"1.0.0\",channel=\"stable\"} 5\n"), "{}", text);
+    }
+}
@@ -0,0 +1,515 @@
+//! Mirror mode, replicating the plugins of an upstream update server.
+//!
+//! Every sync lists the upstream's releases over the framed protocol, downloads any which are
+//! new or changed, checks every file against its hash, and writes each release to the plugins
+//! directory as a plugin folder. The file watcher then picks it up like any other plugin.
+//! Releases removed upstream are removed from the mirror. Plugin folders the mirror didn't create
+//! are left alone.
+
+use std::fs;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use color_eyre::eyre::{self, eyre};
+use serde::{Serialize, Deserialize};
+use tracing::{info, info_span, warn};
+
+use update_protocol::{InstallLocation, ListResponse, Release, Request, sha256_hex};
+use update_protocol::frame::{self, FrameKind};
+
+use crate::admin::Control;
+use crate::config::MirrorConfig;
+use crate::hosted_plugins::{PluginFile, PluginToml, TomlGame, TomlMetadata};
+
+/// File the state of the mirror is kept in, next to the plugins directory
+const STATUS_FILE: &str = "mirror-status.json";
+
+/// Where releases are downloaded to before being moved into the plugins directory, so the
+/// server never loads a partially downloaded release. It's next to the plugins directory, so
+/// releases can be renamed into place.
+const STAGING_DIR: &str = "mirror-staging";
+
+/// Folder within a mirrored release holding its files, named by their hash
+const FILES_DIR: &str = "files";
+
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The state of the mirror, persisted so `update-server status` can report on it
+#[derive(Serialize, Deserialize, Default)]
+pub struct Status {
+    pub upstream: String,
+    /// Unix time of the last sync attempt
+    pub last_attempt: Option<u64>,
+    /// Unix time of the last sync which completed, everything upstream at that point is mirrored
+    pub last_success: Option<u64>,
+    /// Why the last sync failed, if it did
+    pub last_error: Option<String>,
+    /// Entries of the plugins directory created by the mirror
+    pub releases: BTreeSet<String>,
+}
+
+impl Status {
+    fn load(path: &Path) -> eyre::Result<Self> {
+        if path.exists() {
+            Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+        } else {
+            Ok(Status::default())
+        }
+    }
+
+    fn save(&self, path: &Path) -> eyre::Result<()> {
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+/// A framed protocol connection to the upstream server
+struct Upstream {
+    socket: TcpStream,
+}
+
+impl Upstream {
+    fn connect(address: &str) -> eyre::Result<Self> {
+        let addr = address.to_socket_addrs()?
+            .next()
+            .ok_or_else(|| eyre!("upstream {} not found", address))?;
+
+        let mut socket = TcpStream::connect_timeout(&addr, UPSTREAM_TIMEOUT)?;
+        socket.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+        socket.set_write_timeout(Some(UPSTREAM_TIMEOUT))?;
+
+        frame::write_handshake(&mut socket)?;
+        if frame::read_handshake(&mut socket)? != frame::VERSION {
+            eyre::bail!("upstream uses an unsupported version of the framed protocol")
+        }
+
+        Ok(Upstream { socket })
+    }
+
+    fn exchange(&mut self, kind: FrameKind, payload: &[u8], expected: FrameKind, max_len: u32) -> eyre::Result<Vec<u8>> {
+        frame::write_frame(&mut self.socket, kind, payload)?;
+        match frame::read_frame(&mut self.socket, max_len)? {
+            Some(frame) if frame.kind == expected => Ok(frame.payload),
+            Some(frame) if frame.kind == FrameKind::Error => {
+                Err(eyre!("upstream error: {}", String::from_utf8_lossy(&frame.payload)))
+            }
+            Some(_) => Err(eyre!("unexpected frame from upstream")),
+            None => Err(eyre!("upstream closed the connection")),
+        }
+    }
+
+    fn list(&mut self) -> eyre::Result<Vec<Release>> {
+        let request = serde_json::to_vec(&Request::List {})?;
+        let response = self.exchange(FrameKind::Request, &request, FrameKind::Response, frame::MAX_RESPONSE_FRAME)?;
+
+        serde_json::from_slice::<ListResponse>(&response)
+            .map(|list| list.releases)
+            .map_err(|_| eyre!("upstream doesn't support listing releases, it may need updating"))
+    }
+
+    /// Download a file, checking it against its size and hash
+    fn download(&mut self, file: &MirroredFile) -> eyre::Result<Vec<u8>> {
+        let data = self.exchange(FrameKind::FileRequest, &file.download_index.to_be_bytes(), FrameKind::FileData, frame::max_file_frame(file.size))?;
+        if data.len() != file.size || sha256_hex(&data) != file.sha256 {
+            eyre::bail!("file {} from upstream doesn't match its hash", file.download_index)
+        }
+
+        Ok(data)
+    }
+}
+
+/// A file to copy from the upstream, either installed by clients or a metadata asset
+struct MirroredFile {
+    download_index: u64,
+    size: usize,
+    sha256: String,
+}
+
+impl MirroredFile {
+    /// Path of the file within the release's folder
+    fn path(&self) -> PathBuf {
+        Path::new(FILES_DIR).join(&self.sha256)
+    }
+}
+
+/// Every file in a release, checking the upstream gave a usable hash and install location for each
+fn files(release: &Release) -> eyre::Result<Vec<MirroredFile>> {
+    if release.files.iter().any(|file| matches!(file.install_location, InstallLocation::Unknown)) {
+        eyre::bail!("release has a file with an install location this server doesn't support")
+    }
+
+    let installed = release.files.iter().map(|file| {
+        let sha256 = file.sha256.clone()
+            .ok_or_else(|| eyre!("upstream didn't send file hashes, it may need updating"))?;
+        Ok(MirroredFile { download_index: file.download_index, size: file.size, sha256 })
+    });
+
+    let assets = release.images.iter().chain(&release.changelog).map(|asset| {
+        Ok(MirroredFile { download_index: asset.download_index, size: asset.size, sha256: asset.sha256.clone() })
+    });
+
+    let files: Vec<MirroredFile> = installed.chain(assets).collect::<eyre::Result<_>>()?;
+    for file in &files {
+        if file.sha256.len() != 64 || !file.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            eyre::bail!("invalid file hash `{}` from upstream", file.sha256)
+        }
+    }
+
+    Ok(files)
+}
+
+/// Path of a file or folder named `name` next to the plugins directory
+pub(crate) fn beside(plugins_dir: &Path, name: &str) -> PathBuf {
+    plugins_dir.parent().unwrap_or_else(|| Path::new("")).join(name)
+}
+
+/// Where the state of the mirror writing to `plugins_dir` is kept
+pub fn status_path(plugins_dir: &Path) -> PathBuf {
+    beside(plugins_dir, STATUS_FILE)
+}
+
+/// Whether a release id can safely be used as the name of a folder in the plugins directory
+pub fn is_valid_id(id: &str) -> bool {
+    let mut components = Path::new(id).components();
+    matches!(components.next(), Some(Component::Normal(_)))
+        && components.next().is_none()
+        && !id.starts_with('.')
+        && !id.contains(['/', '\\'])
+}
+
+/// The `plugin.toml` describing a mirrored release
+fn plugin_toml(release: &Release, files: &[MirroredFile]) -> eyre::Result<String> {
+    let installed = release.files.len();
+    let path = |file: &MirroredFile| file.path();
+
+    let images: Vec<PathBuf> = files[installed..installed + release.images.len()].iter().map(path).collect();
+    let changelog = release.changelog.as_ref().map(|_| path(&files[files.len() - 1]));
+
+    let has_metadata = release.name.is_some()
+        || release.description.is_some()
+        || !images.is_empty()
+        || changelog.is_some();
+
+    let has_game = !release.title_ids.is_empty() || release.game_versions.is_some();
+
+    let plugin = PluginToml {
+        version: release.version.parse().map_err(|_| eyre!("invalid version `{}`", release.version))?,
+        name: release.plugin_name.clone(),
+        beta: Some(release.beta),
+        files: release.files.iter()
+            .zip(files)
+            .map(|(file, mirrored)| PluginFile {
+                install_location: file.install_location.clone(),
+                filename: mirrored.path(),
+            })
+            .collect(),
+        skyline_version: release.skyline_version.as_ref().and_then(|version| version.parse().ok()),
+        metadata: if has_metadata {
+            Some(TomlMetadata {
+                name: release.name.clone(),
+                images: Some(images),
+                description: release.description.clone(),
+                changelog,
+            })
+        } else {
+            None
+        },
+        game: if has_game {
+            Some(TomlGame {
+                title_ids: Some(release.title_ids.iter().map(|title_id| format!("{:016X}", title_id)).collect()),
+                versions: release.game_versions.clone(),
+            })
+        } else {
+            None
+        },
+        dependencies: release.dependencies.iter()
+            .map(|dependency| (dependency.plugin_name.clone(), dependency.version_req.clone()))
+            .collect::<BTreeMap<_, _>>(),
+    };
+
+    plugin.to_toml_string()
+}
+
+/// Download a release into the staging directory, reusing any files the mirror already has
+fn stage(upstream: &mut Upstream, release: &Release, files: &[MirroredFile], toml: &str, current: &Path, staged: &Path) -> eyre::Result<()> {
+    if staged.exists() {
+        fs::remove_dir_all(staged)?;
+    }
+    fs::create_dir_all(staged.join(FILES_DIR))?;
+
+    for file in files {
+        let path = staged.join(file.path());
+        if path.exists() {
+            continue
+        }
+
+        let existing = current.join(file.path());
+        if existing.is_file() && fs::read(&existing).map(|data| sha256_hex(&data) == file.sha256).unwrap_or(false) {
+            fs::copy(&existing, &path)?;
+        } else {
+            fs::write(&path, upstream.download(file)?)?;
+        }
+    }
+
+    fs::write(staged.join("plugin.toml"), toml)?;
+    info!(release = %release.id, "mirrored release");
+
+    Ok(())
+}
+
+/// Bring the plugins directory in line with the upstream, returning the number of releases
+/// added, changed or removed
+fn sync(upstream: &str, plugins_dir: &Path, staging_dir: &Path, status: &mut Status) -> eyre::Result<usize> {
+    let mut upstream = Upstream::connect(upstream)?;
+    let releases = upstream.list()?;
+    let mut changed = 0;
+
+    for release in &releases {
+        if !is_valid_id(&release.id) {
+            warn!(release = ?release.id, "not mirroring release with an invalid id");
+            continue
+        }
+
+        let dest = plugins_dir.join(&release.id);
+        if dest.exists() && !status.releases.contains(&release.id) {
+            warn!(release = %release.id, "not mirroring release, {} wasn't created by the mirror", dest.display());
+            continue
+        }
+
+        let prepared = files(release).and_then(|files| {
+            let toml = plugin_toml(release, &files)?;
+            Ok((files, toml))
+        });
+        let (files, toml) = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                warn!(release = %release.id, "not mirroring release: {}", e);
+                continue
+            }
+        };
+        let current = fs::read_to_string(dest.join("plugin.toml")).map(|current| current == toml).unwrap_or(false)
+            && files.iter().all(|file| dest.join(file.path()).is_file());
+        if current {
+            continue
+        }
+
+        let staged = staging_dir.join(&release.id);
+        stage(&mut upstream, release, &files, &toml, &dest, &staged)?;
+
+        status.releases.insert(release.id.clone());
+        if dest.exists() {
+            fs::remove_dir_all(&dest)?;
+        }
+        fs::rename(&staged, &dest)?;
+        changed += 1;
+    }
+
+    let upstream_ids: BTreeSet<&str> = releases.iter().map(|release| release.id.as_str()).collect();
+    let removed: Vec<String> = status.releases.iter()
+        .filter(|id| !upstream_ids.contains(id.as_str()))
+        .cloned()
+        .collect();
+
+    for id in removed {
+        let dest = plugins_dir.join(&id);
+        if dest.exists() {
+            fs::remove_dir_all(&dest)?;
+        }
+        info!(release = %id, "removed mirrored release");
+        status.releases.remove(&id);
+        changed += 1;
+    }
+
+    Ok(changed)
+}
+
+/// Sync with the upstream server now and then every `interval` seconds until shut down,
+/// recording the outcome of each sync in [`status_path`]
+pub fn run(config: &MirrorConfig, plugins_dir: &Path, control: &Control) {
+    let _span = info_span!("mirror", upstream = %config.upstream).entered();
+    let status_path = &status_path(plugins_dir);
+    let staging_dir = &beside(plugins_dir, STAGING_DIR);
+
+    loop {
+        let mut status = Status::load(status_path).unwrap_or_default();
+        status.upstream = config.upstream.clone();
+        status.last_attempt = Some(unix_time());
+
+        match sync(&config.upstream, plugins_dir, staging_dir, &mut status) {
+            Ok(changed) => {
+                if changed > 0 {
+                    info!(changed, "synced with upstream");
+                }
+                status.last_success = status.last_attempt;
+                status.last_error = None;
+            }
+            Err(e) => {
+                warn!("failed to sync with upstream: {}", e);
+                status.last_error = Some(e.to_string());
+            }
+        }
+
+        if let Err(e) = status.save(status_path) {
+            warn!("failed to save mirror status: {}", e);
+        }
+
+        if control.wait_for_shutdown(Duration::from_secs(config.interval)) {
+            break
+        }
+    }
+}
+
+fn format_age(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m {}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+/// Entrypoint for `update-server status`, prints how far behind its upstream the mirror is
+pub fn print_status(path: &Path) -> eyre::Result<()> {
+    if !path.exists() {
+        println!("Not running as a mirror ({} not found)", path.display());
+        return Ok(())
+    }
+
+    let status = Status::load(path)?;
+    let now = unix_time();
+
+    println!("Upstream:            {}", status.upstream);
+    println!("Mirrored releases:   {}", status.releases.len());
+    match status.last_success {
+        Some(time) => {
+            let age = format_age(now.saturating_sub(time));
+            println!("Last complete sync:  {} ago", age);
+            println!("Replication lag:     up to {}", age);
+        }
+        None => println!("Last complete sync:  never"),
+    }
+    if let (Some(error), Some(time)) = (&status.last_error, status.last_attempt) {
+        println!("Last sync failed:    {} ago, {}", format_age(now.saturating_sub(time)), error);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::RwLock;
+
+    use crate::access::AccessControl;
+    use crate::limits::Limiter;
+    use crate::metrics::Metrics;
+    use crate::plugin_store::PluginStore;
+    use crate::requests::Context;
+    use crate::stats::Stats;
+    use crate::tcp;
+
+    fn write_plugin(dir: &Path) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("plugin.toml"), r#"
+            version = "1.2.0"
+            name = "a"
+            files = [{ install_location = "sd:/a.nro", filename = "a.nro" }]
+            game = { title_ids = ["01006A800016E000"], versions = ">=13.0.0" }
+            dependencies = { hook = "^1.1" }
+            metadata = { name = "A", images = ["icon.png"], changelog = "CHANGELOG.md" }
+        "#).unwrap();
+        fs::write(dir.join("a.nro"), "plugin").unwrap();
+        fs::write(dir.join("icon.png"), "image").unwrap();
+        fs::write(dir.join("CHANGELOG.md"), "changes").unwrap();
+    }
+
+    /// Releases without their download indices, which differ between servers
+    fn summary(store: &PluginStore) -> Vec<String> {
+        store.releases(|_| true).into_iter()
+            .map(|mut release| {
+                release.files.iter_mut().for_each(|file| file.download_index = 0);
+                release.images.iter_mut().chain(&mut release.changelog).for_each(|asset| asset.download_index = 0);
+                format!("{:?}", release)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn validates_ids() {
+        assert!(is_valid_id("plugin-1.0.0"));
+        assert!(is_valid_id("plugin.zip"));
+        for invalid in &["", ".", "..", ".hidden", "a/b", "a\\b", "/abs"] {
+            assert!(!is_valid_id(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn rejects_unusable_releases() {
+        let file = |install_location| update_protocol::UpdateFile {
+            install_location, download_index: 0, size: 1, sha256: Some("0".repeat(64)),
+        };
+        let mut release = Release {
+            id: "a".to_owned(), plugin_name: "a".to_owned(), version: "1.0.0".to_owned(), beta: false,
+            skyline_version: None, files: vec![file(InstallLocation::AbsolutePath("sd:/a.nro".to_owned()))],
+            dependencies: vec![], title_ids: vec![], game_versions: None, name: None, description: None,
+            images: vec![], changelog: None,
+        };
+        assert!(files(&release).is_ok());
+
+        release.files.push(file(InstallLocation::Unknown));
+        assert!(files(&release).is_err());
+    }
+
+    #[test]
+    fn syncs_with_upstream() {
+        let root = tempfile::tempdir().unwrap();
+        let (upstream_dir, mirror_dir, staging) = (root.path().join("upstream"), root.path().join("mirror"), root.path().join("staging"));
+        write_plugin(&upstream_dir.join("a"));
+        fs::create_dir_all(mirror_dir.join("local")).unwrap();
+
+        let store = RwLock::new(PluginStore::load(&upstream_dir).unwrap());
+        let stats = Stats::load(&root.path().join("stats.json")).unwrap();
+        let metrics = Metrics::default();
+        let access = AccessControl::default();
+        let ctx = Context { store: &store, stats: &stats, metrics: &metrics, access: &access };
+        let limiter = Limiter::new(Default::default(), metrics.clone());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        crossbeam::scope(|scope| {
+            let ctx = &ctx;
+            scope.spawn(move |_| {
+                for socket in listener.incoming().take(3) {
+                    let socket = socket.unwrap();
+                    let conn = limiter.connect(&socket).unwrap();
+                    tcp::serve_request(ctx, &conn, socket);
+                }
+            });
+
+            let mut status = Status::default();
+            assert_eq!(sync(&address, &mirror_dir, &staging, &mut status).unwrap(), 1);
+            let mirror = PluginStore::load(&mirror_dir).unwrap();
+            assert_eq!(summary(&mirror), summary(&store.read().unwrap()));
+
+            assert_eq!(sync(&address, &mirror_dir, &staging, &mut status).unwrap(), 0);
+
+            fs::remove_dir_all(upstream_dir.join("a")).unwrap();
+            store.write().unwrap().reload("a");
+            assert_eq!(sync(&address, &mirror_dir, &staging, &mut status).unwrap(), 1);
+            assert!(!mirror_dir.join("a").exists());
+            assert!(mirror_dir.join("local").exists());
+        }).unwrap();
+    }
+}
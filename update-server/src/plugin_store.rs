@@ -0,0 +1,404 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use color_eyre::eyre;
+use notify::DebouncedEvent;
+use tracing::{info, warn};
+
+use semver::Version;
+use update_protocol::{Asset, Dependency, InstallLocation, UpdateFile, PluginMetadata, Release};
+
+use crate::hosted_plugins::{self, GameConstraints};
+use crate::source::{Blob, LocalDirectory, PluginSource};
+
+pub struct PluginFile {
+    pub install: InstallLocation,
+    pub data: Blob,
+    pub index: u64,
+}
+
+impl From<&PluginFile> for UpdateFile {
+    fn from(file: &PluginFile) -> Self {
+        UpdateFile {
+            size: file.data.len() as usize,
+            download_index: file.index,
+            install_location: file.install.clone(),
+            sha256: Some(file.data.sha256().to_owned()),
+        }
+    }
+}
+
+pub struct Plugin {
+    pub name: String,
+    pub plugin_version: Version,
+    pub files: Vec<PluginFile>,
+    pub metadata: PluginMetadata,
+    pub skyline_version: Version,
+    pub beta: bool,
+    pub dependencies: Vec<Dependency>,
+    pub game: GameConstraints,
+
+    /// Every download index owned by this plugin, including metadata assets
+    indices: Vec<u64>,
+}
+
+impl Plugin {
+    /// Whether the plugin is only offered on the beta channel, either because the `plugin.toml`
+    /// says so or because its version is a pre-release
+    pub fn is_beta(&self) -> bool {
+        self.beta || self.plugin_version.is_prerelease()
+    }
+}
+
+/// The set of plugins currently being served, keyed by the id of the release they were loaded
+/// from, such as the name of a folder or bundle in the plugins directory.
+///
+/// Every file is given a download index which stays the same for as long as the plugin
+/// providing it is loaded, so reloading one plugin never disturbs downloads of another.
+pub struct PluginStore {
+    source: Box<dyn PluginSource>,
+    plugins: BTreeMap<String, Plugin>,
+    files: HashMap<u64, StoredFile>,
+    next_index: u64,
+    reloads: u64,
+    reload_failures: u64,
+}
+
+struct StoredFile {
+    data: Blob,
+    /// Release the plugin owning the file was loaded from
+    release: String,
+}
+
+impl PluginStore {
+    /// Load every plugin in `dir`, logging (and skipping) any which fail to load
+    pub fn load(dir: &Path) -> eyre::Result<Self> {
+        Self::with_source(Box::new(LocalDirectory::new(dir)))
+    }
+
+    /// Load every plugin in a source, logging (and skipping) any which fail to load
+    pub fn with_source(source: Box<dyn PluginSource>) -> eyre::Result<Self> {
+        let mut store = PluginStore {
+            source,
+            plugins: BTreeMap::new(),
+            files: HashMap::new(),
+            next_index: 0,
+            reloads: 0,
+            reload_failures: 0,
+        };
+
+        for id in store.source.releases()? {
+            store.reload(&id);
+        }
+
+        Ok(store)
+    }
+
+    pub fn plugins(&self) -> impl Iterator<Item = &Plugin> {
+        self.plugins.values()
+    }
+
+    /// Directory to watch for changes to the plugins, if they're stored on disk
+    pub fn watch_path(&self) -> Option<&Path> {
+        self.source.watch_path()
+    }
+
+    pub fn file(&self, index: u64) -> Option<Blob> {
+        self.files.get(&index).map(|file| file.data.clone())
+    }
+
+    /// Number of plugins loaded successfully and number which failed to load, since startup
+    pub fn reload_counts(&self) -> (u64, u64) {
+        (self.reloads, self.reload_failures)
+    }
+
+    /// Total size in bytes of the files held in memory for each plugin, including metadata assets
+    pub fn memory_usage(&self) -> impl Iterator<Item = (&Plugin, u64)> {
+        self.plugins.values().map(move |plugin| {
+            let size = plugin.indices.iter()
+                .filter_map(|index| self.files.get(index))
+                .map(|file| file.data.memory_usage())
+                .sum();
+
+            (plugin, size)
+        })
+    }
+
+    /// Every release being served of plugins for which `filter` returns true, for mirrors to
+    /// replicate
+    pub fn releases(&self, filter: impl Fn(&Plugin) -> bool) -> Vec<Release> {
+        let asset = |index: u64| {
+            self.files.get(&index).map(|file| Asset {
+                download_index: index,
+                size: file.data.len() as usize,
+                sha256: file.data.sha256().to_owned(),
+            })
+        };
+
+        self.plugins.iter()
+            .filter(|(_, plugin)| filter(plugin))
+            .map(|(id, plugin)| {
+                let metadata = &plugin.metadata;
+                let changelog = Some(metadata.changelog_index)
+                    .filter(|index| plugin.indices.contains(index))
+                    .and_then(asset);
+
+                Release {
+                    id: id.clone(),
+                    plugin_name: plugin.name.clone(),
+                    version: plugin.plugin_version.to_string(),
+                    beta: plugin.beta,
+                    skyline_version: Some(plugin.skyline_version.to_string()),
+                    files: plugin.files.iter().map(UpdateFile::from).collect(),
+                    dependencies: plugin.dependencies.clone(),
+                    title_ids: plugin.game.title_ids.clone(),
+                    game_versions: plugin.game.versions.as_ref().map(ToString::to_string),
+                    name: metadata.name.clone(),
+                    description: metadata.description.clone(),
+                    images: (metadata.images_index..metadata.changelog_index).filter_map(asset).collect(),
+                    changelog,
+                }
+            })
+            .collect()
+    }
+
+    /// The plugin a download index belongs to
+    pub fn file_owner(&self, index: u64) -> Option<&Plugin> {
+        self.plugins.get(&self.files.get(&index)?.release)
+    }
+
+    /// Reload the plugin of a given release.
+    ///
+    /// If the release was removed the plugin stops being served. If the new version fails to
+    /// load the error is logged and the last version which loaded successfully is kept.
+    pub fn reload(&mut self, release: &str) {
+        match self.source.load(release) {
+            Ok(Some(plugin)) => {
+                self.remove(release);
+                self.reloads += 1;
+                let plugin = self.register(release, plugin);
+                info!(release, plugin = %plugin.name, version = %plugin.plugin_version, "loaded plugin");
+                self.plugins.insert(release.to_owned(), plugin);
+            }
+            Ok(None) => {
+                if self.remove(release) {
+                    info!(release, "plugin removed");
+                }
+            }
+            Err(e) => {
+                self.reload_failures += 1;
+                if self.plugins.contains_key(release) {
+                    warn!(release, "failed to reload plugin, keeping previous version: {}", e);
+                } else {
+                    warn!(release, "failed to load plugin: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Reload all plugins affected by a file watcher event
+    pub fn handle_event(&mut self, event: DebouncedEvent) {
+        let paths = match event {
+            DebouncedEvent::Create(path)
+            | DebouncedEvent::Write(path)
+            | DebouncedEvent::Chmod(path)
+            | DebouncedEvent::Remove(path) => vec![path],
+            DebouncedEvent::Rename(from, to) => vec![from, to],
+            DebouncedEvent::Rescan => {
+                info!("rescanning plugins");
+                return self.rescan()
+            }
+            DebouncedEvent::Error(err, Some(path)) => {
+                warn!(path = %path.display(), "file watch error: {}", err);
+                return
+            }
+            DebouncedEvent::Error(err, None) => {
+                warn!("file watch error: {}", err);
+                return
+            }
+            DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) => return,
+        };
+
+        let mut releases: Vec<String> = paths.iter().filter_map(|path| self.source.release_for(path)).collect();
+        releases.dedup();
+
+        for release in releases {
+            info!(release = %release, "change detected, reloading");
+            self.reload(&release);
+        }
+    }
+
+    /// Reload every plugin, including picking up releases which are no longer present
+    pub fn rescan(&mut self) {
+        let mut releases: Vec<String> = self.plugins.keys().cloned().collect();
+        match self.source.releases() {
+            Ok(ids) => releases.extend(ids),
+            Err(e) => warn!("failed to list releases: {}", e),
+        }
+        releases.sort();
+        releases.dedup();
+
+        for release in releases {
+            self.reload(&release);
+        }
+    }
+
+    fn register_file(&mut self, release: &str, data: Blob) -> u64 {
+        let index = self.next_index;
+        self.next_index += 1;
+        self.files.insert(index, StoredFile {
+            data,
+            release: release.to_owned(),
+        });
+        index
+    }
+
+    fn register(&mut self, release: &str, plugin: hosted_plugins::Plugin) -> Plugin {
+        let hosted_plugins::Plugin {
+            name, plugin_version, files, skyline_version, beta, metadata, dependencies, game
+        } = plugin;
+
+        let mut indices = vec![];
+
+        let files = files.into_iter()
+            .map(|(install, data)| {
+                let index = self.register_file(release, data.clone());
+                indices.push(index);
+                PluginFile { install, data, index }
+            })
+            .collect();
+
+        let hosted_plugins::Metadata {
+            name: meta_name, images, changelog, description
+        } = metadata;
+
+        let images = images.unwrap_or_default();
+        let image_count = images.len() as u64;
+        let metadata_files = images.into_iter().chain(changelog);
+
+        // metadata assets are given consecutive indices, images first then the changelog
+        let metadata_start = self.next_index;
+        for data in metadata_files {
+            indices.push(self.register_file(release, data));
+        }
+
+        let metadata = PluginMetadata {
+            name: meta_name,
+            description,
+            images_index: metadata_start,
+            image_count,
+            changelog_index: metadata_start + image_count,
+        };
+
+        Plugin {
+            name,
+            plugin_version,
+            skyline_version,
+            files,
+            metadata,
+            beta,
+            dependencies,
+            game,
+            indices,
+        }
+    }
+
+    fn remove(&mut self, release: &str) -> bool {
+        match self.plugins.remove(release) {
+            Some(plugin) => {
+                for index in &plugin.indices {
+                    self.files.remove(index);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::source::MemorySource;
+
+    fn write_plugin(dir: &Path, version: &str, data: &str) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("plugin.toml"), format!(r#"
+            version = "{}"
+            name = "{}"
+            files = [{{ install_location = "sd:/file.txt", filename = "file.txt" }}]
+        "#, version, dir.file_name().unwrap().to_str().unwrap())).unwrap();
+        fs::write(dir.join("file.txt"), data).unwrap();
+    }
+
+    fn version(store: &PluginStore, name: &str) -> Option<String> {
+        store.plugins().find(|plugin| plugin.name == name).map(|plugin| plugin.plugin_version.to_string())
+    }
+
+    #[test]
+    fn reloads_only_changed_plugin() {
+        let root = tempfile::tempdir().unwrap();
+        let (a, b) = (root.path().join("a"), root.path().join("b"));
+        write_plugin(&a, "1.0.0", "a");
+        write_plugin(&b, "1.0.0", "b");
+
+        let mut store = PluginStore::load(root.path()).unwrap();
+        let b_index = store.plugins().find(|plugin| plugin.name == "b").unwrap().files[0].index;
+
+        write_plugin(&a, "1.1.0", "a2");
+        store.handle_event(DebouncedEvent::Write(a.join("file.txt")));
+
+        assert_eq!(version(&store, "a").as_deref(), Some("1.1.0"));
+        assert_eq!(store.file(b_index).unwrap().read().unwrap(), b"b");
+    }
+
+    #[test]
+    fn keeps_last_good_version() {
+        let root = tempfile::tempdir().unwrap();
+        let a = root.path().join("a");
+        write_plugin(&a, "1.0.0", "a");
+
+        let mut store = PluginStore::load(root.path()).unwrap();
+        let index = store.plugins().next().unwrap().files[0].index;
+
+        fs::write(a.join("plugin.toml"), "version = ").unwrap();
+        store.handle_event(DebouncedEvent::Write(a.join("plugin.toml")));
+        assert_eq!(version(&store, "a").as_deref(), Some("1.0.0"));
+        assert_eq!(store.file(index).unwrap().read().unwrap(), b"a");
+
+        fs::remove_dir_all(&a).unwrap();
+        store.handle_event(DebouncedEvent::Remove(a.clone()));
+        assert_eq!(version(&store, "a"), None);
+        assert!(store.file(index).is_none());
+    }
+
+    #[test]
+    fn loads_from_source() {
+        let source = MemorySource::new();
+        let plugin = |version: &str| vec![
+            ("plugin.toml", format!(r#"
+                version = "{}"
+                name = "a"
+                files = [{{ install_location = "sd:/file.txt", filename = "file.txt" }}]
+            "#, version).into_bytes()),
+            ("file.txt", version.as_bytes().to_vec()),
+        ];
+        source.insert("a", plugin("1.0.0"));
+
+        let mut store = PluginStore::with_source(Box::new(source.clone())).unwrap();
+        assert_eq!(version(&store, "a").as_deref(), Some("1.0.0"));
+        assert!(store.watch_path().is_none());
+
+        source.insert("a", plugin("1.1.0"));
+        store.rescan();
+        let index = store.plugins().next().unwrap().files[0].index;
+        assert_eq!(store.file(index).unwrap().read().unwrap(), b"1.1.0");
+        assert_eq!(store.releases(|_| true)[0].id, "a");
+
+        source.remove("a");
+        store.rescan();
+        assert_eq!(version(&store, "a"), None);
+    }
+}
@@ -0,0 +1,559 @@
+//! Transport-independent handling of update protocol requests, shared by the raw TCP protocol
+//! and the HTTP API.
+
+use std::sync::RwLock;
+use std::io::prelude::*;
+
+use semver::Version;
+use tracing::{debug, info_span, warn};
+
+use crate::access_log::TARGET as ACCESS_LOG;
+use update_protocol::{Capability, GameInfo, ListResponse, Request, UpdateRequestOptions, UpdateResponse, UpdateManyResponse, ResponseCode, PROTOCOL_VERSION};
+
+use crate::access::AccessControl;
+use crate::metrics::Metrics;
+use crate::stats::Stats;
+use crate::plugin_store::{Plugin, PluginStore};
+use crate::source::Blob;
+
+/// Optional protocol features supported by the server
+const CAPABILITIES: &[Capability] = &[
+    Capability::ExtendedResponseCodes,
+    Capability::Framed,
+    Capability::UpdateMany,
+    Capability::List,
+];
+
+/// Everything needed to respond to a request
+pub struct Context<'a> {
+    pub store: &'a RwLock<PluginStore>,
+    pub stats: &'a Stats,
+    pub metrics: &'a Metrics,
+    pub access: &'a AccessControl,
+}
+
+/// A file ready to be sent to a client
+pub struct Download {
+    pub index: u64,
+    pub data: Blob,
+    pub plugin_name: String,
+}
+
+/// Why a file couldn't be downloaded
+#[derive(Debug, PartialEq)]
+pub enum DownloadError {
+    NotFound,
+    /// The file belongs to a release which the client's token doesn't grant access to
+    Unauthorized,
+}
+
+/// Adapt a response to what the client declared it supports, and advertise the server's
+/// protocol version and capabilities
+fn negotiate(mut response: UpdateResponse, options: &UpdateRequestOptions) -> UpdateResponse {
+    if !options.supports(Capability::ExtendedResponseCodes) {
+        response.code = response.code.legacy();
+    }
+
+    response.protocol_version = PROTOCOL_VERSION;
+    response.capabilities = CAPABILITIES.to_vec();
+    response
+}
+
+/// Find the newest version of a plugin, only including beta versions if requested, releases
+/// for which `allowed` returns true and, if the client's game is known, builds compatible with it
+fn find_plugin<'a>(
+    store: &'a PluginStore,
+    plugin_name: &str,
+    beta: bool,
+    game: Option<&GameInfo>,
+    allowed: impl Fn(&Plugin) -> bool,
+) -> Option<&'a Plugin> {
+    store.plugins()
+        .filter(|plugin| plugin.name == plugin_name && (beta || !plugin.is_beta()))
+        .filter(|plugin| game.map(|game| plugin.game.supports(game)).unwrap_or(true))
+        .filter(|plugin| allowed(plugin))
+        .max_by_key(|plugin| &plugin.plugin_version)
+}
+
+/// The release channel a client follows, for logging
+fn channel(beta: bool) -> &'static str {
+    if beta { "beta" } else { "stable" }
+}
+
+/// The type of a request and the plugin it concerns, if any, for logging
+fn describe(request: &Option<Request>) -> (&'static str, Option<&str>) {
+    match request {
+        Some(Request::Update { plugin_name, .. }) => ("Update", Some(plugin_name)),
+        Some(Request::UpdateMany { .. }) => ("UpdateMany", None),
+        Some(Request::Metadata { plugin_name, .. }) => ("Metadata", Some(plugin_name)),
+        Some(Request::List {}) => ("List", None),
+        Some(Request::Stats { plugin_name }) => ("Stats", plugin_name.as_deref()),
+        _ => ("Invalid", None),
+    }
+}
+
+impl Context<'_> {
+    /// Handle a request (`None` if it couldn't be parsed), returning the JSON-encoded response,
+    /// or `None` if there is nothing to respond with (for example, metadata for a plugin which
+    /// doesn't exist)
+    pub fn handle(&self, request: Option<Request>) -> Option<String> {
+        let (kind, plugin) = describe(&request);
+        let _span = info_span!("request", kind, plugin).entered();
+        let store = self.store.read().unwrap();
+
+        let response = match request {
+            Some(Request::Update { plugin_name, plugin_version, beta, options, auth_token }) => {
+                let options = options.unwrap_or_default();
+                let response = self.check_update(&store, plugin_name, &plugin_version, beta, &options, auth_token.as_deref());
+
+                self.metrics.request("Update", format!("{:?}", response.code));
+                serde_json::to_string(&response)
+            }
+            Some(Request::UpdateMany { plugins, options, auth_token }) => {
+                let options = options.unwrap_or_default();
+                let responses = plugins.into_iter()
+                    .map(|query| {
+                        let _span = info_span!("query", plugin = %query.plugin_name).entered();
+                        let response = self.check_update(&store, query.plugin_name, &query.plugin_version, query.beta, &options, auth_token.as_deref());
+                        self.metrics.request("UpdateMany", format!("{:?}", response.code));
+                        response
+                    })
+                    .collect();
+
+                serde_json::to_string(&UpdateManyResponse { responses })
+            }
+            Some(Request::Metadata { plugin_name, beta, auth_token, game }) => {
+                let beta = beta.unwrap_or(false);
+                let allowed = |plugin: &Plugin| self.access.allows(plugin, auth_token.as_deref());
+
+                let channel = channel(beta);
+
+                if let Some(plugin) = find_plugin(&store, &plugin_name, beta, game.as_ref(), allowed) {
+                    debug!(target: ACCESS_LOG, channel, code = "Ok", version = %plugin.plugin_version, "sent metadata");
+                    self.metrics.request("Metadata", "Ok");
+                    serde_json::to_string(&plugin.metadata)
+                } else if find_plugin(&store, &plugin_name, beta, None, allowed).is_some() {
+                    // same precedence as update checks
+                    debug!(target: ACCESS_LOG, channel, code = "IncompatibleGame", "refused metadata");
+                    self.metrics.request("Metadata", "IncompatibleGame");
+                    return None
+                } else if find_plugin(&store, &plugin_name, beta, None, |_| true).is_some() {
+                    debug!(target: ACCESS_LOG, channel, code = "Unauthorized", "refused metadata");
+                    self.metrics.request("Metadata", "Unauthorized");
+                    return None
+                } else {
+                    debug!(target: ACCESS_LOG, channel, code = "PluginNotFound", "refused metadata");
+                    self.metrics.request("Metadata", "PluginNotFound");
+                    return None
+                }
+            }
+            Some(Request::List {}) => {
+                // mirrors only replicate public releases
+                debug!(target: ACCESS_LOG, code = "Ok", "listed releases");
+                self.metrics.request("List", "Ok");
+                let releases = store.releases(|plugin| self.access.allows(plugin, None));
+                serde_json::to_string(&ListResponse { releases })
+            }
+            Some(Request::Stats { plugin_name }) => {
+                debug!(target: ACCESS_LOG, code = "Ok", "sent statistics");
+                self.metrics.request("Stats", "Ok");
+                // statistics reveal which versions of a plugin exist, so only those of plugins
+                // which are entirely public are sent
+                let mut stats = self.stats.snapshot(plugin_name.as_deref());
+                stats.plugins.retain(|name, _| {
+                    let mut plugins = store.plugins().filter(|plugin| &plugin.name == name).peekable();
+                    plugins.peek().is_some() && plugins.all(|plugin| self.access.allows(plugin, None))
+                });
+                serde_json::to_string(&stats)
+            }
+            _ => {
+                debug!(target: ACCESS_LOG, code = "InvalidRequest", "invalid request");
+                self.metrics.request("Invalid", "InvalidRequest");
+                serde_json::to_string(&UpdateResponse::invalid_request())
+            }
+        };
+
+        Some(response.unwrap())
+    }
+
+    /// Check a single plugin for an update
+    fn check_update(
+        &self,
+        store: &PluginStore,
+        plugin_name: String,
+        plugin_version: &str,
+        beta: Option<bool>,
+        options: &UpdateRequestOptions,
+        auth_token: Option<&str>,
+    ) -> UpdateResponse {
+        let beta = beta.unwrap_or(false);
+        let allowed = |plugin: &Plugin| self.access.allows(plugin, auth_token);
+        let plugin = find_plugin(store, &plugin_name, beta, options.game.as_ref(), allowed);
+
+        let response = if let Some(plugin) = plugin {
+            // normalize versions such as `v1.2` or `git describe` output before comparing
+            let current_version = plugin_version.parse::<update_protocol::version::Version>().ok()
+                .and_then(|version| version.to_string().parse::<Version>().ok());
+            self.stats.update_check(&plugin.name, current_version.as_ref().map(ToString::to_string).as_deref());
+
+            if let Some(current_version) = current_version {
+                if current_version < plugin.plugin_version {
+                    self.stats.update_offered(&plugin.name, &plugin.plugin_version.to_string());
+                    UpdateResponse {
+                        code: ResponseCode::Update,
+                        update_plugin: true,
+                        update_skyline: false,
+                        plugin_name,
+                        new_plugin_version: plugin.plugin_version.to_string(),
+                        new_skyline_version: None,
+                        required_files: plugin.files.iter().map(|file| file.into()).collect(),
+                        dependencies: plugin.dependencies.clone(),
+                        ..Default::default()
+                    }
+                } else {
+                    UpdateResponse::no_update()
+                }
+            } else {
+                self.stats.failure(&plugin.name);
+                UpdateResponse::invalid_request()
+            }
+        } else if find_plugin(store, &plugin_name, beta, None, allowed).is_some() {
+            UpdateResponse {
+                code: ResponseCode::IncompatibleGame,
+                plugin_name,
+                ..Default::default()
+            }
+        } else if find_plugin(store, &plugin_name, beta, None, |_| true).is_some() {
+            UpdateResponse {
+                code: ResponseCode::Unauthorized,
+                plugin_name,
+                ..Default::default()
+            }
+        } else {
+            UpdateResponse::plugin_not_found()
+        };
+
+        let response = negotiate(response, options);
+        debug!(
+            target: ACCESS_LOG,
+            client_version = plugin_version,
+            channel = channel(beta),
+            code = ?response.code,
+            version = plugin.map(|plugin| plugin.plugin_version.to_string()).as_deref(),
+            "checked for update",
+        );
+
+        response
+    }
+
+    /// Look up a file by its download index, checking the client's token grants access to it
+    pub fn download(&self, index: u64, auth_token: Option<&str>) -> Result<Download, DownloadError> {
+        let store = self.store.read().unwrap();
+
+        let (data, owner) = store.file(index)
+            .zip(store.file_owner(index))
+            .ok_or(DownloadError::NotFound)
+            .inspect_err(|_| debug!(target: ACCESS_LOG, kind = "Download", file = index, code = "NotFound", "refused download"))?;
+
+        if !self.access.allows(owner, auth_token) {
+            debug!(target: ACCESS_LOG, kind = "Download", file = index, plugin = %owner.name, code = "Unauthorized", "refused download");
+            return Err(DownloadError::Unauthorized)
+        }
+
+        Ok(Download {
+            index,
+            data,
+            plugin_name: owner.name.clone(),
+        })
+    }
+
+    /// Send (part of) a file to a client, recording the transfer in the statistics and metrics
+    pub fn send(&self, socket: &mut impl Write, download: &Download, range: (u64, u64)) -> bool {
+        let _guard = self.metrics.download_started();
+        let (start, end) = range;
+        let (sent, completed) = match download.data.open(start) {
+            Ok(data) => send_file(socket, data.take(end - start), end - start),
+            Err(e) => {
+                warn!(file = download.index, "failed to read file: {}", e);
+                (0, false)
+            }
+        };
+
+        debug!(
+            target: ACCESS_LOG,
+            kind = "Download",
+            file = download.index,
+            plugin = %download.plugin_name,
+            code = "Ok",
+            bytes = sent,
+            completed,
+            "sent file",
+        );
+        self.stats.download(&download.plugin_name, sent, completed);
+        self.metrics.bytes_served(sent);
+
+        completed
+    }
+}
+
+/// Send `len` bytes of a file to a client, returning the number of bytes sent and whether the
+/// transfer completed
+fn send_file(socket: &mut impl Write, mut data: impl Read, len: u64) -> (u64, bool) {
+    let mut sent = 0;
+    let mut chunk = vec![0; 0x10000];
+    loop {
+        let read = match data.read(&mut chunk) {
+            Ok(0) => return (sent, sent == len),
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!("failed to read file: {}", e);
+                return (sent, false)
+            }
+        };
+
+        if socket.write_all(&chunk[..read]).is_err() {
+            return (sent, false)
+        }
+        sent += read as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_capabilities() {
+        let legacy = UpdateRequestOptions::default();
+        let current = UpdateRequestOptions::new(vec![Capability::ExtendedResponseCodes]);
+
+        let response = UpdateResponse { code: ResponseCode::Unknown, ..Default::default() };
+        assert!(matches!(negotiate(response.clone(), &legacy).code, ResponseCode::InvalidRequest));
+
+        let response = negotiate(response, &current);
+        assert!(matches!(response.code, ResponseCode::Unknown));
+        assert_eq!(response.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(response.capabilities, CAPABILITIES);
+    }
+
+    /// Run a test against a store containing the given (folder, plugin.toml) plugins
+    fn with_plugins(plugins: &[(&str, &str)], test: impl FnOnce(&Context)) {
+        with_access(plugins, "", test)
+    }
+
+    /// Run a test against a store containing the given plugins, protected by the access rules
+    /// in an `update-server.toml`
+    fn with_access(plugins: &[(&str, &str)], config: &str, test: impl FnOnce(&Context)) {
+        let dir = tempfile::tempdir().unwrap();
+        for (folder, toml) in plugins {
+            let plugin = dir.path().join("plugins").join(folder);
+            std::fs::create_dir_all(&plugin).unwrap();
+            std::fs::write(plugin.join("plugin.toml"), toml).unwrap();
+        }
+
+        let store = RwLock::new(PluginStore::load(&dir.path().join("plugins")).unwrap());
+        let stats = Stats::load(&dir.path().join("stats.json")).unwrap();
+        let metrics = Metrics::default();
+        let access = AccessControl::new(toml::from_str::<crate::config::Config>(config).unwrap().access_rules);
+        test(&Context { store: &store, stats: &stats, metrics: &metrics, access: &access });
+    }
+
+    #[test]
+    fn update_many() {
+        with_plugins(&[("a", r#"
+            version = "1.1.0"
+            name = "a"
+            files = []
+        "#)], |ctx| {
+            let query = |plugin_name: &str, plugin_version: &str| update_protocol::UpdateQuery {
+                plugin_name: plugin_name.to_owned(),
+                plugin_version: plugin_version.to_owned(),
+                beta: None,
+            };
+            let response = ctx.handle(Some(Request::UpdateMany {
+                plugins: vec![query("a", "1.0.0"), query("b", "1.0.0"), query("a", "1.1.0")],
+                options: None,
+                auth_token: None,
+            })).unwrap();
+
+            let codes: Vec<_> = serde_json::from_str::<UpdateManyResponse>(&response).unwrap()
+                .responses
+                .into_iter()
+                .map(|response| format!("{:?}", response.code))
+                .collect();
+            assert_eq!(codes, ["Update", "PluginNotFound", "NoUpdate"]);
+        });
+    }
+
+    #[test]
+    fn normalizes_versions() {
+        with_plugins(&[
+            ("stable", r#"
+                version = "1.1.0"
+                name = "a"
+                files = []
+            "#),
+            ("rc", r#"
+                version = "1.2.0-rc.1"
+                name = "a"
+                files = []
+            "#),
+        ], |ctx| {
+            let check = |plugin_version: &str, beta: bool| {
+                let store = ctx.store.read().unwrap();
+                let options = UpdateRequestOptions::default();
+                let response = ctx.check_update(&store, "a".to_owned(), plugin_version, Some(beta), &options, None);
+                format!("{:?} {}", response.code, response.new_plugin_version)
+            };
+
+            assert_eq!(check("v1.0", false), "Update 1.1.0");
+            assert_eq!(check("1.1.0-beta.1", false), "Update 1.1.0");
+            assert_eq!(check("v1.1.0-3-gabcdef1", false), "NoUpdate ");
+            assert_eq!(check("v1.1.0-3-gabcdef1", true), "Update 1.2.0-rc.1");
+            assert_eq!(check("1.2.0-rc.1+build.5", true), "NoUpdate ");
+            assert_eq!(check("latest", false), "InvalidRequest ");
+        });
+    }
+
+    #[test]
+    fn game_compatibility() {
+        with_plugins(&[
+            ("old", r#"
+                version = "1.0.0"
+                name = "a"
+                files = []
+                game = { title_ids = ["01006A800016E000"], versions = "=13.0.1" }
+                metadata = { name = "Old" }
+            "#),
+            ("new", r#"
+                version = "1.1.0"
+                name = "a"
+                files = []
+                game = { title_ids = ["01006A800016E000"], versions = ">=13.0.2" }
+                metadata = { name = "New" }
+            "#),
+        ], |ctx| {
+            let check = |game_version: &str, capabilities: Vec<Capability>| {
+                let game = GameInfo { title_id: 0x01006A800016E000, version: game_version.to_owned() };
+                let store = ctx.store.read().unwrap();
+                let options = UpdateRequestOptions::new(capabilities).game(Some(game));
+                let response = ctx.check_update(&store, "a".to_owned(), "0.9.0", None, &options, None);
+                format!("{:?} {}", response.code, response.new_plugin_version)
+            };
+
+            assert_eq!(check("13.0.1", vec![]), "Update 1.0.0");
+            assert_eq!(check("13.0.2", vec![]), "Update 1.1.0");
+            assert_eq!(check("13.1", vec![]), "Update 1.1.0");
+            assert_eq!(check("12.0.0", vec![Capability::ExtendedResponseCodes]), "IncompatibleGame ");
+            assert_eq!(check("12.0.0", vec![]), "NoUpdate ");
+
+            let metadata = |game_version: Option<&str>| {
+                let game = game_version.map(|version| GameInfo { title_id: 0x01006A800016E000, version: version.to_owned() });
+                let request = Request::Metadata { plugin_name: "a".to_owned(), beta: None, auth_token: None, game };
+                ctx.handle(Some(request))
+                    .map(|response| serde_json::from_str::<update_protocol::PluginMetadata>(&response).unwrap().name.unwrap())
+            };
+
+            assert_eq!(metadata(Some("13.0.1")).as_deref(), Some("Old"));
+            assert_eq!(metadata(Some("13.0.2")).as_deref(), Some("New"));
+            assert_eq!(metadata(None).as_deref(), Some("New"));
+            assert_eq!(metadata(Some("12.0.0")), None);
+        });
+    }
+
+    #[test]
+    fn access_tokens() {
+        with_access(&[
+            ("stable", r#"
+                version = "1.0.0"
+                name = "a"
+                files = [{ install_location = "sd:/a.nro", filename = "plugin.toml" }]
+            "#),
+            ("early", r#"
+                version = "1.1.0-beta.1"
+                name = "a"
+                files = [{ install_location = "sd:/a.nro", filename = "plugin.toml" }]
+            "#),
+            ("private", r#"
+                version = "1.0.0"
+                name = "b"
+                files = [{ install_location = "sd:/b.nro", filename = "plugin.toml" }]
+                game = { title_ids = ["01006A800016E000"], versions = ">=13.0.0" }
+            "#),
+            ("public", r#"
+                version = "1.0.0"
+                name = "c"
+                files = [{ install_location = "sd:/c.nro", filename = "plugin.toml" }]
+            "#),
+        ], r#"
+            [[access]]
+            plugins = ["a"]
+            channel = "beta"
+            tokens = ["tester"]
+
+            [[access]]
+            plugins = ["b"]
+            tokens = ["friend"]
+        "#, |ctx| {
+            let check = |plugin_name: &str, beta: bool, token: Option<&str>, capabilities: Vec<Capability>| {
+                let store = ctx.store.read().unwrap();
+                let options = UpdateRequestOptions::new(capabilities);
+                let response = ctx.check_update(&store, plugin_name.to_owned(), "0.9.0", Some(beta), &options, token);
+                format!("{:?} {}", response.code, response.new_plugin_version)
+            };
+            let extended = || vec![Capability::ExtendedResponseCodes];
+
+            assert_eq!(check("a", false, None, extended()), "Update 1.0.0");
+            assert_eq!(check("a", true, None, extended()), "Update 1.0.0");
+            assert_eq!(check("a", true, Some("tester"), extended()), "Update 1.1.0-beta.1");
+            assert_eq!(check("b", false, None, extended()), "Unauthorized ");
+            assert_eq!(check("b", false, Some("tester"), extended()), "Unauthorized ");
+            assert_eq!(check("b", false, None, vec![]), "PluginNotFound ");
+            assert_eq!(check("b", false, Some("friend"), extended()), "Update 1.0.0");
+            assert_eq!(check("c", false, None, extended()), "Update 1.0.0");
+
+            let metadata = |token: Option<&str>, game: Option<GameInfo>| ctx.handle(Some(Request::Metadata {
+                plugin_name: "b".to_owned(),
+                beta: None,
+                auth_token: token.map(str::to_owned),
+                game,
+            }));
+            assert!(metadata(None, None).is_none());
+            assert!(metadata(Some("friend"), None).is_some());
+
+            // a private plugin without a build for the game is reported the same way by both
+            let old_game = || Some(GameInfo { title_id: 0x01006A800016E000, version: "12.0.0".to_owned() });
+            let check_game = |token: Option<&str>| {
+                let store = ctx.store.read().unwrap();
+                let options = UpdateRequestOptions::new(extended()).game(old_game());
+                format!("{:?}", ctx.check_update(&store, "b".to_owned(), "0.9.0", None, &options, token).code)
+            };
+            assert_eq!(check_game(None), "Unauthorized");
+            assert_eq!(check_game(Some("friend")), "IncompatibleGame");
+            assert!(metadata(None, old_game()).is_none());
+            assert!(metadata(Some("friend"), old_game()).is_none());
+
+            let metrics = ctx.metrics.render(&ctx.store.read().unwrap());
+            assert!(metrics.contains("{request=\"Metadata\",code=\"Unauthorized\"} 2\n"), "{}", metrics);
+            assert!(metrics.contains("{request=\"Metadata\",code=\"IncompatibleGame\"} 1\n"), "{}", metrics);
+
+            let index = ctx.store.read().unwrap().plugins()
+                .find(|plugin| plugin.name == "b")
+                .unwrap()
+                .files[0].index;
+            assert_eq!(ctx.download(index, None).err(), Some(DownloadError::Unauthorized));
+            assert_eq!(ctx.download(index, Some("friend")).unwrap().plugin_name, "b");
+            assert_eq!(ctx.download(u64::MAX, Some("friend")).err(), Some(DownloadError::NotFound));
+
+            let list: ListResponse = serde_json::from_str(&ctx.handle(Some(Request::List {})).unwrap()).unwrap();
+            let mut ids: Vec<_> = list.releases.into_iter().map(|release| release.id).collect();
+            ids.sort();
+            assert_eq!(ids, ["public", "stable"]);
+
+            let stats = ctx.handle(Some(Request::Stats { plugin_name: None })).unwrap();
+            let stats: update_protocol::StatsResponse = serde_json::from_str(&stats).unwrap();
+            assert_eq!(stats.plugins.keys().collect::<Vec<_>>(), ["c"]);
+        });
+    }
+}
@@ -0,0 +1,545 @@
+//! An embeddable update server.
+//!
+//! [`Server`] configures a server, [`Server::bind`] binds its listeners (so ephemeral ports can
+//! be looked up before it starts) and [`BoundServer::run`] serves clients until shut down through
+//! a [`ServerHandle`], a signal or the admin socket.
+
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{self, eyre};
+use notify::{watcher, RecursiveMode, Watcher};
+use tracing::{info, warn};
+
+use crate::access::AccessControl;
+use crate::admin::{self, Control};
+use crate::config::{AccessRule, Config, LimitsConfig, TlsConfig, DEFAULT_DRAIN_TIMEOUT};
+use crate::limits::Limiter;
+use crate::metrics::Metrics;
+use crate::plugin_store::PluginStore;
+use crate::requests::Context;
+use crate::source::PluginSource;
+use crate::stats::Stats;
+use crate::{http, import, logging, mirror, tcp, tls};
+
+/// Default request port, downloads are served from the port after it
+pub const DEFAULT_PORT: u16 = 45000;
+
+/// Default path of the statistics file, relative to the working directory
+pub const STATS_PATH: &str = "stats.json";
+
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long file changes in the plugins directory are batched up before reloading
+const WATCH_DELAY: Duration = Duration::from_secs(10);
+
+/// Configuration of an update server, see [`Server::bind`]
+pub struct Server {
+    plugins_dir: PathBuf,
+    source: Option<Box<dyn PluginSource>>,
+    stats_path: PathBuf,
+    request_address: SocketAddr,
+    download_address: Option<SocketAddr>,
+    tls_address: Option<SocketAddr>,
+    tls_download_address: Option<SocketAddr>,
+    config: Config,
+}
+
+/// A server with its listeners bound, ready to run
+pub struct BoundServer {
+    config: Config,
+    plugins_dir: PathBuf,
+    watch_path: Option<PathBuf>,
+    store: RwLock<PluginStore>,
+    stats: Stats,
+    metrics: Metrics,
+    access: AccessControl,
+    limiter: Limiter,
+    control: Arc<Control>,
+    request_address: SocketAddr,
+    download_address: SocketAddr,
+    listeners: Listeners,
+}
+
+/// Controls a running server from another thread
+#[derive(Clone)]
+pub struct ServerHandle {
+    control: Arc<Control>,
+}
+
+struct TlsListeners {
+    config: Arc<rustls::ServerConfig>,
+    main_port: TcpListener,
+    download_port: TcpListener,
+    https_port: Option<TcpListener>,
+}
+
+struct Listeners {
+    main_port: TcpListener,
+    download_port: TcpListener,
+    http_port: Option<TcpListener>,
+    metrics_port: Option<TcpListener>,
+    admin_port: Option<TcpListener>,
+    tls: Option<TlsListeners>,
+}
+
+/// Bind a listener which is polled from the main loop
+fn listen(addr: impl ToSocketAddrs) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Accepted sockets from non-blocking listeners are blocking, but shouldn't block forever
+fn with_timeout(socket: TcpStream) -> TcpStream {
+    let _ = socket.set_read_timeout(Some(CLIENT_TIMEOUT));
+    socket
+}
+
+/// The address with the port after `address`'s, or any free port if its port is 0
+fn next_port(address: SocketAddr) -> eyre::Result<SocketAddr> {
+    let port = match address.port() {
+        0 => 0,
+        port => port.checked_add(1).ok_or_else(|| eyre!("there's no port after {} to serve downloads on", address))?,
+    };
+
+    Ok(SocketAddr::new(address.ip(), port))
+}
+
+/// Wait for open connections to finish, closing any still open after `timeout`
+fn drain(limiter: &Limiter, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    let mut open = limiter.open_connections();
+    if open > 0 {
+        info!(open, "waiting for connections to finish");
+    }
+
+    while open > 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+        open = limiter.open_connections();
+    }
+
+    if open > 0 {
+        warn!(open, "timed out waiting for connections to finish, closing them");
+        limiter.close_all();
+    }
+}
+
+impl Server {
+    /// A server for the plugins in `plugins_dir`, listening on the default ports of every
+    /// interface
+    pub fn new(plugins_dir: impl Into<PathBuf>) -> Self {
+        Self::with_config(plugins_dir, Config::default())
+    }
+
+    /// A server configured as by `update-server.toml`
+    pub fn with_config(plugins_dir: impl Into<PathBuf>, config: Config) -> Self {
+        Self {
+            plugins_dir: plugins_dir.into(),
+            source: None,
+            stats_path: PathBuf::from(STATS_PATH),
+            request_address: SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT)),
+            download_address: None,
+            tls_address: None,
+            tls_download_address: None,
+            config,
+        }
+    }
+
+    /// Serve plugins from a source other than the plugins directory, such as a
+    /// [`BlobStore`](crate::source::BlobStore). Mirroring and importing releases aren't supported
+    /// with other sources, as they write releases to the plugins directory.
+    pub fn plugin_source(mut self, source: impl PluginSource + 'static) -> Self {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    /// Address to accept requests on, port 0 picking any free port
+    pub fn request_address(mut self, address: SocketAddr) -> Self {
+        self.request_address = address;
+        self
+    }
+
+    /// Address to serve downloads on, defaults to the port after the request port (or any free
+    /// port if the request port is 0)
+    pub fn download_address(mut self, address: SocketAddr) -> Self {
+        self.download_address = Some(address);
+        self
+    }
+
+    /// Serve the HTTP API on an address
+    pub fn http_address(mut self, address: SocketAddr) -> Self {
+        self.config.http_address = Some(address);
+        self
+    }
+
+    /// Serve Prometheus metrics on an address
+    pub fn metrics_address(mut self, address: SocketAddr) -> Self {
+        self.config.metrics_address = Some(address);
+        self
+    }
+
+    /// Accept admin commands on an address, which must be a loopback address
+    pub fn admin_address(mut self, address: SocketAddr) -> Self {
+        self.config.admin_address = Some(address);
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.config.tls = Some(tls);
+        self
+    }
+
+    /// Address to accept TLS requests on, defaults to the configured TLS port on the IP of the
+    /// request address
+    pub fn tls_address(mut self, address: SocketAddr) -> Self {
+        self.tls_address = Some(address);
+        self
+    }
+
+    /// Address to serve TLS downloads on, defaults to the port after the TLS request port (or
+    /// any free port if the TLS request port is 0)
+    pub fn tls_download_address(mut self, address: SocketAddr) -> Self {
+        self.tls_download_address = Some(address);
+        self
+    }
+
+    /// Require access tokens for some plugins, see [`AccessRule`]
+    pub fn access_rules(mut self, rules: Vec<AccessRule>) -> Self {
+        self.config.access_rules = rules;
+        self
+    }
+
+    pub fn limits(mut self, limits: LimitsConfig) -> Self {
+        self.config.limits = limits;
+        self
+    }
+
+    /// How long to wait for open connections to finish when shutting down
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.config.drain_timeout = Some(timeout.as_secs());
+        self
+    }
+
+    /// Where to load and save statistics, defaults to [`STATS_PATH`]
+    pub fn stats_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.stats_path = path.into();
+        self
+    }
+
+    /// Load the plugins and bind every listener
+    pub fn bind(self) -> eyre::Result<BoundServer> {
+        let Server {
+            plugins_dir, source, stats_path, request_address, download_address, tls_address, tls_download_address, mut config
+        } = self;
+
+        if let Some(address) = config.admin_address.filter(|address| !address.ip().is_loopback()) {
+            eyre::bail!("the admin socket isn't authenticated, so it can only listen on a loopback address, not {}", address)
+        }
+
+        let store = match source {
+            Some(_) if config.mirror.is_some() || !config.imports.is_empty() => {
+                eyre::bail!("mirroring and importing releases need plugins to be served from the plugins directory")
+            }
+            Some(source) => PluginStore::with_source(source)?,
+            None => {
+                if !plugins_dir.exists() {
+                    fs::create_dir(&plugins_dir)?;
+                }
+                PluginStore::load(&plugins_dir)?
+            }
+        };
+
+        let download_address = match download_address {
+            Some(address) => address,
+            None => next_port(request_address)?,
+        };
+
+        let main_port = listen(request_address)?;
+        let download_port = listen(download_address)?;
+        let tls = match &config.tls {
+            Some(tls) => {
+                let tls_address = tls_address.unwrap_or_else(|| SocketAddr::new(request_address.ip(), tls.port));
+                let tls_download_address = match tls_download_address {
+                    Some(address) => address,
+                    None => next_port(tls_address)?,
+                };
+
+                Some(TlsListeners {
+                    config: tls::server_config(&tls.cert, &tls.key)?,
+                    main_port: listen(tls_address)?,
+                    download_port: listen(tls_download_address)?,
+                    https_port: tls.https_address.map(listen).transpose()?,
+                })
+            }
+            None => None,
+        };
+
+        let listeners = Listeners {
+            http_port: config.http_address.map(listen).transpose()?,
+            metrics_port: config.metrics_address.map(listen).transpose()?,
+            admin_port: config.admin_address.map(listen).transpose()?,
+            main_port,
+            download_port,
+            tls,
+        };
+
+        let metrics = Metrics::default();
+        Ok(BoundServer {
+            request_address: listeners.main_port.local_addr()?,
+            download_address: listeners.download_port.local_addr()?,
+            watch_path: store.watch_path().map(Path::to_owned),
+            store: RwLock::new(store),
+            stats: Stats::load(&stats_path)?,
+            access: AccessControl::new(std::mem::take(&mut config.access_rules)),
+            limiter: Limiter::new(std::mem::take(&mut config.limits), metrics.clone()),
+            metrics,
+            control: Arc::default(),
+            plugins_dir,
+            listeners,
+            config,
+        })
+    }
+}
+
+impl BoundServer {
+    /// Address requests are accepted on
+    pub fn request_address(&self) -> SocketAddr {
+        self.request_address
+    }
+
+    /// Address downloads are served on
+    pub fn download_address(&self) -> SocketAddr {
+        self.download_address
+    }
+
+    /// Address TLS requests are accepted on, if enabled
+    pub fn tls_address(&self) -> Option<SocketAddr> {
+        self.listeners.tls.as_ref().and_then(|tls| tls.main_port.local_addr().ok())
+    }
+
+    /// Address TLS downloads are served on, if enabled
+    pub fn tls_download_address(&self) -> Option<SocketAddr> {
+        self.listeners.tls.as_ref().and_then(|tls| tls.download_port.local_addr().ok())
+    }
+
+    /// Address the HTTP API is served on, if enabled
+    pub fn http_address(&self) -> Option<SocketAddr> {
+        self.listeners.http_port.as_ref().and_then(|port| port.local_addr().ok())
+    }
+
+    /// Address metrics are served on, if enabled
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        self.listeners.metrics_port.as_ref().and_then(|port| port.local_addr().ok())
+    }
+
+    /// Address admin commands are accepted on, if enabled
+    pub fn admin_address(&self) -> Option<SocketAddr> {
+        self.listeners.admin_port.as_ref().and_then(|port| port.local_addr().ok())
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle { control: Arc::clone(&self.control) }
+    }
+
+    /// Run the server on a new thread
+    pub fn spawn(self) -> thread::JoinHandle<eyre::Result<()>> {
+        thread::spawn(move || self.run())
+    }
+
+    /// Serve clients until shut down, then wait for open connections to finish, up to the drain
+    /// timeout
+    pub fn run(self) -> eyre::Result<()> {
+        let BoundServer { config, plugins_dir, watch_path, store, stats, metrics, access, limiter, control, listeners, .. } = self;
+        let plugins_dir: &Path = &plugins_dir;
+
+        let (tx, rx) = channel();
+        let mut watcher = watcher(tx, WATCH_DELAY)?;
+        if let Some(path) = &watch_path {
+            watcher.watch(path, RecursiveMode::Recursive)?;
+        }
+
+        let ctx = Context {
+            store: &store,
+            stats: &stats,
+            metrics: &metrics,
+            access: &access,
+        };
+
+        let status = || format!(
+            "{} plugin(s) loaded, {} connection(s) open",
+            store.read().unwrap().plugins().count(),
+            limiter.open_connections(),
+        );
+
+        // outlives the listeners, which are closed before draining
+        let tls_config = listeners.tls.as_ref().map(|tls| Arc::clone(&tls.config));
+
+        crossbeam::scope(|scope| {
+            let ctx = &ctx;
+            let control = &*control;
+            let status = &status;
+            let limiter = &limiter;
+
+            if let Some(mirror) = &config.mirror {
+                info!(upstream = %mirror.upstream, interval = mirror.interval, "mirroring");
+                scope.spawn(move |_| mirror::run(mirror, plugins_dir, control));
+            }
+
+            for feed in &config.imports {
+                info!(plugin = %feed.name, feed = %feed.feed, interval = feed.interval, "importing releases");
+                scope.spawn(move |_| import::run(feed, plugins_dir, control));
+            }
+
+            while !control.shutdown_requested() {
+                while let Ok(event) = rx.try_recv() {
+                    store.write().unwrap().handle_event(event);
+                }
+
+                if control.take_reload() {
+                    info!("reloading plugins");
+                    store.write().unwrap().rescan();
+                }
+
+                stats.save_if_needed();
+
+                while let Ok((socket, addr)) = listeners.main_port.accept() {
+                    if let Some(conn) = limiter.connect(&socket) {
+                        let span = logging::connection_span("tcp", addr);
+                        scope.spawn(move |_| span.in_scope(|| tcp::serve_request(ctx, &conn, with_timeout(socket))));
+                    }
+                }
+
+                while let Ok((socket, addr)) = listeners.download_port.accept() {
+                    if let Some(conn) = limiter.connect(&socket) {
+                        let span = logging::connection_span("download", addr);
+                        scope.spawn(move |_| span.in_scope(|| tcp::serve_download(ctx, &conn, with_timeout(socket))));
+                    }
+                }
+
+                if let Some(http_port) = &listeners.http_port {
+                    while let Ok((socket, addr)) = http_port.accept() {
+                        if let Some(conn) = limiter.connect(&socket) {
+                            let span = logging::connection_span("http", addr);
+                            scope.spawn(move |_| span.in_scope(|| http::serve(ctx, &conn, with_timeout(socket))));
+                        }
+                    }
+                }
+
+                if let Some(tls) = &listeners.tls {
+                    let config = tls_config.as_ref().unwrap();
+                    while let Ok((socket, addr)) = tls.main_port.accept() {
+                        if let Some(conn) = limiter.connect(&socket) {
+                            let span = logging::connection_span("tls", addr);
+                            scope.spawn(move |_| {
+                                let _span = span.enter();
+                                if let Ok(socket) = tls::accept(config, with_timeout(socket)) {
+                                    tcp::serve_request(ctx, &conn, socket);
+                                }
+                            });
+                        }
+                    }
+
+                    while let Ok((socket, addr)) = tls.download_port.accept() {
+                        if let Some(conn) = limiter.connect(&socket) {
+                            let span = logging::connection_span("tls-download", addr);
+                            scope.spawn(move |_| {
+                                let _span = span.enter();
+                                if let Ok(socket) = tls::accept(config, with_timeout(socket)) {
+                                    tcp::serve_download(ctx, &conn, socket);
+                                }
+                            });
+                        }
+                    }
+
+                    if let Some(https_port) = &tls.https_port {
+                        while let Ok((socket, addr)) = https_port.accept() {
+                            if let Some(conn) = limiter.connect(&socket) {
+                                let span = logging::connection_span("https", addr);
+                                scope.spawn(move |_| {
+                                    let _span = span.enter();
+                                    if let Ok(socket) = tls::accept(config, with_timeout(socket)) {
+                                        http::serve(ctx, &conn, socket);
+                                    }
+                                });
+                            }
+                        }
+                    }
+                }
+
+                if let Some(metrics_port) = &listeners.metrics_port {
+                    while let Ok((socket, _)) = metrics_port.accept() {
+                        let body = metrics.render(&store.read().unwrap());
+                        scope.spawn(move |_| Metrics::respond(socket, body));
+                    }
+                }
+
+                if let Some(admin_port) = &listeners.admin_port {
+                    while let Ok((socket, _)) = admin_port.accept() {
+                        scope.spawn(move |_| admin::serve(control, with_timeout(socket), status));
+                    }
+                }
+
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            info!("shutting down");
+            // refuse new connections rather than leaving them in the backlog until exiting
+            drop(listeners);
+            drain(limiter, Duration::from_secs(config.drain_timeout.unwrap_or(DEFAULT_DRAIN_TIMEOUT)));
+        }).map_err(|_| eyre!("a server thread panicked"))?;
+
+        stats.save()
+    }
+}
+
+impl ServerHandle {
+    /// Stop accepting connections and shut down once open connections finish
+    pub fn shutdown(&self) {
+        self.control.request_shutdown();
+    }
+
+    /// Reload every plugin
+    pub fn reload(&self) {
+        self.control.request_reload();
+    }
+
+    /// Shut down on SIGTERM or SIGINT and reload on SIGHUP
+    pub fn handle_signals(&self) -> io::Result<()> {
+        self.control.handle_signals()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_next_port() {
+        let address = |address: &str| address.parse::<SocketAddr>().unwrap();
+        assert_eq!(next_port(address("127.0.0.1:45000")).unwrap(), address("127.0.0.1:45001"));
+        assert_eq!(next_port(address("127.0.0.1:0")).unwrap(), address("127.0.0.1:0"));
+        assert!(next_port(address("127.0.0.1:65535")).is_err());
+    }
+
+    #[test]
+    fn refuses_remote_admin_address() {
+        let root = tempfile::tempdir().unwrap();
+        let server = |admin: &str| {
+            Server::new(root.path().join("plugins"))
+                .request_address("127.0.0.1:0".parse().unwrap())
+                .stats_path(root.path().join("stats.json"))
+                .admin_address(admin.parse().unwrap())
+                .bind()
+        };
+
+        assert!(server("0.0.0.0:0").is_err());
+        assert!(server("127.0.0.1:0").is_ok());
+    }
+}
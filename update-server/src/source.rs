@@ -0,0 +1,463 @@
+//! Where the plugins being served are stored.
+//!
+//! A [`PluginSource`] lists releases by id and loads them when asked to. [`LocalDirectory`]
+//! serves the plugin folders and bundles of a plugins directory, [`MemorySource`] holds releases
+//! in memory (mostly useful for tests) and [`BlobStore`] keeps every file once in a
+//! content-addressed directory, reading files from disk only when they're downloaded.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, prelude::*, Cursor, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use color_eyre::eyre::{self, eyre};
+
+use crate::bundle;
+use crate::hosted_plugins::{self, PluginRoot, PluginToml};
+use update_protocol::sha256_hex;
+
+pub use crate::hosted_plugins::{Metadata, Plugin};
+
+/// Folder of a [`BlobStore`] holding files, named by their hash
+const BLOBS_DIR: &str = "blobs";
+
+/// Folder of a [`BlobStore`] holding the `plugin.toml` of every release
+const RELEASES_DIR: &str = "releases";
+
+/// Contents of every file of a release, by path within its plugin folder
+type Files = HashMap<PathBuf, Vec<u8>>;
+
+/// Storage the server loads plugins from
+pub trait PluginSource: Send + Sync {
+    /// Ids of every release in the source
+    fn releases(&self) -> eyre::Result<Vec<String>>;
+
+    /// Load a release, returning `None` if it no longer exists or isn't a release
+    fn load(&self, id: &str) -> eyre::Result<Option<Plugin>>;
+
+    /// Directory to watch for changes, if the source is stored on disk
+    fn watch_path(&self) -> Option<&Path> {
+        None
+    }
+
+    /// The release a changed path within [`PluginSource::watch_path`] belongs to
+    fn release_for(&self, _path: &Path) -> Option<String> {
+        None
+    }
+}
+
+/// The contents of a file being served, either held in memory or read from disk when downloaded
+#[derive(Clone)]
+pub struct Blob {
+    len: u64,
+    sha256: String,
+    contents: Contents,
+}
+
+#[derive(Clone)]
+enum Contents {
+    Memory(Arc<[u8]>),
+    File(PathBuf),
+}
+
+impl Blob {
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        Self {
+            len: data.len() as u64,
+            sha256: sha256_hex(&data),
+            contents: Contents::Memory(data.into()),
+        }
+    }
+
+    /// A file on disk whose hash is already known. It must not change while being served.
+    pub fn from_file(path: impl Into<PathBuf>, sha256: String) -> io::Result<Self> {
+        let path = path.into();
+        Ok(Self {
+            len: fs::metadata(&path)?.len(),
+            sha256,
+            contents: Contents::File(path),
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Lowercase hex SHA-256 of the contents, as sent to clients and mirrors
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    /// Number of bytes held in memory, 0 for files read from disk
+    pub fn memory_usage(&self) -> u64 {
+        match self.contents {
+            Contents::Memory(_) => self.len,
+            Contents::File(_) => 0,
+        }
+    }
+
+    /// Read the contents starting at `offset`
+    pub fn open(&self, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+        match &self.contents {
+            Contents::Memory(data) => {
+                let mut reader = Cursor::new(Arc::clone(data));
+                reader.set_position(offset);
+                Ok(Box::new(reader))
+            }
+            Contents::File(path) => {
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(offset))?;
+                Ok(Box::new(file))
+            }
+        }
+    }
+
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.len as usize);
+        self.open(0)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+/// A plugins directory, every plugin folder or bundle in it being a release named after it.
+///
+/// Files are held in memory, as plugin folders can be edited while they're being served.
+pub struct LocalDirectory {
+    dir: PathBuf,
+    canonical_dir: Option<PathBuf>,
+}
+
+impl LocalDirectory {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        let dir = dir.into();
+        Self {
+            canonical_dir: fs::canonicalize(&dir).ok(),
+            dir,
+        }
+    }
+}
+
+impl PluginSource for LocalDirectory {
+    fn releases(&self) -> eyre::Result<Vec<String>> {
+        let mut ids = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            if let Some(id) = entry?.file_name().to_str() {
+                ids.push(id.to_owned());
+            }
+        }
+
+        Ok(ids)
+    }
+
+    fn load(&self, id: &str) -> eyre::Result<Option<Plugin>> {
+        let path = self.dir.join(id);
+        if path.exists() {
+            hosted_plugins::folder_to_plugin(&path)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn watch_path(&self) -> Option<&Path> {
+        Some(&self.dir)
+    }
+
+    /// Map a path within the plugins directory to the entry (folder or bundle) which owns it
+    fn release_for(&self, path: &Path) -> Option<String> {
+        let relative = path.strip_prefix(&self.dir).ok()
+            .or_else(|| path.strip_prefix(self.canonical_dir.as_ref()?).ok())?;
+
+        match relative.components().next()? {
+            Component::Normal(name) => name.to_str().map(str::to_owned),
+            _ => None,
+        }
+    }
+}
+
+/// Releases held in memory, each as the contents of a plugin folder.
+///
+/// Clones share the same releases, so one can be kept to add releases after handing another to
+/// a server, which picks them up when reloaded.
+#[derive(Clone, Default)]
+pub struct MemorySource {
+    releases: Arc<RwLock<BTreeMap<String, Files>>>,
+}
+
+impl MemorySource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add or replace a release, given the path and contents of every file of its plugin folder
+    /// including the `plugin.toml`
+    pub fn insert<P, D>(&self, id: &str, files: impl IntoIterator<Item = (P, D)>)
+        where P: Into<PathBuf>, D: Into<Vec<u8>>
+    {
+        let files = files.into_iter()
+            .filter_map(|(path, data)| Some((bundle::normalize(&path.into())?, data.into())))
+            .collect();
+        self.releases.write().unwrap().insert(id.to_owned(), files);
+    }
+
+    pub fn remove(&self, id: &str) -> bool {
+        self.releases.write().unwrap().remove(id).is_some()
+    }
+}
+
+impl PluginSource for MemorySource {
+    fn releases(&self) -> eyre::Result<Vec<String>> {
+        Ok(self.releases.read().unwrap().keys().cloned().collect())
+    }
+
+    fn load(&self, id: &str) -> eyre::Result<Option<Plugin>> {
+        let files = match self.releases.read().unwrap().get(id) {
+            Some(files) => files.clone(),
+            None => return Ok(None),
+        };
+
+        hosted_plugins::root_to_plugin(&PluginRoot::Bundle { path: PathBuf::from(id), files }).map(Some)
+    }
+}
+
+/// A release added to a [`BlobStore`]
+pub struct Added {
+    pub id: String,
+    /// Number of files in the release, including metadata assets
+    pub files: usize,
+    /// Number of those files which weren't already stored by another release
+    pub new_files: usize,
+}
+
+/// A content-addressed store of releases, so files shared between releases are only stored once
+/// and files are only read when downloaded.
+///
+/// Every file is stored as `blobs/<first two hex digits>/<sha256>`, and every release as a
+/// `releases/<id>.toml` in the `plugin.toml` format which names files by their hash. Files are
+/// never modified once stored, and files no longer used by any release aren't deleted.
+pub struct BlobStore {
+    dir: PathBuf,
+    releases_dir: PathBuf,
+    canonical_releases_dir: Option<PathBuf>,
+}
+
+fn is_sha256(name: &str) -> bool {
+    name.len() == 64 && name.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+/// Write a file such that it's never seen partially written
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, data)?;
+    fs::rename(&temp, path)
+}
+
+impl BlobStore {
+    /// Open the blob store in `dir`, creating it if it doesn't exist
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        let releases_dir = dir.join(RELEASES_DIR);
+        fs::create_dir_all(dir.join(BLOBS_DIR))?;
+        fs::create_dir_all(&releases_dir)?;
+
+        Ok(Self {
+            canonical_releases_dir: fs::canonicalize(&releases_dir).ok(),
+            releases_dir,
+            dir,
+        })
+    }
+
+    fn blob_path(&self, sha256: &str) -> PathBuf {
+        self.dir.join(BLOBS_DIR).join(&sha256[..2]).join(sha256)
+    }
+
+    fn manifest_path(&self, id: &str) -> PathBuf {
+        self.releases_dir.join(format!("{}.toml", id))
+    }
+
+    fn blob(&self, id: &str, name: &Path) -> eyre::Result<Blob> {
+        let sha256 = name.to_str()
+            .filter(|name| is_sha256(name))
+            .ok_or_else(|| eyre!("release {} references `{}`, which isn't a file hash", id, name.display()))?;
+
+        Blob::from_file(self.blob_path(sha256), sha256.to_owned())
+            .map_err(|e| eyre!("file {} of release {} can't be read: {}", sha256, id, e))
+    }
+
+    /// Store a plugin folder or bundle as a release named after it, replacing any release of
+    /// the same name
+    pub fn add(&self, path: &Path) -> eyre::Result<Added> {
+        let root = PluginRoot::open(path)?
+            .ok_or_else(|| eyre!("{} is neither a plugin folder nor a bundle", path.display()))?;
+        let id = path.file_name()
+            .and_then(|name| name.to_str())
+            .filter(|name| !name.starts_with('.'))
+            .ok_or_else(|| eyre!("{} can't be used as a release id", path.display()))?
+            .to_owned();
+
+        // make sure the release would load before storing anything
+        hosted_plugins::root_to_plugin(&root)?;
+        let mut manifest: PluginToml = toml::from_str(&root.read_to_string(Path::new("plugin.toml"))?)?;
+
+        let (mut files, mut new_files) = (0, 0);
+        let mut store = |file: &mut PathBuf| -> eyre::Result<()> {
+            let data = root.read(file)?;
+            let sha256 = sha256_hex(&data);
+            let blob = self.blob_path(&sha256);
+            if !blob.exists() {
+                fs::create_dir_all(blob.parent().unwrap())?;
+                write_atomic(&blob, &data)?;
+                new_files += 1;
+            }
+
+            files += 1;
+            *file = PathBuf::from(sha256);
+            Ok(())
+        };
+
+        for file in &mut manifest.files {
+            store(&mut file.filename)?;
+        }
+        if let Some(metadata) = &mut manifest.metadata {
+            for image in metadata.images.iter_mut().flatten() {
+                store(image)?;
+            }
+            if let Some(changelog) = &mut metadata.changelog {
+                store(changelog)?;
+            }
+        }
+
+        write_atomic(&self.manifest_path(&id), manifest.to_toml_string()?.as_bytes())?;
+        Ok(Added { id, files, new_files })
+    }
+
+    /// Stop serving a release, returning whether it existed
+    pub fn remove(&self, id: &str) -> io::Result<bool> {
+        match fs::remove_file(self.manifest_path(id)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl PluginSource for BlobStore {
+    fn releases(&self) -> eyre::Result<Vec<String>> {
+        let mut ids = vec![];
+        for entry in fs::read_dir(&self.releases_dir)? {
+            if let Some(id) = self.release_for(&entry?.path()) {
+                ids.push(id);
+            }
+        }
+
+        Ok(ids)
+    }
+
+    fn load(&self, id: &str) -> eyre::Result<Option<Plugin>> {
+        let manifest = match fs::read_to_string(self.manifest_path(id)) {
+            Ok(manifest) => manifest,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        hosted_plugins::to_plugin(toml::from_str(&manifest)?, |name| self.blob(id, name)).map(Some)
+    }
+
+    fn watch_path(&self) -> Option<&Path> {
+        Some(&self.releases_dir)
+    }
+
+    fn release_for(&self, path: &Path) -> Option<String> {
+        let parent = path.parent()?;
+        if parent != self.releases_dir && Some(parent) != self.canonical_releases_dir.as_deref() {
+            return None
+        }
+
+        path.file_name()?
+            .to_str()?
+            .strip_suffix(".toml")
+            .filter(|id| !id.is_empty() && !id.starts_with('.'))
+            .map(str::to_owned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLUGIN_TOML: &str = r#"
+        version = "1.0.0"
+        name = "a"
+        files = [
+            { install_location = "sd:/a.nro", filename = "a.nro" },
+            { install_location = "sd:/shared.arc", filename = "shared.arc" },
+        ]
+    "#;
+
+    fn write_plugin(dir: &Path, nro: &str) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("plugin.toml"), PLUGIN_TOML).unwrap();
+        fs::write(dir.join("a.nro"), nro).unwrap();
+        fs::write(dir.join("shared.arc"), "shared").unwrap();
+    }
+
+    fn contents(plugin: &Plugin) -> Vec<Vec<u8>> {
+        plugin.files.iter().map(|(_, blob)| blob.read().unwrap()).collect()
+    }
+
+    #[test]
+    fn blob_store_deduplicates_files() {
+        let root = tempfile::tempdir().unwrap();
+        let (v1, v2) = (root.path().join("a-1.0.0"), root.path().join("a-1.0.1"));
+        write_plugin(&v1, "v1");
+        write_plugin(&v2, "v2");
+
+        let store = BlobStore::open(root.path().join("store")).unwrap();
+        let added = store.add(&v1).unwrap();
+        assert_eq!((added.id.as_str(), added.files, added.new_files), ("a-1.0.0", 2, 2));
+        let added = store.add(&v2).unwrap();
+        assert_eq!((added.files, added.new_files), (2, 1));
+
+        let mut releases = store.releases().unwrap();
+        releases.sort();
+        assert_eq!(releases, ["a-1.0.0", "a-1.0.1"]);
+
+        let plugin = store.load("a-1.0.1").unwrap().unwrap();
+        assert_eq!(contents(&plugin), [b"v2".to_vec(), b"shared".to_vec()]);
+        assert!(plugin.files.iter().all(|(_, blob)| blob.memory_usage() == 0));
+        assert_eq!(plugin.files[1].1.sha256(), sha256_hex(b"shared"));
+
+        // the folders can go away once stored
+        fs::remove_dir_all(&v1).unwrap();
+        let plugin = store.load("a-1.0.0").unwrap().unwrap();
+        let mut data = vec![];
+        plugin.files[0].1.open(1).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"1");
+
+        assert_eq!(store.release_for(&store.manifest_path("a-1.0.0")).as_deref(), Some("a-1.0.0"));
+        assert!(store.remove("a-1.0.0").unwrap());
+        assert!(store.load("a-1.0.0").unwrap().is_none());
+    }
+
+    #[test]
+    fn memory_source_is_shared_between_clones() {
+        let source = MemorySource::new();
+        let clone = source.clone();
+        source.insert("a", vec![
+            ("plugin.toml", PLUGIN_TOML.as_bytes()),
+            ("a.nro", b"nro"),
+            ("shared.arc", b"shared"),
+        ]);
+
+        assert_eq!(clone.releases().unwrap(), ["a"]);
+        assert_eq!(contents(&clone.load("a").unwrap().unwrap()), [b"nro".to_vec(), b"shared".to_vec()]);
+
+        assert!(clone.remove("a"));
+        assert!(source.load("a").unwrap().is_none());
+    }
+}
@@ -0,0 +1,190 @@
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre;
+use tracing::warn;
+
+use update_protocol::{PluginStats, StatsResponse};
+
+/// How often changed statistics are written back to disk
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Update checks from clients reporting a version which isn't valid are counted under this key
+pub const INVALID_VERSION: &str = "invalid";
+
+struct Inner {
+    path: PathBuf,
+    stats: StatsResponse,
+    dirty: bool,
+    last_save: Instant,
+}
+
+/// Per-plugin usage statistics, persisted as JSON so they survive restarts.
+///
+/// Cheap to clone, clones share the same underlying statistics so download threads can
+/// record to it directly.
+#[derive(Clone)]
+pub struct Stats(Arc<Mutex<Inner>>);
+
+impl Stats {
+    /// Load statistics from `path`, starting from scratch if the file doesn't exist yet
+    pub fn load(path: &Path) -> eyre::Result<Self> {
+        let stats = read(path)?;
+
+        Ok(Stats(Arc::new(Mutex::new(Inner {
+            path: path.to_owned(),
+            stats,
+            dirty: false,
+            last_save: Instant::now(),
+        }))))
+    }
+
+    fn record(&self, plugin_name: &str, f: impl FnOnce(&mut PluginStats)) {
+        let mut inner = self.0.lock().unwrap();
+        f(inner.stats.plugins.entry(plugin_name.to_owned()).or_default());
+        inner.dirty = true;
+    }
+
+    /// Record an update check from a client on `client_version`, `None` if the version it sent
+    /// isn't valid
+    pub fn update_check(&self, plugin_name: &str, client_version: Option<&str>) {
+        self.record(plugin_name, |stats| {
+            let version = client_version.unwrap_or(INVALID_VERSION);
+            *stats.update_checks.entry(version.to_owned()).or_default() += 1;
+        });
+    }
+
+    pub fn update_offered(&self, plugin_name: &str, new_version: &str) {
+        self.record(plugin_name, |stats| {
+            *stats.updates_offered.entry(new_version.to_owned()).or_default() += 1;
+        });
+    }
+
+    /// Record a finished download, `bytes` being how much was actually sent
+    pub fn download(&self, plugin_name: &str, bytes: u64, completed: bool) {
+        self.record(plugin_name, |stats| {
+            stats.bytes_served += bytes;
+            if completed {
+                stats.files_downloaded += 1;
+            } else {
+                stats.failures += 1;
+            }
+        });
+    }
+
+    pub fn failure(&self, plugin_name: &str) {
+        self.record(plugin_name, |stats| stats.failures += 1);
+    }
+
+    /// Get a copy of the statistics, optionally only for a single plugin
+    pub fn snapshot(&self, plugin_name: Option<&str>) -> StatsResponse {
+        let inner = self.0.lock().unwrap();
+        let plugins = inner.stats.plugins.iter()
+            .filter(|(name, _)| plugin_name.map(|filter| filter == name.as_str()).unwrap_or(true))
+            .map(|(name, stats)| (name.clone(), stats.clone()))
+            .collect();
+
+        StatsResponse { plugins }
+    }
+
+    /// Write the statistics to disk if they have changed and enough time has passed
+    pub fn save_if_needed(&self) {
+        let needs_save = {
+            let inner = self.0.lock().unwrap();
+            inner.dirty && inner.last_save.elapsed() >= SAVE_INTERVAL
+        };
+
+        if needs_save {
+            if let Err(e) = self.save() {
+                warn!("failed to save statistics: {}", e);
+            }
+        }
+    }
+
+    pub fn save(&self) -> eyre::Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        let json = serde_json::to_string_pretty(&inner.stats)?;
+
+        // write to a temporary file first so a crash mid-write can't corrupt the statistics
+        let tmp_path = inner.path.with_extension("json.tmp");
+        fs::write(&tmp_path, json)?;
+        fs::rename(&tmp_path, &inner.path)?;
+
+        inner.dirty = false;
+        inner.last_save = Instant::now();
+
+        Ok(())
+    }
+}
+
+fn read(path: &Path) -> eyre::Result<StatsResponse> {
+    if path.exists() {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    } else {
+        Ok(StatsResponse::default())
+    }
+}
+
+/// Entrypoint for `update-server stats [plugin]`, prints the statistics recorded by the server
+pub fn print(path: &Path, plugin_name: Option<&str>) -> eyre::Result<()> {
+    let stats = read(path)?;
+
+    let plugins = stats.plugins.iter()
+        .filter(|(name, _)| plugin_name.map(|filter| filter == name.as_str()).unwrap_or(true));
+
+    let mut any = false;
+    for (name, stats) in plugins {
+        any = true;
+        println!("{}", name);
+        println!("  files downloaded: {}", stats.files_downloaded);
+        println!("  bytes served:     {}", stats.bytes_served);
+        println!("  failures:         {}", stats.failures);
+        println!("  update checks by client version:");
+        for (version, count) in &stats.update_checks {
+            println!("    {:<16} {}", version, count);
+        }
+        println!("  updates offered by version:");
+        for (version, count) in &stats.updates_offered {
+            println!("    {:<16} {}", version, count);
+        }
+    }
+
+    if !any {
+        println!("No statistics recorded in {}", path.display());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persists_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.json");
+
+        let stats = Stats::load(&path).unwrap();
+        stats.update_check("a", Some("1.0.0"));
+        stats.update_check("a", Some("1.0.0"));
+        stats.update_check("a", None);
+        stats.update_offered("a", "1.1.0");
+        stats.download("a", 10, true);
+        stats.download("a", 4, false);
+        stats.update_check("b", Some("0.1.0"));
+        stats.save().unwrap();
+
+        let stats = Stats::load(&path).unwrap().snapshot(Some("a"));
+        assert_eq!(stats.plugins.len(), 1);
+        let a = &stats.plugins["a"];
+        assert_eq!(a.update_checks["1.0.0"], 2);
+        assert_eq!(a.update_checks[INVALID_VERSION], 1);
+        assert_eq!(a.updates_offered["1.1.0"], 1);
+        assert_eq!(a.files_downloaded, 1);
+        assert_eq!(a.bytes_served, 14);
+        assert_eq!(a.failures, 1);
+    }
+}
@@ -0,0 +1,24 @@
+use std::io::prelude::*;
+use std::net::{Shutdown, TcpStream};
+
+use crate::tls::TlsStream;
+
+/// A connection to a client, either plain TCP or TLS
+pub trait Stream: Read + Write {
+    /// Cleanly close the connection, signaling to the client that the response is complete
+    fn close(&mut self);
+}
+
+impl Stream for TcpStream {
+    fn close(&mut self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+impl Stream for TlsStream {
+    fn close(&mut self) {
+        self.conn.send_close_notify();
+        let _ = self.flush();
+        let _ = self.sock.shutdown(Shutdown::Both);
+    }
+}
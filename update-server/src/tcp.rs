@@ -0,0 +1,163 @@
+//! The TCP protocols served on the request and download ports.
+//!
+//! The original protocol is a single line of JSON request on the request port, responded to
+//! with a single line of JSON, and a big endian `u64` download index on the download port,
+//! responded to with the contents of the file. The framed protocol (see
+//! `update_protocol::frame`) is served on the request port alongside it.
+//!
+//! Downloads of files which aren't public include an access token after the index, see
+//! `update_protocol::DOWNLOAD_TOKEN_FLAG`.
+
+use std::convert::TryFrom;
+use std::io::{self, prelude::*, BufReader};
+
+use update_protocol::{Request, DOWNLOAD_TOKEN_FLAG};
+use update_protocol::frame::{self, FrameKind};
+use tracing::debug;
+
+use crate::limits::{Action, Connection};
+use crate::stream::Stream;
+use crate::requests::{Context, DownloadError};
+
+/// Handle a single connection to the request port
+pub fn serve_request(ctx: &Context, conn: &Connection, socket: impl Stream) {
+    let mut socket = BufReader::new(socket);
+
+    let framed = socket.fill_buf()
+        .map(|buf| buf.first() == Some(&frame::MAGIC[0]))
+        .unwrap_or(false);
+
+    if framed {
+        serve_framed(ctx, conn, &mut socket);
+        socket.into_inner().close();
+        return
+    }
+
+    let max_size = conn.max_request_size();
+    let mut packet = String::new();
+    let _ = (&mut socket).take(max_size as u64).read_line(&mut packet);
+    if packet.len() >= max_size && !packet.ends_with('\n') {
+        conn.request_too_large();
+        socket.into_inner().close();
+        return
+    }
+
+    if !conn.allow(Action::Request) {
+        socket.into_inner().close();
+        return
+    }
+
+    if let Some(response) = ctx.handle(serde_json::from_str::<Request>(&packet).ok()) {
+        let mut socket = socket.into_inner();
+        let _ = socket.write_all(format!("{}\n", response).as_bytes());
+        socket.close();
+    }
+}
+
+/// Handle a framed protocol session, until the client disconnects, sends an invalid frame or
+/// exceeds a limit
+fn serve_framed(ctx: &Context, conn: &Connection, socket: &mut BufReader<impl Stream>) {
+    match frame::read_handshake(socket) {
+        Ok(frame::VERSION) => {}
+        _ => return,
+    }
+    if frame::write_handshake(socket.get_mut()).is_err() {
+        return
+    }
+
+    let max_size = frame::MAX_CLIENT_FRAME.min(conn.max_request_size() as u32);
+    loop {
+        let request = match frame::read_frame(socket, max_size) {
+            Ok(Some(request)) => request,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                conn.request_too_large();
+                break
+            }
+            _ => break,
+        };
+
+        let action = match request.kind {
+            FrameKind::FileRequest => Action::Download,
+            _ => Action::Request,
+        };
+        let socket = socket.get_mut();
+        if !conn.allow(action) {
+            let _ = frame::write_frame(socket, FrameKind::Error, b"rate limited");
+            break
+        }
+
+        let result = match request.kind {
+            FrameKind::Request => {
+                match ctx.handle(serde_json::from_slice::<Request>(&request.payload).ok()) {
+                    Some(response) => frame::write_frame(socket, FrameKind::Response, response.as_bytes()),
+                    None => frame::write_frame(socket, FrameKind::Error, b"not found"),
+                }
+            }
+            FrameKind::FileRequest if request.payload.len() >= 8 => {
+                let mut index = [0; 8];
+                index.copy_from_slice(&request.payload[..8]);
+                let token = std::str::from_utf8(&request.payload[8..]).ok().filter(|token| !token.is_empty());
+
+                match ctx.download(u64::from_be_bytes(index), token) {
+                    Ok(download) => {
+                        let len = download.data.len();
+                        match u32::try_from(len) {
+                            Ok(frame_len) => {
+                                if frame::write_header(socket, FrameKind::FileData, frame_len).is_err()
+                                    || !ctx.send(socket, &download, (0, len))
+                                {
+                                    break
+                                }
+                                socket.flush()
+                            }
+                            Err(_) => frame::write_frame(socket, FrameKind::Error, b"file too large for the framed protocol"),
+                        }
+                    }
+                    Err(DownloadError::NotFound) => frame::write_frame(socket, FrameKind::Error, b"file not found"),
+                    Err(DownloadError::Unauthorized) => frame::write_frame(socket, FrameKind::Error, b"unauthorized"),
+                }
+            }
+            _ => frame::write_frame(socket, FrameKind::Error, b"unexpected frame"),
+        };
+
+        if result.is_err() {
+            break
+        }
+    }
+}
+
+/// Read a download request, the index and the access token if one was sent
+fn read_download_request(socket: &mut impl Read) -> io::Result<(u64, Option<String>)> {
+    let mut buf = [0; 8];
+    socket.read_exact(&mut buf)?;
+    let index = u64::from_be_bytes(buf);
+    if index & DOWNLOAD_TOKEN_FLAG == 0 {
+        return Ok((index, None))
+    }
+
+    let mut len = [0; 2];
+    socket.read_exact(&mut len)?;
+    let mut token = vec![0; u16::from_be_bytes(len) as usize];
+    socket.read_exact(&mut token)?;
+
+    Ok((index & !DOWNLOAD_TOKEN_FLAG, String::from_utf8(token).ok()))
+}
+
+/// Handle a single connection to the download port
+pub fn serve_download(ctx: &Context, conn: &Connection, mut socket: impl Stream) {
+    if let Ok((index, token)) = read_download_request(&mut socket) {
+        if !conn.allow(Action::Download) {
+            socket.close();
+            return
+        }
+
+        if let Ok(download) = ctx.download(index, token.as_deref()) {
+            let len = download.data.len();
+            ctx.send(&mut socket, &download, (0, len));
+        }
+    } else {
+        debug!("failed to read download request");
+    }
+
+    socket.close();
+}
@@ -0,0 +1,54 @@
+use std::fs;
+use std::io;
+use std::sync::Arc;
+use std::path::Path;
+use std::net::TcpStream;
+
+use color_eyre::eyre;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+
+pub type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// Build a TLS configuration from a PEM certificate chain and private key
+pub fn server_config(cert: &Path, key: &Path) -> eyre::Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .map_err(|e| eyre::eyre!("Failed to read certificate {}: {}", cert.display(), e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| eyre::eyre!("Failed to parse certificate {}: {}", cert.display(), e))?;
+
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| eyre::eyre!("Failed to read private key {}: {}", key.display(), e))?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(Arc::new(config))
+}
+
+/// Perform the TLS handshake on a newly accepted connection
+pub fn accept(config: &Arc<ServerConfig>, sock: TcpStream) -> io::Result<TlsStream> {
+    let conn = ServerConnection::new(Arc::clone(config))
+        .map_err(io::Error::other)?;
+
+    let mut stream = StreamOwned::new(conn, sock);
+    while stream.conn.is_handshaking() {
+        stream.conn.complete_io(&mut stream.sock)?;
+    }
+
+    Ok(stream)
+}
+
+/// SHA-256 fingerprint of the server certificate (the first certificate in the PEM file), for
+/// pinning in skyline-update
+pub fn fingerprint(cert: &Path) -> eyre::Result<String> {
+    let pem = fs::read(cert)?;
+    let cert = CertificateDer::pem_slice_iter(&pem)
+        .next()
+        .ok_or_else(|| eyre::eyre!("No certificate found in {}", cert.display()))?
+        .map_err(|e| eyre::eyre!("Failed to parse certificate {}: {}", cert.display(), e))?;
+
+    Ok(update_protocol::sha256_hex(cert.as_ref()))
+}
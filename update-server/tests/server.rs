@@ -0,0 +1,144 @@
+//! Servers started on ephemeral ports, updated from with the client
+
+use std::cell::RefCell;
+use std::fs;
+use std::io::Read;
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use skyline_update::{Client, Installer, Transport, UpdateResponse};
+use update_protocol::ResponseCode;
+use update_server::Server;
+use update_server::source::BlobStore;
+
+const LOCALHOST: &str = "127.0.0.1:0";
+
+/// Installs files into memory
+#[derive(Default)]
+struct MemoryInstaller(RefCell<Vec<(PathBuf, Vec<u8>)>>);
+
+impl Installer for MemoryInstaller {
+    fn should_update(&self, _: &UpdateResponse) -> bool {
+        true
+    }
+
+    fn install_file(&self, path: PathBuf, buf: Vec<u8>) -> Result<(), ()> {
+        self.0.borrow_mut().push((path, buf));
+        Ok(())
+    }
+}
+
+fn write_plugin(plugins_dir: &Path, version: &str) {
+    let dir = plugins_dir.join(format!("example-{}", version));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("plugin.toml"), format!(r#"
+        name = "example"
+        version = "{}"
+        files = [{{ install_location = "sd:/atmosphere/example.nro", filename = "example.nro" }}]
+    "#, version)).unwrap();
+    fs::write(dir.join("example.nro"), version).unwrap();
+}
+
+fn server(root: &Path) -> Server {
+    let localhost: SocketAddr = LOCALHOST.parse().unwrap();
+    Server::new(root.join("plugins"))
+        .request_address(localhost)
+        .http_address(localhost)
+        .stats_path(root.join("stats.json"))
+}
+
+fn client(http_address: SocketAddr) -> Client {
+    Client::new(http_address.ip()).transport(Transport::Http { port: http_address.port() })
+}
+
+#[test]
+fn serves_updates() {
+    let root = tempfile::tempdir().unwrap();
+    write_plugin(&root.path().join("plugins"), "1.1.0");
+
+    let server = server(root.path()).bind().unwrap();
+    let client = client(server.http_address().unwrap());
+    let handle = server.handle();
+    let thread = server.spawn();
+
+    let update = client.try_get_update_info("example", "1.0.0", false).unwrap();
+    assert!(matches!(update.code, ResponseCode::Update));
+    assert_eq!(update.new_plugin_version, "1.1.0");
+
+    let installer = MemoryInstaller::default();
+    assert!(client.custom_install_update(&update, &installer));
+    assert_eq!(installer.0.into_inner(), vec![(PathBuf::from("sd:/atmosphere/example.nro"), b"1.1.0".to_vec())]);
+
+    let update = client.try_get_update_info("example", "1.1.0", false).unwrap();
+    assert!(matches!(update.code, ResponseCode::NoUpdate));
+
+    handle.shutdown();
+    thread.join().unwrap().unwrap();
+    assert!(root.path().join("stats.json").exists());
+}
+
+#[test]
+fn reloads_and_drains() {
+    let root = tempfile::tempdir().unwrap();
+    let server = server(root.path()).drain_timeout(Duration::from_secs(1)).bind().unwrap();
+    let client = client(server.http_address().unwrap());
+    let request_address = server.request_address();
+    let handle = server.handle();
+    let thread = server.spawn();
+
+    let update = client.try_get_update_info("example", "1.0.0", false).unwrap();
+    assert!(matches!(update.code, ResponseCode::PluginNotFound));
+
+    write_plugin(&root.path().join("plugins"), "1.1.0");
+    handle.reload();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let update = client.try_get_update_info("example", "1.0.0", false).unwrap();
+        if matches!(update.code, ResponseCode::Update) {
+            break
+        }
+        assert!(Instant::now() < deadline, "plugin wasn't reloaded");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    // a client which never sends its request is disconnected once the drain timeout passes
+    let mut idle = TcpStream::connect(request_address).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let start = Instant::now();
+    handle.shutdown();
+
+    // new connections are refused while draining
+    thread::sleep(Duration::from_millis(200));
+    assert!(TcpStream::connect(request_address).is_err());
+
+    thread.join().unwrap().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(idle.read(&mut [0; 1]).unwrap_or(0), 0);
+}
+
+#[test]
+fn serves_from_blob_store() {
+    let root = tempfile::tempdir().unwrap();
+    let folders = root.path().join("folders");
+    write_plugin(&folders, "1.1.0");
+
+    let store = BlobStore::open(root.path().join("store")).unwrap();
+    store.add(&folders.join("example-1.1.0")).unwrap();
+    fs::remove_dir_all(&folders).unwrap();
+
+    let server = server(root.path()).plugin_source(store).bind().unwrap();
+    let client = client(server.http_address().unwrap());
+    let handle = server.handle();
+    let thread = server.spawn();
+
+    let update = client.try_get_update_info("example", "1.0.0", false).unwrap();
+    let installer = MemoryInstaller::default();
+    assert!(client.custom_install_update(&update, &installer));
+    assert_eq!(installer.0.into_inner(), vec![(PathBuf::from("sd:/atmosphere/example.nro"), b"1.1.0".to_vec())]);
+
+    handle.shutdown();
+    thread.join().unwrap().unwrap();
+    assert!(!root.path().join("plugins").exists());
+}